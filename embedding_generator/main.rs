@@ -1,57 +1,90 @@
 use anyhow::Result;
-use bincode;
 use candle::Tensor;
-use csv;
-use inference_server::models::bert::BertInferenceModel;
+use inference_server::chunking::{chunk_documents, ChunkStrategy};
+use inference_server::models::bert::{BertInferenceModel, Pooling};
+use inference_server::search::bm25::Bm25Index;
+use inference_server::search::hnsw::{HnswIndex, HnswParams};
+use inference_server::search::ivfpq::{IvfPqIndex, IvfPqParams};
+use inference_server::search::store::{DocumentMeta, EmbeddingModel, IndexWriter};
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::fs::File;
+use serde_json::Value;
+use std::time::Instant;
+
+// Index directory read by the server
+const INDEX_DIR: &str = "index";
 
 fn main() -> Result<()> {
     // Get the file name from command-line arguments
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: embedding_generator <file_name>");
+    // --hnsw and --ivfpq also build approximate nearest-neighbour indexes of the embeddings;
+    // --pooling=mean|max|cls overrides the pooling of the model repo
+    let mut args: Vec<String> = std::env::args().collect();
+    let build_hnsw = args.iter().any(|arg| arg == "--hnsw");
+    let build_ivfpq = args.iter().any(|arg| arg == "--ivfpq");
+    let pooling = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--pooling="))
+        .map(|pooling| serde_json::from_value::<Pooling>(Value::String(pooling.to_string())))
+        .transpose()?;
+    args.retain(|arg| arg != "--hnsw" && arg != "--ivfpq" && !arg.starts_with("--pooling="));
+    if args.len() < 2 || args.len() > 5 {
+        println!(
+            "Usage: embedding_generator <file_name> [token_window|sentence|paragraph] \
+             [chunk_tokens] [overlap] [--hnsw] [--ivfpq] [--pooling=mean|max|cls]"
+        );
         std::process::exit(1);
     }
     let csv_file_path = &args[1];
     println!("Starting to generate embeddings from {}", csv_file_path);
 
-    // Load sentences from the CSV file
-    let text_map: HashMap<String, String> =
-        load_sentences_from_csv(csv_file_path, 0, 1).expect("Failed to load sentences from CSV");
-    println!("Loaded sentences - total count: {}", text_map.len());
-
-    // Convert sentences to a Vec<String> for embedding generation
-    let sentences: Vec<String> = text_map.values().map(|s| s.to_string()).collect();
-
-    // Serialize the map to a binary file
-    let mut sentence_file = File::create("text_map.bin")
-        .expect("Failed to create text_map.bin for serialized sentences");
-    bincode::encode_into_std_write(&sentences, &mut sentence_file, bincode::config::standard())
-        .expect("Failed to encode sentences");
-    println!("Serialized text_map to text_map.bin");
+    // Load documents from the CSV file: the first column is the id, the second the text and the
+    // others metadata
+    let (documents, metadata) =
+        load_documents_from_csv(csv_file_path, 0, 1).expect("Failed to load documents from CSV");
+    println!("Loaded documents - total count: {}", documents.len());
 
     // Load the BERT model
-    let bert_model = BertInferenceModel::load(
+    let mut bert_model = BertInferenceModel::load(
         "sentence-transformers/all-MiniLM-L6-v2",
         // "main",
         "refs/pr/21",
-        "",
-        "",
         candle::Device::Cpu,
     )
     .expect("Failed to load BERT model");
+    // The manifest records the pooling, and the server must embed queries the same way
+    if let Some(pooling) = pooling {
+        bert_model.set_pooling(pooling);
+        println!("Using {:?} pooling", pooling);
+    }
     println!("Loaded BERT model");
 
-    // Generate embeddings in parallel
-    let embedding_results: Vec<Result<Tensor, _>> = sentences
+    // Split the documents into chunks the model embeds whole
+    let strategy = parse_chunk_strategy(&args[2..], bert_model.max_chunk_tokens())?;
+    let chunks = chunk_documents(bert_model.tokenizer(), &strategy, &documents)?;
+    println!(
+        "Split {} documents into {} chunks with {:?}",
+        documents.len(),
+        chunks.len(),
+        strategy
+    );
+
+    // Everything is written to a new index directory that replaces the old one once complete
+    let mut index = IndexWriter::create(INDEX_DIR)?;
+    index.write_documents(&documents, &metadata)?;
+    index.write_chunks(&chunks)?;
+    let chunk_texts: Vec<String> = chunks
+        .iter()
+        .map(|chunk| chunk.text(&documents).unwrap_or_default().to_string())
+        .collect();
+    index.write_bm25(&Bm25Index::build(chunk_texts.iter().map(String::as_str)))?;
+
+    // Generate embeddings in parallel, one per chunk
+    let embedding_results: Vec<Result<Tensor, _>> = chunk_texts
         .par_chunks(200)
         .map(|chunk| bert_model.create_embeddings(chunk.to_vec()))
         .collect();
     println!("Embeddings generated");
 
-    // Concatenate the embeddings and save as a binary file
+    // Concatenate the embeddings and save them to the index
     let embeddings = Tensor::cat(
         &embedding_results
             .iter()
@@ -60,29 +93,108 @@ fn main() -> Result<()> {
         0,
     )
     .expect("Failed to concatenate embeddings");
-    embeddings
-        .save_safetensors("my_embedding", "embeddings.bin")
-        .expect("Failed to save embeddings.bin");
-    println!("Saved embeddings.bin");
+    index.write_embeddings(&embeddings)?;
+    println!("Saved embeddings");
+
+    let vectors = embeddings.flatten_all()?.to_vec1::<f32>()?;
+    let dim = embeddings.dim(1)?;
+    if build_hnsw {
+        let started = Instant::now();
+        let hnsw = HnswIndex::build(&vectors, dim, HnswParams::default())?;
+        index.write_hnsw(&hnsw)?;
+        println!(
+            "Saved HNSW index ({} vectors) in {:.2?}",
+            hnsw.len(),
+            started.elapsed()
+        );
+    }
+    if build_ivfpq {
+        let started = Instant::now();
+        let params = IvfPqParams::for_size(vectors.len() / dim, dim);
+        let ivfpq = IvfPqIndex::build(&vectors, dim, params)?;
+        index.write_ivfpq(&ivfpq)?;
+        println!(
+            "Saved IVF-PQ index ({} vectors, {:?}) in {:.2?}",
+            ivfpq.len(),
+            params,
+            started.elapsed()
+        );
+    }
 
+    let manifest = index.finish(EmbeddingModel::of(&bert_model), Some(strategy))?;
+    println!(
+        "Wrote index {} ({} documents, {} chunks)",
+        INDEX_DIR, manifest.documents, manifest.count
+    );
     Ok(())
 }
 
-// Helper function to load CSV data into a HashMap
-fn load_sentences_from_csv(
+// Chunking from the optional command-line arguments; whole paragraphs up to the model's input
+// size by default
+fn parse_chunk_strategy(args: &[String], max_chunk_tokens: usize) -> Result<ChunkStrategy> {
+    let number = |index: usize, default: usize| -> Result<usize> {
+        match args.get(index) {
+            Some(arg) => Ok(arg.parse()?),
+            None => Ok(default),
+        }
+    };
+    let strategy = match args.first().map(String::as_str) {
+        Some("token_window") => ChunkStrategy::TokenWindow {
+            size: number(1, max_chunk_tokens)?,
+            overlap: number(2, 0)?,
+        },
+        Some("sentence") => ChunkStrategy::Sentence {
+            max_tokens: number(1, max_chunk_tokens)?,
+        },
+        Some("paragraph") | None => ChunkStrategy::Paragraph {
+            max_tokens: number(1, max_chunk_tokens)?,
+        },
+        Some(other) => anyhow::bail!("unknown chunking strategy {:?}", other),
+    };
+    strategy
+        .validate(max_chunk_tokens)
+        .map_err(anyhow::Error::msg)?;
+    Ok(strategy)
+}
+
+// Helper function to load CSV rows as documents, in file order; columns other than the id and
+// the text become metadata keyed by their header
+fn load_documents_from_csv(
     csv_file_path: &str,
-    name_col_index: usize,
+    id_col_index: usize,
     text_col_index: usize,
-) -> Result<HashMap<String, String>> {
-    let mut sentence_map = HashMap::new();
+) -> Result<(Vec<String>, Vec<DocumentMeta>)> {
+    let mut documents = Vec::new();
+    let mut metadata = Vec::new();
     let mut reader = csv::ReaderBuilder::new()
         .has_headers(true)
         .from_path(csv_file_path)?;
+    let headers = reader.headers()?.clone();
     for result in reader.records() {
         let record = result?;
-        let name = record.get(name_col_index).unwrap().to_string();
+        let id = record.get(id_col_index).unwrap().to_string();
         let text = record.get(text_col_index).unwrap().to_string();
-        sentence_map.insert(name, text);
+        let fields = headers
+            .iter()
+            .zip(record.iter())
+            .enumerate()
+            .filter(|(index, _)| *index != id_col_index && *index != text_col_index)
+            .map(|(_, (name, value))| (name.to_string(), metadata_value(value)))
+            .collect();
+        documents.push(text);
+        metadata.push(DocumentMeta {
+            id,
+            metadata: fields,
+        });
+    }
+    Ok((documents, metadata))
+}
+
+// Cells holding JSON numbers, objects or arrays (like news.csv's word counts and entity
+// sentiments) keep their structure; anything else is a string
+fn metadata_value(cell: &str) -> Value {
+    match serde_json::from_str(cell) {
+        Ok(value @ (Value::Number(_) | Value::Object(_) | Value::Array(_))) => value,
+        _ => Value::String(cell.to_string()),
     }
-    Ok(sentence_map)
 }
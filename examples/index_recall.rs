@@ -0,0 +1,157 @@
+// Recall of the approximate indexes against brute-force search: HNSW over a range of
+// `ef_search` values and IVF-PQ over a range of `nprobe` values, with and without re-ranking.
+//
+// Usage: cargo run --release --example index_recall [index/embeddings.safetensors [tensor_key]]
+// Without an embeddings file, runs on synthetic clustered vectors.
+use anyhow::Result;
+use candle::{Device, Tensor};
+use inference_server::search::hnsw::{HnswIndex, HnswParams};
+use inference_server::search::ivfpq::{self, IvfPqIndex, IvfPqParams};
+use inference_server::search::store::EMBEDDINGS_KEY;
+use inference_server::search::{dot, top_k, MappedVectors};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const NUM_QUERIES: usize = 200;
+const K: usize = 10;
+const EF_SEARCH: &[usize] = &[10, 20, 40, 80, 160, 320];
+const NPROBE: &[usize] = &[1, 4, 16, 64];
+// A search of one index with fixed parameters
+type Search<'a> = dyn Fn(&[f32]) -> Result<Vec<(usize, f32)>> + 'a;
+
+const RERANK: usize = ivfpq::DEFAULT_RERANK_FACTOR * K;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let mut rng = StdRng::seed_from_u64(7);
+    let key = args.get(2).map(String::as_str).unwrap_or(EMBEDDINGS_KEY);
+    let (vectors, dim, vectors_path) = match args.get(1) {
+        Some(path) => {
+            let tensors = candle::safetensors::load(path, &Device::Cpu)?;
+            let tensor = tensors
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("no tensor {:?} in {}", key, path))?;
+            let dim = tensor.dim(1)?;
+            (
+                tensor.flatten_all()?.to_vec1::<f32>()?,
+                dim,
+                PathBuf::from(path),
+            )
+        }
+        None => {
+            let (len, dim) = (20_000, 64);
+            let vectors = clustered_vectors(&mut rng, len, dim, 100);
+            let path = std::env::temp_dir().join("index_recall.safetensors");
+            Tensor::from_slice(&vectors, (len, dim), &Device::Cpu)?.save_safetensors(key, &path)?;
+            (vectors, dim, path)
+        }
+    };
+    let len = vectors.len() / dim;
+    println!("{} vectors of {} dimensions", len, dim);
+
+    // Queries near indexed vectors, like a paraphrase of an indexed passage
+    let queries: Vec<Vec<f32>> = (0..NUM_QUERIES)
+        .map(|_| {
+            let row = rng.gen_range(0..len);
+            let noisy: Vec<f32> = vectors[row * dim..(row + 1) * dim]
+                .iter()
+                .map(|value| value + rng.gen_range(-0.05..0.05))
+                .collect();
+            normalize(noisy)
+        })
+        .collect();
+
+    let mut exact_time = Duration::ZERO;
+    let exact: Vec<Vec<usize>> = queries
+        .iter()
+        .map(|query| {
+            let started = Instant::now();
+            let scores = vectors.chunks_exact(dim).map(|row| dot(row, query));
+            let best = top_k(scores.enumerate(), K);
+            exact_time += started.elapsed();
+            best.into_iter().map(|(row, _)| row).collect()
+        })
+        .collect();
+    println!(
+        "brute force: {:.2?} per query",
+        exact_time / NUM_QUERIES as u32
+    );
+    let evaluate = |name: String, search: &Search| {
+        let mut hits = 0;
+        let mut time = Duration::ZERO;
+        for (query, exact) in queries.iter().zip(&exact) {
+            let started = Instant::now();
+            let found = search(query)?;
+            time += started.elapsed();
+            hits += found.iter().filter(|(row, _)| exact.contains(row)).count();
+        }
+        println!(
+            "{}: recall@{} {:.4}, {:.2?} per query",
+            name,
+            K,
+            hits as f64 / (NUM_QUERIES * K) as f64,
+            time / NUM_QUERIES as u32
+        );
+        Ok::<_, anyhow::Error>(())
+    };
+
+    // Search the indexes as the server does, opened from disk
+    let started = Instant::now();
+    let path = std::env::temp_dir().join("index_recall.hnsw");
+    HnswIndex::build(&vectors, dim, HnswParams::default())?.save(&path)?;
+    println!("Built HNSW index in {:.2?}", started.elapsed());
+    let hnsw = HnswIndex::open(&path)?;
+    for &ef_search in EF_SEARCH {
+        evaluate(format!("hnsw ef_search {:>4}", ef_search), &|query| {
+            hnsw.search(query, K, ef_search, None)
+        })?;
+    }
+
+    let started = Instant::now();
+    let params = IvfPqParams::for_size(len, dim);
+    let path = std::env::temp_dir().join("index_recall.ivfpq");
+    IvfPqIndex::build(&vectors, dim, params)?.save(&path)?;
+    println!(
+        "Built IVF-PQ index in {:.2?} with {:?}",
+        started.elapsed(),
+        params
+    );
+    let codes_only = IvfPqIndex::open(&path)?;
+    let reranked =
+        IvfPqIndex::open(&path)?.with_vectors(MappedVectors::open(&vectors_path, key)?)?;
+    for &nprobe in NPROBE {
+        evaluate(
+            format!("ivfpq nprobe {:>3}, codes only", nprobe),
+            &|query| codes_only.search(query, K, nprobe, K, None),
+        )?;
+        evaluate(
+            format!("ivfpq nprobe {:>3}, rerank {}", nprobe, RERANK),
+            &|query| reranked.search(query, K, nprobe, RERANK, None),
+        )?;
+    }
+    Ok(())
+}
+
+// Normalized vectors scattered around `clusters` random centers, like topics of a corpus
+fn clustered_vectors(rng: &mut StdRng, len: usize, dim: usize, clusters: usize) -> Vec<f32> {
+    let centers: Vec<Vec<f32>> = (0..clusters)
+        .map(|_| (0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect())
+        .collect();
+    (0..len)
+        .flat_map(|_| {
+            let center = &centers[rng.gen_range(0..clusters)];
+            let vector = center
+                .iter()
+                .map(|value| value + rng.gen_range(-0.5..0.5))
+                .collect();
+            normalize(vector)
+        })
+        .collect()
+}
+
+fn normalize(vector: Vec<f32>) -> Vec<f32> {
+    let norm = dot(&vector, &vector).sqrt().max(f32::EPSILON);
+    vector.into_iter().map(|value| value / norm).collect()
+}
@@ -0,0 +1,157 @@
+// Adding, replacing and deleting the documents searched by /find_similar and /rag
+use crate::state::AppState;
+use actix_web::{web, HttpResponse, Responder};
+use inference_server::search::store::DocumentMeta;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+// Upper bound on the length of a document id
+const MAX_DOCUMENT_ID_BYTES: usize = 256;
+// Upper bound on the number of chunks one document is split into
+const MAX_DOCUMENT_CHUNKS: usize = 1024;
+// Chunks embedded per forward pass
+const EMBEDDING_BATCH_SIZE: usize = 32;
+
+#[derive(Deserialize)]
+pub struct UpsertDocumentRequest {
+    text: String,
+    #[serde(default)]
+    metadata: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+pub struct DocumentResponse {
+    id: String,
+    text: String,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    metadata: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+pub struct UpsertDocumentResponse {
+    id: String,
+    // Chunks the text was split into and embedded
+    chunks: usize,
+    // Whether the id was new, rather than an existing document replaced
+    created: bool,
+}
+
+// Why a document could not be stored
+enum DocumentError {
+    Invalid(String),
+    Failed(anyhow::Error),
+}
+
+fn document_not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("document {:?} not found", id))
+}
+
+pub async fn get_document(state: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
+    let corpus = state.corpus.read();
+    let Some((text, meta)) = corpus.get(&id) else {
+        return document_not_found(&id);
+    };
+    HttpResponse::Ok().json(DocumentResponse {
+        id: meta.id.clone(),
+        text: text.to_string(),
+        metadata: meta.metadata.clone(),
+    })
+}
+
+/*
+Adds the document `id`, or replaces the document with that id.
+
+The text is chunked like the indexed documents and every chunk is embedded with the BERT model.
+The change is logged to disk before it becomes visible to searches, and replaced chunks stop
+matching at the same time as the new ones start. Responds 201 for a new id and 200 otherwise.
+*/
+pub async fn upsert_document(
+    state: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: web::Json<UpsertDocumentRequest>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let payload = payload.into_inner();
+    if id.is_empty() || id.len() > MAX_DOCUMENT_ID_BYTES {
+        return HttpResponse::BadRequest().body(format!(
+            "id must be between 1 and {} bytes, got {}",
+            MAX_DOCUMENT_ID_BYTES,
+            id.len()
+        ));
+    }
+    if payload.text.trim().is_empty() {
+        return HttpResponse::BadRequest().body("text is empty");
+    }
+
+    let bert_model = Arc::clone(&state.bert_model);
+    let corpus = Arc::clone(&state.corpus);
+    let result = web::block(move || {
+        let chunks = corpus
+            .chunk(&bert_model, &payload.text)
+            .map_err(DocumentError::Failed)?;
+        if chunks.is_empty() || chunks.len() > MAX_DOCUMENT_CHUNKS {
+            return Err(DocumentError::Invalid(format!(
+                "text must split into between 1 and {} chunks, got {}",
+                MAX_DOCUMENT_CHUNKS,
+                chunks.len()
+            )));
+        }
+        let texts: Vec<String> = chunks
+            .iter()
+            .map(|range| payload.text[range.clone()].to_string())
+            .collect();
+        let mut embeddings = Vec::with_capacity(texts.len() * bert_model.hidden_size());
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            let batch_embeddings = bert_model
+                .create_embeddings(batch.to_vec())
+                .and_then(|tensor| Ok(tensor.flatten_all()?.to_vec1::<f32>()?))
+                .map_err(DocumentError::Failed)?;
+            embeddings.extend(batch_embeddings);
+        }
+        let document = DocumentMeta {
+            id: id.clone(),
+            metadata: payload.metadata,
+        };
+        let created = corpus
+            .upsert(document, payload.text, &chunks, embeddings)
+            .map_err(DocumentError::Failed)?;
+        Ok(UpsertDocumentResponse {
+            id,
+            chunks: chunks.len(),
+            created,
+        })
+    })
+    .await;
+
+    match result {
+        Ok(Ok(response)) if response.created => HttpResponse::Created().json(response),
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(DocumentError::Invalid(message))) => HttpResponse::BadRequest().body(message),
+        Ok(Err(DocumentError::Failed(e))) => {
+            eprintln!("Error storing document: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to store document")
+        }
+        Err(e) => {
+            eprintln!("Error storing document: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to store document")
+        }
+    }
+}
+
+pub async fn delete_document(state: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
+    let corpus = Arc::clone(&state.corpus);
+    let deleted_id = id.clone();
+    match web::block(move || corpus.delete(&deleted_id)).await {
+        Ok(Ok(true)) => HttpResponse::NoContent().finish(),
+        Ok(Ok(false)) => document_not_found(&id),
+        Ok(Err(e)) => {
+            eprintln!("Error deleting document: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to delete document")
+        }
+        Err(e) => {
+            eprintln!("Error deleting document: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to delete document")
+        }
+    }
+}
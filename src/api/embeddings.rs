@@ -0,0 +1,249 @@
+// Sentence embeddings over HTTP, in the format of OpenAI's /v1/embeddings
+use crate::state::AppState;
+use actix_web::{web, HttpResponse, Responder};
+use base64::Engine;
+use inference_server::chunking::ChunkStrategy;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Upper bound on the number of inputs in one request
+const MAX_EMBEDDING_INPUTS: usize = 256;
+// Upper bound on the number of chunks the inputs of one request are split into
+const MAX_EMBEDDING_CHUNKS: usize = 1024;
+// Inputs embedded per forward pass
+const EMBEDDING_BATCH_SIZE: usize = 32;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    // Little-endian f32 values, base64 encoded
+    Base64,
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingsRequest {
+    input: EmbeddingInput,
+    // Must name the loaded embedding model when set
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    encoding_format: EncodingFormat,
+    // Split inputs into chunks and embed each chunk, instead of rejecting inputs that are too long
+    #[serde(default)]
+    chunking: Option<ChunkStrategy>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Serialize)]
+pub struct Embedding {
+    object: &'static str,
+    index: usize,
+    embedding: EmbeddingVector,
+    // Where the embedded text comes from, when inputs are chunked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk: Option<ChunkSpan>,
+}
+
+// Character range of a chunk in input `input`
+#[derive(Serialize)]
+pub struct ChunkSpan {
+    input: usize,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingsResponse {
+    object: &'static str,
+    data: Vec<Embedding>,
+    model: String,
+    usage: EmbeddingUsage,
+}
+
+// Why a batch of inputs could not be embedded
+enum EmbeddingError {
+    Invalid(String),
+    Failed(anyhow::Error),
+}
+
+impl EmbeddingsRequest {
+    fn inputs(self) -> Vec<String> {
+        match self.input {
+            EmbeddingInput::Single(input) => vec![input],
+            EmbeddingInput::Batch(inputs) => inputs,
+        }
+    }
+}
+
+impl EncodingFormat {
+    fn encode(self, embedding: Vec<f32>) -> EmbeddingVector {
+        match self {
+            EncodingFormat::Float => EmbeddingVector::Float(embedding),
+            EncodingFormat::Base64 => {
+                let bytes: Vec<u8> = embedding
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect();
+                EmbeddingVector::Base64(base64::engine::general_purpose::STANDARD.encode(bytes))
+            }
+        }
+    }
+}
+
+/*
+Embeds one input or a list of inputs with the BERT model.
+
+Inputs are embedded in batches of `EMBEDDING_BATCH_SIZE` through `create_embeddings` and the
+vectors are L2-normalized. Every input must be non-empty and fit in the model's position
+embeddings, unless `chunking` is set: inputs are then split into chunks that fit and every chunk
+is embedded, with its input and character range. Usage counts the tokens of all embedded texts,
+including special tokens.
+*/
+pub async fn create_embeddings(
+    state: web::Data<AppState>,
+    payload: web::Json<EmbeddingsRequest>,
+) -> impl Responder {
+    let bert_model = Arc::clone(&state.bert_model);
+    let payload = payload.into_inner();
+    if let Some(model) = &payload.model {
+        if *model != bert_model.model_id {
+            return HttpResponse::BadRequest().body(format!(
+                "model {:?} is not served; use {:?}",
+                model, bert_model.model_id
+            ));
+        }
+    }
+    let encoding_format = payload.encoding_format;
+    let chunking = payload.chunking;
+    if let Some(chunking) = &chunking {
+        if let Err(message) = chunking.validate(bert_model.max_chunk_tokens()) {
+            return HttpResponse::BadRequest().body(message);
+        }
+    }
+    let inputs = payload.inputs();
+    if inputs.is_empty() || inputs.len() > MAX_EMBEDDING_INPUTS {
+        return HttpResponse::BadRequest().body(format!(
+            "input must hold between 1 and {} strings, got {}",
+            MAX_EMBEDDING_INPUTS,
+            inputs.len()
+        ));
+    }
+    if let Some(index) = inputs.iter().position(String::is_empty) {
+        return HttpResponse::BadRequest().body(format!("input {} is empty", index));
+    }
+
+    let result = web::block(move || {
+        // The texts to embed, with the chunk each one is when chunking
+        let (texts, spans) = match &chunking {
+            Some(chunking) => {
+                let mut texts = Vec::new();
+                let mut spans = Vec::new();
+                for (index, input) in inputs.iter().enumerate() {
+                    let ranges = chunking
+                        .split(bert_model.tokenizer(), input)
+                        .map_err(EmbeddingError::Failed)?;
+                    for range in ranges {
+                        let start = input[..range.start].chars().count();
+                        spans.push(Some(ChunkSpan {
+                            input: index,
+                            start,
+                            end: start + input[range.clone()].chars().count(),
+                        }));
+                        texts.push(input[range].to_string());
+                    }
+                }
+                if texts.len() > MAX_EMBEDDING_CHUNKS {
+                    return Err(EmbeddingError::Invalid(format!(
+                        "inputs split into {} chunks, more than the limit of {}",
+                        texts.len(),
+                        MAX_EMBEDDING_CHUNKS
+                    )));
+                }
+                (texts, spans)
+            }
+            None => {
+                let spans = inputs.iter().map(|_| None).collect();
+                (inputs, spans)
+            }
+        };
+
+        let mut prompt_tokens = 0;
+        for (index, text) in texts.iter().enumerate() {
+            let tokens = bert_model
+                .count_tokens(text)
+                .map_err(EmbeddingError::Failed)?;
+            if tokens > bert_model.max_input_tokens() {
+                return Err(EmbeddingError::Invalid(format!(
+                    "input {} has {} tokens, more than the limit of {}; set chunking to split it",
+                    index,
+                    tokens,
+                    bert_model.max_input_tokens()
+                )));
+            }
+            prompt_tokens += tokens;
+        }
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            let batch_embeddings = bert_model
+                .create_embeddings(batch.to_vec())
+                .and_then(|tensor| Ok(tensor.to_vec2::<f32>()?))
+                .map_err(EmbeddingError::Failed)?;
+            embeddings.extend(batch_embeddings);
+        }
+        Ok((embeddings, spans, prompt_tokens))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((embeddings, spans, prompt_tokens))) => HttpResponse::Ok().json(EmbeddingsResponse {
+            object: "list",
+            data: embeddings
+                .into_iter()
+                .zip(spans)
+                .enumerate()
+                .map(|(index, (embedding, chunk))| Embedding {
+                    object: "embedding",
+                    index,
+                    embedding: encoding_format.encode(embedding),
+                    chunk,
+                })
+                .collect(),
+            model: state.bert_model.model_id.clone(),
+            usage: EmbeddingUsage {
+                prompt_tokens,
+                total_tokens: prompt_tokens,
+            },
+        }),
+        Ok(Err(EmbeddingError::Invalid(message))) => HttpResponse::BadRequest().body(message),
+        Ok(Err(EmbeddingError::Failed(e))) => {
+            eprintln!("Error generating embeddings: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to generate embeddings")
+        }
+        Err(e) => {
+            eprintln!("Error generating embeddings: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to generate embeddings")
+        }
+    }
+}
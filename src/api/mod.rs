@@ -0,0 +1,551 @@
+use crate::state::AppState;
+use actix_web::http::header::{HeaderValue, CACHE_CONTROL};
+use actix_web::HttpRequest;
+use actix_web::{web, HttpResponse, Responder};
+use actix_web_lab::sse::{self, Data, Event};
+use inference_server::chunking::best_chunk_per_document;
+use inference_server::constraints::{ResponseFormat, TokenConstraint};
+use inference_server::models::candidates::{BeamSearch, Candidate, Candidates};
+use inference_server::models::llama::{
+    generate_next_tokens, ContextShift, GenerationState, LlamaInferenceModel, Truncate, Usage,
+};
+use inference_server::models::speculative::SpeculativeMode;
+use inference_server::search::filter::MetadataFilter;
+use inference_server::search::{reciprocal_rank_fusion, SearchMode, SearchParams};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub mod documents;
+pub mod embeddings;
+pub mod rag;
+pub mod sessions;
+pub mod tokenize;
+
+// Upper bound on `num_results` for a single similarity query
+const MAX_SIMILARITY_RESULTS: usize = 100;
+// Upper bound on `ef_search`; an approximate search visits at least that many vectors
+const MAX_EF_SEARCH: usize = 4096;
+// Upper bound on `nprobe`, the IVF lists scanned per query
+const MAX_NPROBE: usize = 1024;
+// Upper bound on `rerank`; each candidate reads a full vector from disk
+const MAX_RERANK: usize = 4096;
+// Chunks retrieved per requested result, so that documents with several matching chunks do not
+// crowd out the others
+const CHUNKS_PER_RESULT: usize = 4;
+// Upper bound on `max_length` when context shifting lets a generation outgrow the window
+const MAX_SHIFTED_GENERATION_LENGTH: usize = 16_384;
+// Upper bound on `n` and `num_beams`; every candidate keeps its own copy of the KV cache
+const MAX_CANDIDATES: usize = 8;
+
+#[derive(Serialize)]
+pub struct TopResult {
+    // Id of the document in the index
+    id: String,
+    item: String,
+    score: f32,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    metadata: Map<String, Value>,
+    // The best-matching part of `item`, when it was indexed in several chunks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    passage: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SimilarityResponse {
+    top_results: Vec<TopResult>,
+}
+
+#[derive(Deserialize)]
+pub struct SimilarityRequest {
+    text: String,
+    num_results: usize,
+    // Accuracy of an approximate index search (ef_search, nprobe, rerank); higher values are
+    // slower and more accurate
+    #[serde(flatten)]
+    search: SearchParams,
+    // Conditions on document ids and metadata that every result meets
+    #[serde(default)]
+    filter: Option<MetadataFilter>,
+    // Ranking by embeddings (default), by BM25 over the text, or both fused; `score` is then
+    // the cosine similarity, the BM25 score or the fused reciprocal rank score
+    #[serde(default)]
+    mode: SearchMode,
+}
+
+impl SimilarityRequest {
+    fn validate(&self) -> Result<(), String> {
+        if self.num_results == 0 || self.num_results > MAX_SIMILARITY_RESULTS {
+            return Err(format!(
+                "num_results must be between 1 and {}, got {}",
+                MAX_SIMILARITY_RESULTS, self.num_results
+            ));
+        }
+        if let Some(filter) = &self.filter {
+            filter.validate()?;
+        }
+        let search = &self.search;
+        for (name, value, max) in [
+            ("ef_search", search.ef_search, MAX_EF_SEARCH),
+            ("nprobe", search.nprobe, MAX_NPROBE),
+            ("rerank", search.rerank, MAX_RERANK),
+        ] {
+            if let Some(value) = value {
+                if value == 0 || value > max {
+                    return Err(format!(
+                        "{} must be between 1 and {}, got {}",
+                        name, max, value
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GenerateTextRequest {
+    prompt: String,
+    max_length: usize,
+    // How to shorten a prompt that does not fit in the context window; rejected when unset
+    #[serde(default)]
+    truncate: Option<Truncate>,
+    // Opt-in sliding window that lets the generation run past the context length
+    #[serde(default)]
+    context_shift: Option<ContextShift>,
+    // Sampling temperature; greedy decoding when unset or 0
+    #[serde(default)]
+    temperature: Option<f64>,
+    // Source of speculative proposals: the draft model (default), prompt lookup or off
+    #[serde(default)]
+    speculative: SpeculativeMode,
+    // Number of candidates to return: independent samples, or the best beams with beam_search
+    #[serde(default)]
+    n: Option<usize>,
+    #[serde(default)]
+    beam_search: Option<BeamSearch>,
+    // Structured output: constrain generation to JSON, a regex or a list of choices
+    #[serde(default)]
+    response_format: Option<ResponseFormat>,
+}
+
+impl GenerateTextRequest {
+    /*
+    Encodes the prompt and checks it against the model limits.
+
+    `max_length` must be at least 1 and leave room for one prompt token, and the prompt
+    plus `max_length` must fit in the model's context window. Over-long prompts are either
+    rejected with 400 or shortened according to `truncate`. With `context_shift` only the
+    prompt itself has to fit, since the window slides once it fills up.
+    */
+    fn prepare_prompt(&self, llama_model: &LlamaInferenceModel) -> Result<Vec<u32>, HttpResponse> {
+        if let Some(temperature) = self.temperature {
+            if !temperature.is_finite() || temperature < 0.0 {
+                return Err(HttpResponse::BadRequest().body(format!(
+                    "temperature must be a non-negative number, got {}",
+                    temperature
+                )));
+            }
+        }
+
+        let n = self.num_candidates();
+        let max_n = self
+            .beam_search
+            .map_or(MAX_CANDIDATES, |beam_search| beam_search.num_beams);
+        if n == 0 || n > max_n {
+            return Err(HttpResponse::BadRequest()
+                .body(format!("n must be between 1 and {}, got {}", max_n, n)));
+        }
+        if let Some(beam_search) = &self.beam_search {
+            beam_search
+                .validate(MAX_CANDIDATES)
+                .map_err(|message| HttpResponse::BadRequest().body(message))?;
+        }
+
+        let context_length = llama_model.context_length();
+        let max_length_limit = match &self.context_shift {
+            Some(context_shift) => {
+                context_shift
+                    .validate(context_length)
+                    .map_err(|message| HttpResponse::BadRequest().body(message))?;
+                MAX_SHIFTED_GENERATION_LENGTH
+            }
+            None => context_length - 1,
+        };
+        if self.max_length == 0 || self.max_length > max_length_limit {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "max_length must be between 1 and {}, got {}",
+                max_length_limit, self.max_length
+            )));
+        }
+
+        let mut tokens = llama_model.encode_prompt(&self.prompt).map_err(|e| {
+            eprintln!("Error encoding prompt: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to encode prompt")
+        })?;
+
+        let prompt_budget = match self.context_shift {
+            Some(_) => context_length - 1,
+            None => context_length - self.max_length,
+        };
+        if tokens.len() > prompt_budget {
+            let Some(truncate) = self.truncate else {
+                return Err(HttpResponse::BadRequest().body(format!(
+                    "prompt ({} tokens) plus max_length ({}) exceeds the context length of {} \
+                     tokens; shorten the prompt or set truncate to \"left\" or \"right\"",
+                    tokens.len(),
+                    self.max_length,
+                    context_length
+                )));
+            };
+            truncate.apply(&mut tokens, prompt_budget, llama_model.bos_token_id());
+        }
+        Ok(tokens)
+    }
+
+    // Compile `response_format` into a token constraint, rejecting invalid schemas with 400
+    fn prepare_constraint(
+        &self,
+        llama_model: &LlamaInferenceModel,
+    ) -> Result<Option<TokenConstraint>, HttpResponse> {
+        let Some(response_format) = &self.response_format else {
+            return Ok(None);
+        };
+        let constraint = llama_model.constraint(response_format).map_err(|message| {
+            HttpResponse::BadRequest().body(format!("invalid response_format: {}", message))
+        })?;
+        if constraint.is_some() && self.wants_candidates() {
+            return Err(HttpResponse::BadRequest()
+                .body("response_format is not supported with n > 1 or beam_search"));
+        }
+        Ok(constraint)
+    }
+
+    // Set up the decoding state of a single sequence with the request's sampling options
+    fn start_generation(
+        &self,
+        llama_model: &LlamaInferenceModel,
+        tokens: Vec<u32>,
+        constraint: Option<TokenConstraint>,
+    ) -> anyhow::Result<GenerationState> {
+        let mut state = llama_model.start_generation(tokens, 42, self.context_shift)?;
+        state.set_temperature(self.temperature);
+        state.set_speculative_mode(self.speculative);
+        state.set_constraint(constraint);
+        Ok(state)
+    }
+
+    fn num_candidates(&self) -> usize {
+        self.n.unwrap_or(1)
+    }
+
+    // Whether the request asks for several candidates or beam search rather than one sequence
+    fn wants_candidates(&self) -> bool {
+        self.num_candidates() > 1 || self.beam_search.is_some()
+    }
+
+    // Sample `n` candidates or run beam search, sharing one prefill of `tokens`
+    fn generate_candidates(
+        &self,
+        llama_model: &LlamaInferenceModel,
+        tokens: Vec<u32>,
+    ) -> anyhow::Result<Candidates> {
+        let mut state = llama_model.start_generation(tokens, 42, self.context_shift)?;
+        state.set_temperature(self.temperature);
+        match self.beam_search {
+            Some(beam_search) => llama_model.beam_search(
+                &mut state,
+                beam_search,
+                self.num_candidates(),
+                self.max_length,
+            ),
+            None => {
+                llama_model.generate_samples(&mut state, self.num_candidates(), self.max_length)
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GenerateTextResponse {
+    generated_text: String,
+    usage: Usage,
+    // Ranked candidates, best first, when more than one was requested or with beam search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidates: Option<Vec<Candidate>>,
+}
+
+pub async fn find_similar(
+    state: web::Data<AppState>,
+    payload: web::Json<SimilarityRequest>,
+) -> impl Responder {
+    if let Err(message) = payload.validate() {
+        return HttpResponse::BadRequest().body(message);
+    }
+    let bert_model = &state.bert_model;
+    let mode = payload.mode;
+
+    // Generate embedding for the input text, unless ranking by terms alone
+    let query_embedding = match mode {
+        SearchMode::Lexical => None,
+        SearchMode::Semantic | SearchMode::Hybrid => {
+            match bert_model.infer_sentence_embedding(&payload.text) {
+                Ok(embedding) => Some(embedding),
+                Err(_) => {
+                    return HttpResponse::InternalServerError().body("Failed to generate embedding")
+                }
+            }
+        }
+    };
+
+    // Retrieve the best chunks of the documents the filter accepts, then keep the best chunk of
+    // each document. Filtering happens during the search, so filtered queries still find
+    // `num_results` documents when that many match.
+    let corpus = state.corpus.read();
+    let num_chunks = payload.num_results * CHUNKS_PER_RESULT;
+    let semantic = match query_embedding {
+        Some(query_embedding) => match corpus.search(
+            query_embedding,
+            num_chunks,
+            &payload.search.or(&state.search_params),
+            payload.filter.as_ref(),
+        ) {
+            Ok(res) => Some(res),
+            Err(_) => {
+                return HttpResponse::InternalServerError()
+                    .body("Failed to score vector similarity")
+            }
+        },
+        None => None,
+    };
+    let lexical = match mode {
+        SearchMode::Semantic => None,
+        SearchMode::Lexical | SearchMode::Hybrid => {
+            Some(corpus.lexical_search(&payload.text, num_chunks, payload.filter.as_ref()))
+        }
+    };
+    let results: Vec<(usize, f32)> = match (semantic, lexical) {
+        (Some(semantic), Some(lexical)) => reciprocal_rank_fusion(&[semantic, lexical], num_chunks),
+        (Some(results), None) | (None, Some(results)) => results,
+        (None, None) => Vec::new(),
+    };
+
+    let top_results: Vec<TopResult> =
+        best_chunk_per_document(&corpus.chunks, &results, payload.num_results)
+            .into_iter()
+            .filter_map(|(chunk, score)| {
+                let item = corpus.documents.get(chunk.document)?;
+                let meta = corpus.metadata.get(chunk.document)?;
+                let passage = chunk
+                    .text(&corpus.documents)
+                    .filter(|passage| passage.len() < item.len());
+                Some(TopResult {
+                    id: meta.id.clone(),
+                    item: item.to_string(),
+                    score,
+                    metadata: meta.metadata.clone(),
+                    passage: passage.map(str::to_string),
+                })
+            })
+            .collect();
+
+    HttpResponse::Ok().json(SimilarityResponse { top_results })
+}
+
+pub async fn generate_text(
+    state: web::Data<AppState>,
+    payload: web::Json<GenerateTextRequest>,
+) -> impl Responder {
+    println!("Generating text for prompt: {}", payload.prompt);
+    let llama_model = &state.llama_model;
+    let tokens = match payload.prepare_prompt(llama_model) {
+        Ok(tokens) => tokens,
+        Err(response) => return response,
+    };
+    let constraint = match payload.prepare_constraint(llama_model) {
+        Ok(constraint) => constraint,
+        Err(response) => return response,
+    };
+
+    if payload.wants_candidates() {
+        return match payload.generate_candidates(llama_model, tokens) {
+            Ok(result) => HttpResponse::Ok().json(GenerateTextResponse {
+                generated_text: result
+                    .candidates
+                    .first()
+                    .map(|candidate| candidate.text.clone())
+                    .unwrap_or_default(),
+                usage: result.usage,
+                candidates: Some(result.candidates),
+            }),
+            Err(e) => {
+                eprintln!("Error generating candidates: {:?}", e);
+                HttpResponse::InternalServerError().body("Failed to generate text")
+            }
+        };
+    }
+
+    // Generate text using LLaMA model
+    let generation = payload
+        .start_generation(llama_model, tokens, constraint)
+        .and_then(|mut generation_state| {
+            let generation = llama_model.generate(&mut generation_state, payload.max_length)?;
+            Ok((generation, generation_state.constraint_satisfied()))
+        });
+    match generation {
+        Ok((_, false)) => HttpResponse::UnprocessableEntity()
+            .body("max_length was reached before the structured output was complete"),
+        Ok((generation, true)) => HttpResponse::Ok().json(GenerateTextResponse {
+            generated_text: generation.text,
+            usage: generation.usage,
+            candidates: None,
+        }),
+        Err(e) => {
+            eprintln!("Error generating text: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to generate text")
+        }
+    }
+}
+
+pub async fn generate_text_stream(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    payload: web::Json<GenerateTextRequest>,
+) -> impl Responder {
+    println!("Streaming text for prompt: {}", payload.prompt);
+    let llama_model = Arc::clone(&state.llama_model);
+    if payload.wants_candidates() {
+        return HttpResponse::BadRequest()
+            .body("n > 1 and beam_search are not supported when streaming");
+    }
+
+    // Encode and validate the prompt to create the initial tokens
+    let initial_tokens = match payload.prepare_prompt(&llama_model) {
+        Ok(tokens) => tokens,
+        Err(response) => return response,
+    };
+    println!("Initial Tokens: {:?}", initial_tokens);
+    let constraint = match payload.prepare_constraint(&llama_model) {
+        Ok(constraint) => constraint,
+        Err(response) => return response,
+    };
+
+    // Create a channel for sending SSE events
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+
+    // Spawn a task to generate tokens and send them as SSE events
+    actix_web::rt::spawn(async move {
+        // Initialize the KV cache and logits processor of the sequence
+        let generation_state =
+            match payload.start_generation(&llama_model, initial_tokens, constraint) {
+                Ok(state) => Arc::new(Mutex::new(state)),
+                Err(e) => {
+                    eprintln!("Error creating cache: {:?}", e);
+                    // Handle the error appropriately, e.g., send an error message through the channel
+                    if let Err(send_err) =
+                        tx.send(Event::Comment("Error creating cache".into())).await
+                    {
+                        eprintln!("Failed to send error message: {:?}", send_err);
+                    }
+                    return; // Exit the async block early
+                }
+            };
+
+        let (completion_tokens, _) =
+            stream_tokens(&llama_model, &generation_state, payload.max_length, &tx).await;
+
+        // Report token usage as a named event so plain `data:` consumers can ignore it
+        let (usage, constraint_satisfied) = match generation_state.lock() {
+            Ok(state) => (state.usage(completion_tokens), state.constraint_satisfied()),
+            Err(_) => return,
+        };
+        if !constraint_satisfied {
+            let _ = tx
+                .send(Event::Comment(
+                    "max_length was reached before the structured output was complete".into(),
+                ))
+                .await;
+        }
+        if let Ok(data) = Data::new_json(usage) {
+            let _ = tx.send(Event::Data(data.event("usage"))).await;
+        }
+    });
+
+    // Return the SSE response
+    sse_response(&req, rx)
+}
+
+// SSE response relaying the events sent through `rx`
+fn sse_response(req: &HttpRequest, rx: tokio::sync::mpsc::Receiver<Event>) -> HttpResponse {
+    sse::Sse::from_infallible_receiver(rx)
+        .with_keep_alive(Duration::from_secs(10)) // Optional: send keep-alive comments every 10 seconds
+        .customize()
+        .insert_header((CACHE_CONTROL, HeaderValue::from_static("no-transform")))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .respond_to(req)
+        .map_into_boxed_body()
+}
+
+/*
+Streams the tokens generated from `generation_state` as SSE data events, followed by an "EOS"
+event when the model stops on its own. Returns the number of generated tokens and their text;
+generation stops early when the client disconnects.
+*/
+async fn stream_tokens(
+    llama_model: &Arc<LlamaInferenceModel>,
+    generation_state: &Arc<Mutex<GenerationState>>,
+    max_length: usize,
+    tx: &tokio::sync::mpsc::Sender<Event>,
+) -> (usize, String) {
+    let eos_token_id = llama_model.eos_token_id();
+    let mut completion_tokens = 0;
+    let mut text = String::new();
+    'generation: while completion_tokens < max_length {
+        // A speculative step may yield several tokens at once
+        let next_tokens = match generate_next_tokens(
+            Arc::clone(llama_model),
+            Arc::clone(generation_state),
+            max_length - completion_tokens,
+        )
+        .await
+        {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                eprintln!("Error generating next token: {:?}", e);
+                let _ = tx
+                    .send(Event::Comment("Error generating next token".into()))
+                    .await;
+                break;
+            }
+        };
+
+        for next_token in next_tokens {
+            completion_tokens += 1;
+
+            // Check for EOS token
+            if Some(next_token) == eos_token_id {
+                println!("EOS token found");
+                let _ = tx.send(Event::Data(Data::new("EOS"))).await;
+                break 'generation;
+            }
+
+            let formatted_text = llama_model.decode_token(next_token);
+            println!("Generated token: {:?}", formatted_text);
+            text.push_str(&formatted_text);
+
+            let message = Event::Data(Data::new(formatted_text));
+            if tx.send(message).await.is_err() {
+                // Client disconnected
+                break 'generation;
+            }
+        }
+    }
+    (completion_tokens, text)
+}
+
+// Acceptance metrics of the draft model and of prompt lookup
+pub async fn speculative_metrics(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.llama_model.speculative_stats())
+}
@@ -0,0 +1,170 @@
+// Retrieval-augmented generation: answer questions from the passages of the embedding index
+use super::{sse_response, stream_tokens, CHUNKS_PER_RESULT};
+use crate::state::AppState;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web_lab::sse::{Data, Event};
+use inference_server::chunking::best_chunk_per_document;
+use inference_server::rag::{build_prompt, cited_passages, Passage};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+// Upper bound on `num_passages`; every passage takes room in the prompt
+const MAX_RAG_PASSAGES: usize = 10;
+
+#[derive(Deserialize)]
+pub struct RagRequest {
+    question: String,
+    max_length: usize,
+    // Passages retrieved for the question
+    #[serde(default = "RagRequest::default_num_passages")]
+    num_passages: usize,
+    // Sampling temperature; greedy decoding when unset or 0
+    #[serde(default)]
+    temperature: Option<f64>,
+}
+
+impl RagRequest {
+    fn default_num_passages() -> usize {
+        4
+    }
+
+    fn validate(&self, context_length: usize) -> Result<(), String> {
+        if self.num_passages == 0 || self.num_passages > MAX_RAG_PASSAGES {
+            return Err(format!(
+                "num_passages must be between 1 and {}, got {}",
+                MAX_RAG_PASSAGES, self.num_passages
+            ));
+        }
+        if self.max_length == 0 || self.max_length >= context_length {
+            return Err(format!(
+                "max_length must be between 1 and {}, got {}",
+                context_length - 1,
+                self.max_length
+            ));
+        }
+        if let Some(temperature) = self.temperature {
+            if !temperature.is_finite() || temperature < 0.0 {
+                return Err(format!(
+                    "temperature must be a non-negative number, got {}",
+                    temperature
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/*
+Answers `question` from the passages most similar to it.
+
+The question is embedded with the BERT model, and the best chunk of each of the `num_passages`
+best-matching documents is numbered and placed in the prompt; the lowest-ranked ones are
+dropped when the prompt plus `max_length` would not fit in the context window. The answer is streamed like
+`/generate_text_stream`, followed by a `citations` event listing the passages the answer cites
+as `[n]` and a `usage` event.
+*/
+pub async fn rag(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    payload: web::Json<RagRequest>,
+) -> impl Responder {
+    let llama_model = Arc::clone(&state.llama_model);
+    let context_length = llama_model.context_length();
+    if let Err(message) = payload.validate(context_length) {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    // Retrieve the passages most similar to the question
+    let bert_model = &state.bert_model;
+    let query_embedding = match bert_model.infer_sentence_embedding(&payload.question) {
+        Ok(embedding) => embedding,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to generate embedding"),
+    };
+    // Chunks are oversampled so that the passages come from different documents, rather than
+    // overlapping chunks of one long document
+    let corpus = state.corpus.read();
+    let results = match corpus.search(
+        query_embedding,
+        payload.num_passages * CHUNKS_PER_RESULT,
+        &state.search_params,
+        None,
+    ) {
+        Ok(res) => res,
+        Err(_) => {
+            return HttpResponse::InternalServerError().body("Failed to score vector similarity")
+        }
+    };
+    let mut passages: Vec<Passage> =
+        best_chunk_per_document(&corpus.chunks, &results, payload.num_passages)
+            .into_iter()
+            .filter_map(|(chunk, score)| Some((chunk.text(&corpus.documents)?, score)))
+            .enumerate()
+            .map(|(rank, (item, score))| Passage {
+                number: rank + 1,
+                item: item.to_string(),
+                score,
+            })
+            .collect();
+    drop(corpus);
+
+    // Keep as many passages as fit next to the question and the answer
+    let tokens = loop {
+        let prompt = build_prompt(&payload.question, &passages);
+        let tokens = match llama_model.encode_prompt(&prompt) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                eprintln!("Error encoding prompt: {:?}", e);
+                return HttpResponse::InternalServerError().body("Failed to encode prompt");
+            }
+        };
+        if tokens.len() + payload.max_length <= context_length {
+            break tokens;
+        }
+        if passages.pop().is_none() {
+            return HttpResponse::BadRequest().body(format!(
+                "question ({} tokens) plus max_length ({}) exceeds the context length of {} \
+                 tokens",
+                tokens.len(),
+                payload.max_length,
+                context_length
+            ));
+        }
+    };
+    println!(
+        "Answering question with {} passages: {}",
+        passages.len(),
+        payload.question
+    );
+
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    actix_web::rt::spawn(async move {
+        let generation_state = match llama_model.start_generation(tokens, 42, None) {
+            Ok(mut state) => {
+                state.set_temperature(payload.temperature);
+                Arc::new(Mutex::new(state))
+            }
+            Err(e) => {
+                eprintln!("Error creating cache: {:?}", e);
+                let _ = tx.send(Event::Comment("Error creating cache".into())).await;
+                return;
+            }
+        };
+
+        let (completion_tokens, answer) =
+            stream_tokens(&llama_model, &generation_state, payload.max_length, &tx).await;
+
+        let citations = cited_passages(&answer, &passages);
+        if let Ok(data) = Data::new_json(citations) {
+            let _ = tx.send(Event::Data(data.event("citations"))).await;
+        }
+        let usage = match generation_state.lock() {
+            Ok(state) => state.usage(completion_tokens),
+            Err(_) => return,
+        };
+        if let Ok(data) = Data::new_json(usage) {
+            let _ = tx.send(Event::Data(data.event("usage"))).await;
+        }
+    });
+
+    sse_response(&req, rx)
+}
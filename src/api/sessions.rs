@@ -0,0 +1,317 @@
+// Stateful chat sessions: the server keeps the history and KV cache between turns
+use super::MAX_SHIFTED_GENERATION_LENGTH;
+use crate::state::AppState;
+use actix_web::{web, HttpResponse, Responder};
+use inference_server::chat::{ChatMessage, Tool, ToolChoice};
+use inference_server::models::llama::Usage;
+use inference_server::sessions::Session;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, TryLockError};
+
+#[derive(Deserialize)]
+pub struct CreateSessionRequest {
+    #[serde(default)]
+    system_prompt: Option<String>,
+    // Slide the window past old turns (keeping the system prompt) instead of failing
+    #[serde(default)]
+    context_shift: bool,
+}
+
+#[derive(Serialize)]
+pub struct CreateSessionResponse {
+    session_id: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionHistoryResponse {
+    session_id: String,
+    messages: Vec<ChatMessage>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionGenerateRequest {
+    max_length: usize,
+    // Tools offered to the model for this reply
+    #[serde(default)]
+    tools: Vec<Tool>,
+    #[serde(default)]
+    tool_choice: ToolChoice,
+}
+
+#[derive(Serialize)]
+pub struct SessionGenerateResponse {
+    message: ChatMessage,
+    usage: Usage,
+}
+
+#[derive(Serialize)]
+pub struct SessionSnapshotResponse {
+    session_id: String,
+    path: String,
+}
+
+// Why a session operation could not complete
+enum TurnError {
+    Busy,
+    Invalid(String),
+    Failed(anyhow::Error),
+}
+
+fn session_not_found(session_id: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("session {} not found or expired", session_id))
+}
+
+pub async fn create_session(
+    state: web::Data<AppState>,
+    payload: web::Json<CreateSessionRequest>,
+) -> impl Responder {
+    let payload = payload.into_inner();
+    let session = Session::new(payload.system_prompt, payload.context_shift);
+    match state.sessions.create(session) {
+        Some(session_id) => HttpResponse::Created().json(CreateSessionResponse { session_id }),
+        None => HttpResponse::ServiceUnavailable().body("Too many active sessions"),
+    }
+}
+
+pub async fn get_session(
+    state: web::Data<AppState>,
+    session_id: web::Path<String>,
+) -> impl Responder {
+    let Some(session) = state.sessions.get(&session_id) else {
+        return session_not_found(&session_id);
+    };
+    let messages = match session.try_lock() {
+        Ok(session) => session.messages().to_vec(),
+        Err(TryLockError::WouldBlock) => {
+            return HttpResponse::Conflict().body("Session is generating a reply")
+        }
+        Err(TryLockError::Poisoned(e)) => e.into_inner().messages().to_vec(),
+    };
+    HttpResponse::Ok().json(SessionHistoryResponse {
+        session_id: session_id.into_inner(),
+        messages,
+    })
+}
+
+pub async fn delete_session(
+    state: web::Data<AppState>,
+    session_id: web::Path<String>,
+) -> impl Responder {
+    match state.sessions.remove(&session_id) {
+        true => HttpResponse::NoContent().finish(),
+        false => session_not_found(&session_id),
+    }
+}
+
+pub async fn append_message(
+    state: web::Data<AppState>,
+    session_id: web::Path<String>,
+    payload: web::Json<ChatMessage>,
+) -> impl Responder {
+    let message = payload.into_inner();
+    if let Err(error) = message.validate() {
+        return HttpResponse::BadRequest().body(error);
+    }
+    let Some(session) = state.sessions.get(&session_id) else {
+        return session_not_found(&session_id);
+    };
+    let mut session = match session.try_lock() {
+        Ok(session) => session,
+        Err(TryLockError::WouldBlock) => {
+            return HttpResponse::Conflict().body("Session is generating a reply")
+        }
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+    };
+    if let Some(tool_call_id) = &message.tool_call_id {
+        if !session.has_tool_call(tool_call_id) {
+            return HttpResponse::BadRequest()
+                .body(format!("no tool call {:?} in this session", tool_call_id));
+        }
+    }
+    session.push_message(message);
+    HttpResponse::Ok().json(SessionHistoryResponse {
+        session_id: session_id.into_inner(),
+        messages: session.messages().to_vec(),
+    })
+}
+
+/*
+Generates the assistant reply to the messages appended since the last turn.
+
+Only the new messages are prefilled; the rest of the conversation is already in the
+session's KV cache. Without context shifting the whole window plus `max_length` must fit in
+the model's context length.
+
+With `tools` (and unless `tool_choice` is "none") the tools are offered in a system message
+and the reply may instead be a call whose arguments follow the tool's parameter schema; its
+result is appended as a `tool` message before the next turn.
+*/
+pub async fn generate_reply(
+    state: web::Data<AppState>,
+    session_id: web::Path<String>,
+    payload: web::Json<SessionGenerateRequest>,
+) -> impl Responder {
+    let Some(session) = state.sessions.get(&session_id) else {
+        return session_not_found(&session_id);
+    };
+    let llama_model = Arc::clone(&state.llama_model);
+    let payload = payload.into_inner();
+    let max_length = payload.max_length;
+    let tool_matcher = match payload.tool_choice.matcher(&payload.tools) {
+        Ok(matcher) => matcher,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let result = web::block(move || {
+        let mut session = match session.try_lock() {
+            Ok(session) => session,
+            Err(TryLockError::WouldBlock) => return Err(TurnError::Busy),
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        };
+        if !session.has_pending_messages() {
+            return Err(TurnError::Invalid(
+                "no new messages since the last reply".to_string(),
+            ));
+        }
+
+        let context_length = llama_model.context_length();
+        let max_length_limit = match session.context_shift() {
+            true => MAX_SHIFTED_GENERATION_LENGTH,
+            false => context_length - 1,
+        };
+        if max_length == 0 || max_length > max_length_limit {
+            return Err(TurnError::Invalid(format!(
+                "max_length must be between 1 and {}, got {}",
+                max_length_limit, max_length
+            )));
+        }
+
+        let tools = tool_matcher.is_some().then_some(payload.tools.as_slice());
+        let pending = session
+            .pending_tokens(&llama_model, tools)
+            .map_err(TurnError::Failed)?;
+        let window_len = session.window_len() + pending.len();
+        if !session.context_shift() && window_len + max_length > context_length {
+            return Err(TurnError::Invalid(format!(
+                "conversation ({} tokens) plus max_length ({}) exceeds the context length of \
+                 {} tokens; start a new session or enable context_shift",
+                window_len, max_length, context_length
+            )));
+        }
+
+        // The turn is valid; only now does the history change
+        if let Some(tools) = tools {
+            session.offer_tools(tools);
+        }
+        let tool_constraint = tool_matcher.map(|matcher| llama_model.matcher_constraint(matcher));
+        session
+            .generate_reply(&llama_model, pending, max_length, 42, tool_constraint)
+            .map(|generation| SessionGenerateResponse {
+                message: session.messages()[session.messages().len() - 1].clone(),
+                usage: generation.usage,
+            })
+            .map_err(TurnError::Failed)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(TurnError::Busy)) => {
+            HttpResponse::Conflict().body("Session is already generating a reply")
+        }
+        Ok(Err(TurnError::Invalid(message))) => HttpResponse::BadRequest().body(message),
+        Ok(Err(TurnError::Failed(e))) => {
+            eprintln!("Error generating session reply: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to generate reply")
+        }
+        Err(e) => {
+            eprintln!("Error running session turn: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to generate reply")
+        }
+    }
+}
+
+// Write the session's history and KV cache to its snapshot file
+pub async fn snapshot_session(
+    state: web::Data<AppState>,
+    session_id: web::Path<String>,
+) -> impl Responder {
+    let Some(session) = state.sessions.get(&session_id) else {
+        return session_not_found(&session_id);
+    };
+    let path = match state.sessions.snapshot_path(&session_id) {
+        Ok(path) => path,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let llama_model = Arc::clone(&state.llama_model);
+
+    let snapshot_path = path.clone();
+    let result = web::block(move || {
+        let session = match session.try_lock() {
+            Ok(session) => session,
+            Err(TryLockError::WouldBlock) => return Err(TurnError::Busy),
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        };
+        session
+            .save(&llama_model, &snapshot_path)
+            .map_err(TurnError::Failed)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => HttpResponse::Ok().json(SessionSnapshotResponse {
+            session_id: session_id.into_inner(),
+            path: path.display().to_string(),
+        }),
+        Ok(Err(TurnError::Busy)) => HttpResponse::Conflict().body("Session is generating a reply"),
+        Ok(Err(TurnError::Invalid(message))) => HttpResponse::BadRequest().body(message),
+        Ok(Err(TurnError::Failed(e))) => {
+            eprintln!("Error saving session snapshot: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to save session snapshot")
+        }
+        Err(e) => {
+            eprintln!("Error saving session snapshot: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to save session snapshot")
+        }
+    }
+}
+
+/*
+Load a session from its snapshot file, e.g. after a restart or on another replica sharing the
+snapshot directory. A live session with the same id is replaced.
+*/
+pub async fn restore_session(
+    state: web::Data<AppState>,
+    session_id: web::Path<String>,
+) -> impl Responder {
+    let path = match state.sessions.snapshot_path(&session_id) {
+        Ok(path) => path,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if !path.exists() {
+        return HttpResponse::NotFound().body(format!("no snapshot for session {}", session_id));
+    }
+    let llama_model = Arc::clone(&state.llama_model);
+
+    let session = match web::block(move || Session::restore(&llama_model, &path, 42)).await {
+        Ok(Ok(session)) => session,
+        Ok(Err(e)) => {
+            eprintln!("Error restoring session snapshot: {:?}", e);
+            return HttpResponse::UnprocessableEntity()
+                .body(format!("Failed to restore session snapshot: {}", e));
+        }
+        Err(e) => {
+            eprintln!("Error restoring session snapshot: {:?}", e);
+            return HttpResponse::InternalServerError().body("Failed to restore session snapshot");
+        }
+    };
+
+    let messages = session.messages().to_vec();
+    if !state.sessions.insert(&session_id, session) {
+        return HttpResponse::ServiceUnavailable().body("Too many active sessions");
+    }
+    HttpResponse::Ok().json(SessionHistoryResponse {
+        session_id: session_id.into_inner(),
+        messages,
+    })
+}
@@ -0,0 +1,126 @@
+// Tokenize and detokenize text with the tokenizer of either loaded model
+use crate::state::AppState;
+use actix_web::{web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+#[derive(Deserialize)]
+pub struct TokenizeRequest {
+    text: String,
+    // Model whose tokenizer to use; the LLaMA model when unset
+    #[serde(default)]
+    model: Option<String>,
+    // Add the special tokens the model expects around an input (e.g. BOS, or [CLS] and [SEP])
+    #[serde(default = "default_true")]
+    add_special_tokens: bool,
+}
+
+#[derive(Serialize)]
+pub struct TokenizeResponse {
+    model: String,
+    ids: Vec<u32>,
+    tokens: Vec<String>,
+    // Character (not byte) range of the text each token covers; (0, 0) for added special tokens
+    offsets: Vec<(usize, usize)>,
+    // Whether the tokenizer added special tokens to the ones of the text
+    special_tokens_added: bool,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+pub struct DetokenizeRequest {
+    ids: Vec<u32>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default = "default_true")]
+    skip_special_tokens: bool,
+}
+
+#[derive(Serialize)]
+pub struct DetokenizeResponse {
+    model: String,
+    text: String,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// Id and tokenizer of the model named `model`, or of the LLaMA model when unset
+fn find_tokenizer<'a>(
+    state: &'a AppState,
+    model: Option<&str>,
+) -> Result<(&'a str, &'a Tokenizer), HttpResponse> {
+    let llama_model = &state.llama_model;
+    let bert_model = &state.bert_model;
+    match model {
+        None => Ok((&llama_model.model_id, llama_model.tokenizer())),
+        Some(model) if model == llama_model.model_id => {
+            Ok((&llama_model.model_id, llama_model.tokenizer()))
+        }
+        Some(model) if model == bert_model.model_id => {
+            Ok((&bert_model.model_id, bert_model.tokenizer()))
+        }
+        Some(model) => Err(HttpResponse::BadRequest().body(format!(
+            "model {:?} is not served; use {:?} or {:?}",
+            model, llama_model.model_id, bert_model.model_id
+        ))),
+    }
+}
+
+pub async fn tokenize(
+    state: web::Data<AppState>,
+    payload: web::Json<TokenizeRequest>,
+) -> impl Responder {
+    let (model, tokenizer) = match find_tokenizer(&state, payload.model.as_deref()) {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let encoding =
+        match tokenizer.encode_char_offsets(payload.text.as_str(), payload.add_special_tokens) {
+            Ok(encoding) => encoding,
+            Err(e) => {
+                eprintln!("Error tokenizing text: {:?}", e);
+                return HttpResponse::InternalServerError().body("Failed to tokenize text");
+            }
+        };
+
+    // Without special tokens requested the mask only flags special tokens written in the text
+    let special_tokens_added =
+        payload.add_special_tokens && encoding.get_special_tokens_mask().contains(&1);
+    HttpResponse::Ok().json(TokenizeResponse {
+        model: model.to_string(),
+        ids: encoding.get_ids().to_vec(),
+        tokens: encoding.get_tokens().to_vec(),
+        offsets: encoding.get_offsets().to_vec(),
+        special_tokens_added,
+        count: encoding.len(),
+    })
+}
+
+pub async fn detokenize(
+    state: web::Data<AppState>,
+    payload: web::Json<DetokenizeRequest>,
+) -> impl Responder {
+    let (model, tokenizer) = match find_tokenizer(&state, payload.model.as_deref()) {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let vocab_size = tokenizer.get_vocab_size(true);
+    if let Some(id) = payload.ids.iter().find(|&&id| id as usize >= vocab_size) {
+        return HttpResponse::BadRequest().body(format!(
+            "token id {} is out of range for a vocabulary of {} tokens",
+            id, vocab_size
+        ));
+    }
+    match tokenizer.decode(&payload.ids, payload.skip_special_tokens) {
+        Ok(text) => HttpResponse::Ok().json(DetokenizeResponse {
+            model: model.to_string(),
+            text,
+        }),
+        Err(e) => {
+            eprintln!("Error detokenizing ids: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to detokenize ids")
+        }
+    }
+}
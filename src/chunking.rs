@@ -0,0 +1,424 @@
+// Splitting long documents into chunks that fit the embedding model, and mapping chunks back
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use tokenizers::Tokenizer;
+
+/// How a document is cut into chunks; sizes are in tokens, without special tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum ChunkStrategy {
+    /// Fixed windows of `size` tokens, each repeating the last `overlap` tokens of the previous.
+    TokenWindow { size: usize, overlap: usize },
+    /// Whole sentences packed up to `max_tokens`; longer sentences are cut into windows.
+    Sentence { max_tokens: usize },
+    /// Whole paragraphs packed up to `max_tokens`; longer paragraphs are cut at sentences.
+    Paragraph { max_tokens: usize },
+}
+
+/// A chunk of a document: the byte range of its text in the document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Chunk {
+    pub document: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ChunkStrategy {
+    // Largest chunk the strategy produces, in tokens
+    pub fn max_tokens(&self) -> usize {
+        match *self {
+            ChunkStrategy::TokenWindow { size, .. } => size,
+            ChunkStrategy::Sentence { max_tokens } | ChunkStrategy::Paragraph { max_tokens } => {
+                max_tokens
+            }
+        }
+    }
+
+    // Checks the sizes, with `limit` the longest chunk the embedding model takes whole
+    pub fn validate(&self, limit: usize) -> Result<(), String> {
+        let max_tokens = self.max_tokens();
+        if max_tokens == 0 || max_tokens > limit {
+            return Err(format!(
+                "chunk size must be between 1 and {} tokens, got {}",
+                limit, max_tokens
+            ));
+        }
+        if let ChunkStrategy::TokenWindow { size, overlap } = *self {
+            if overlap >= size {
+                return Err(format!(
+                    "overlap must be less than the window size of {} tokens, got {}",
+                    size, overlap
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    Byte ranges of the chunks of `text`, in order.
+
+    Tokens are counted with `tokenizer`, which must not truncate or pad. Chunks start and end on
+    token boundaries, so whitespace between chunks is dropped; a text without tokens is a single
+    chunk.
+    */
+    pub fn split(&self, tokenizer: &Tokenizer, text: &str) -> anyhow::Result<Vec<Range<usize>>> {
+        let encoding = tokenizer.encode(text, false).map_err(anyhow::Error::msg)?;
+        let tokens: Vec<Range<usize>> = encoding
+            .get_offsets()
+            .iter()
+            .map(|&(start, end)| start..end)
+            .collect();
+        if tokens.is_empty() {
+            return Ok(std::iter::once(0..text.len()).collect());
+        }
+
+        let pieces = match *self {
+            ChunkStrategy::TokenWindow { size, overlap } => {
+                token_windows(0..tokens.len(), size, overlap)
+            }
+            ChunkStrategy::Sentence { max_tokens } => {
+                let sentences = split_points(&tokens, &sentence_starts(text));
+                pack(
+                    pieces(0..tokens.len(), &[&sentences], max_tokens),
+                    max_tokens,
+                )
+            }
+            ChunkStrategy::Paragraph { max_tokens } => {
+                let paragraphs = split_points(&tokens, &paragraph_starts(text));
+                let sentences = split_points(&tokens, &sentence_starts(text));
+                pack(
+                    pieces(0..tokens.len(), &[&paragraphs, &sentences], max_tokens),
+                    max_tokens,
+                )
+            }
+        };
+        Ok(pieces
+            .into_iter()
+            .map(|piece| tokens[piece.start].start..tokens[piece.end - 1].end)
+            .collect())
+    }
+}
+
+// Chunks of each of `documents`, in document order
+pub fn chunk_documents(
+    tokenizer: &Tokenizer,
+    strategy: &ChunkStrategy,
+    documents: &[String],
+) -> anyhow::Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    for (document, text) in documents.iter().enumerate() {
+        for range in strategy.split(tokenizer, text)? {
+            chunks.push(Chunk {
+                document,
+                start: range.start,
+                end: range.end,
+            });
+        }
+    }
+    Ok(chunks)
+}
+
+// One chunk per document holding all of its text, for indexes built without chunking
+pub fn whole_documents(documents: &[String]) -> Vec<Chunk> {
+    documents
+        .iter()
+        .enumerate()
+        .map(|(document, text)| Chunk {
+            document,
+            start: 0,
+            end: text.len(),
+        })
+        .collect()
+}
+
+impl Chunk {
+    pub fn text<'a>(&self, documents: &'a [String]) -> Option<&'a str> {
+        documents.get(self.document)?.get(self.start..self.end)
+    }
+}
+
+/*
+Aggregates chunk search results into document results.
+
+`results` are (chunk, score) pairs sorted best first; a document scores as its best chunk, which
+is returned with it. Keeps the `top_k` best documents.
+*/
+pub fn best_chunk_per_document(
+    chunks: &[Chunk],
+    results: &[(usize, f32)],
+    top_k: usize,
+) -> Vec<(Chunk, f32)> {
+    let mut documents: Vec<(Chunk, f32)> = Vec::new();
+    for &(chunk, score) in results {
+        let Some(chunk) = chunks.get(chunk) else {
+            continue;
+        };
+        if documents.len() == top_k {
+            break;
+        }
+        if !documents
+            .iter()
+            .any(|(seen, _)| seen.document == chunk.document)
+        {
+            documents.push((*chunk, score));
+        }
+    }
+    documents
+}
+
+// Windows of `size` tokens over `tokens`, `overlap` tokens apart from the previous window's end
+fn token_windows(tokens: Range<usize>, size: usize, overlap: usize) -> Vec<Range<usize>> {
+    let mut windows = Vec::new();
+    let mut start = tokens.start;
+    loop {
+        let end = (start + size).min(tokens.end);
+        windows.push(start..end);
+        if end == tokens.end {
+            return windows;
+        }
+        start += size - overlap;
+    }
+}
+
+/*
+Cuts `tokens` into pieces of at most `max_tokens`.
+
+A range that is too long is cut at the split points of the first level (e.g. paragraphs), and
+its parts that are still too long at those of the next level (e.g. sentences); without levels
+left, into windows.
+*/
+fn pieces(tokens: Range<usize>, levels: &[&[usize]], max_tokens: usize) -> Vec<Range<usize>> {
+    if tokens.len() <= max_tokens {
+        return vec![tokens];
+    }
+    let Some((splits, levels)) = levels.split_first() else {
+        return token_windows(tokens, max_tokens, 0);
+    };
+    let mut bounds = vec![tokens.start];
+    bounds.extend(
+        splits
+            .iter()
+            .copied()
+            .filter(|&split| split > tokens.start && split < tokens.end),
+    );
+    bounds.push(tokens.end);
+    bounds
+        .windows(2)
+        .flat_map(|bounds| pieces(bounds[0]..bounds[1], levels, max_tokens))
+        .collect()
+}
+
+// Merges consecutive pieces while they fit in `max_tokens`
+fn pack(pieces: Vec<Range<usize>>, max_tokens: usize) -> Vec<Range<usize>> {
+    let mut chunks: Vec<Range<usize>> = Vec::new();
+    for piece in pieces {
+        match chunks.last_mut() {
+            Some(chunk) if piece.end - chunk.start <= max_tokens => chunk.end = piece.end,
+            _ => chunks.push(piece),
+        }
+    }
+    chunks
+}
+
+// Indices of the first tokens starting at or after each of the byte offsets `starts`
+fn split_points(tokens: &[Range<usize>], starts: &[usize]) -> Vec<usize> {
+    let mut points: Vec<usize> = starts
+        .iter()
+        .map(|&start| tokens.partition_point(|token| token.start < start))
+        .collect();
+    points.dedup();
+    points
+}
+
+// Byte offsets where sentences start: after a line break, or after `.`, `!` or `?` (and any
+// closing quotes or brackets) followed by whitespace
+fn sentence_starts(text: &str) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if c == '\n' {
+            if let Some(&(next, _)) = chars.peek() {
+                starts.push(next);
+            }
+        } else if matches!(c, '.' | '!' | '?') {
+            while let Some(&(_, '"' | '\'' | ')' | ']' | '”' | '’')) = chars.peek() {
+                chars.next();
+            }
+            if let Some(&(next, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    starts.push(next);
+                }
+            }
+        }
+    }
+    starts
+}
+
+// Byte offsets where paragraphs start: after a blank line
+fn paragraph_starts(text: &str) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut blank_line = false;
+    let mut line_start = true;
+    for (index, c) in text.char_indices() {
+        if c == '\n' {
+            blank_line |= line_start;
+            line_start = true;
+        } else if !c.is_whitespace() {
+            if blank_line {
+                starts.push(index);
+            }
+            blank_line = false;
+            line_start = false;
+        }
+    }
+    starts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One token per word or run of punctuation, with byte offsets into the text
+    fn word_tokenizer() -> Tokenizer {
+        let json = r#"{
+            "version": "1.0",
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {"type": "WordLevel", "vocab": {"[UNK]": 0}, "unk_token": "[UNK]"}
+        }"#;
+        json.parse().unwrap()
+    }
+
+    fn chunks(strategy: ChunkStrategy, text: &str) -> Vec<&str> {
+        strategy
+            .split(&word_tokenizer(), text)
+            .unwrap()
+            .into_iter()
+            .map(|range| &text[range])
+            .collect()
+    }
+
+    #[test]
+    fn token_windows_repeat_the_overlap() {
+        assert_eq!(token_windows(0..10, 4, 1), vec![0..4, 3..7, 6..10]);
+        assert_eq!(token_windows(0..9, 4, 1), vec![0..4, 3..7, 6..9]);
+        assert_eq!(token_windows(0..4, 4, 1), vec![0..4]);
+        assert_eq!(token_windows(2..8, 3, 0), vec![2..5, 5..8]);
+        assert_eq!(token_windows(0..6, 4, 3), vec![0..4, 1..5, 2..6]);
+    }
+
+    #[test]
+    fn token_window_chunks_start_and_end_on_tokens() {
+        let text = "w0 w1 w2  w3 w4\tw5 w6 w7 w8 w9 ";
+        assert_eq!(
+            chunks(
+                ChunkStrategy::TokenWindow {
+                    size: 4,
+                    overlap: 1
+                },
+                text
+            ),
+            vec!["w0 w1 w2  w3", "w3 w4\tw5 w6", "w6 w7 w8 w9"]
+        );
+        assert_eq!(
+            chunks(
+                ChunkStrategy::TokenWindow {
+                    size: 20,
+                    overlap: 0
+                },
+                text
+            ),
+            vec!["w0 w1 w2  w3 w4\tw5 w6 w7 w8 w9"]
+        );
+    }
+
+    #[test]
+    fn sentence_chunks_pack_whole_sentences() {
+        let text = "One two three. Four five. Six seven eight nine ten eleven.";
+        assert_eq!(
+            chunks(ChunkStrategy::Sentence { max_tokens: 8 }, text),
+            vec![
+                "One two three. Four five.",
+                "Six seven eight nine ten eleven."
+            ]
+        );
+        // Sentences longer than a chunk are cut into windows
+        assert_eq!(
+            chunks(ChunkStrategy::Sentence { max_tokens: 5 }, text),
+            vec![
+                "One two three.",
+                "Four five.",
+                "Six seven eight nine ten",
+                "eleven."
+            ]
+        );
+        // Closing quotes stay with their sentence, and decimals do not end one
+        let text = "He said \"stop.\" Pi is 3.14 today.";
+        assert_eq!(
+            chunks(ChunkStrategy::Sentence { max_tokens: 7 }, text),
+            vec!["He said \"stop.\"", "Pi is 3.14 today."]
+        );
+    }
+
+    #[test]
+    fn paragraph_chunks_fall_back_to_sentences() {
+        let text = "A b. C d.\n\nE f g. H i.\n\nJ.";
+        assert_eq!(
+            chunks(ChunkStrategy::Paragraph { max_tokens: 7 }, text),
+            vec!["A b. C d.", "E f g. H i.", "J."]
+        );
+        // The second paragraph is too long and is cut at its sentences
+        assert_eq!(
+            chunks(ChunkStrategy::Paragraph { max_tokens: 6 }, text),
+            vec!["A b. C d.", "E f g.", "H i.\n\nJ."]
+        );
+    }
+
+    #[test]
+    fn every_document_gets_chunks_within_its_text() {
+        let documents = vec!["a b c d e".to_string(), String::new(), "f g".to_string()];
+        let strategy = ChunkStrategy::TokenWindow {
+            size: 3,
+            overlap: 1,
+        };
+        let chunks = chunk_documents(&word_tokenizer(), &strategy, &documents).unwrap();
+        let texts: Vec<(usize, &str)> = chunks
+            .iter()
+            .map(|chunk| (chunk.document, chunk.text(&documents).unwrap()))
+            .collect();
+        assert_eq!(texts, vec![(0, "a b c"), (0, "c d e"), (1, ""), (2, "f g")]);
+        assert_eq!(whole_documents(&documents)[2].text(&documents), Some("f g"));
+    }
+
+    #[test]
+    fn documents_score_as_their_best_chunk() {
+        let chunk = |document, start| Chunk {
+            document,
+            start,
+            end: start + 1,
+        };
+        let chunks = [chunk(0, 0), chunk(0, 5), chunk(1, 0), chunk(2, 0)];
+        let results = [(1, 0.9), (0, 0.8), (7, 0.75), (2, 0.7), (3, 0.6)];
+        assert_eq!(
+            best_chunk_per_document(&chunks, &results, 2),
+            vec![(chunk(0, 5), 0.9), (chunk(1, 0), 0.7)]
+        );
+        assert_eq!(best_chunk_per_document(&chunks, &results, 10).len(), 3);
+    }
+
+    #[test]
+    fn chunk_sizes_are_validated() {
+        let window = |size, overlap| ChunkStrategy::TokenWindow { size, overlap };
+        assert!(window(128, 16).validate(510).is_ok());
+        assert!(window(128, 128).validate(510).is_err());
+        assert!(window(0, 0).validate(510).is_err());
+        assert!(window(511, 0).validate(510).is_err());
+        assert!(ChunkStrategy::Paragraph { max_tokens: 510 }
+            .validate(510)
+            .is_ok());
+    }
+}
@@ -0,0 +1,977 @@
+/*
+Character-level JSON matcher driven by a JSON schema.
+
+The schema is compiled into a small arena of nodes, and the matcher keeps a stack of frames,
+one per value being generated (object, array, string, number or literal). Every character is
+checked against the innermost frame, so the generated text is valid JSON that validates
+against the schema once the matcher is complete.
+
+Supported keywords: `type` (a single type), `properties`, `required`, `items`, `minItems`,
+`maxItems`, `minLength`, `maxLength`, `enum` and `const`. Object properties are generated in
+the order the schema lists them; optional ones may be skipped and no other properties are
+produced. An object schema with `required` but no `properties` produces just the required
+properties, with any values. Whitespace between tokens and the length of numbers are capped so a
+generation cannot stall in either.
+*/
+use serde_json::Value;
+use std::sync::Arc;
+
+// Consecutive whitespace characters allowed between JSON tokens
+const MAX_WHITESPACE: usize = 16;
+// Characters allowed in a single number
+const MAX_NUMBER_CHARS: usize = 24;
+// Digits allowed in an exponent, which keeps numbers within the range of an f64
+const MAX_EXPONENT_DIGITS: u8 = 2;
+
+// Nodes every schema arena starts with, used for unconstrained values
+const ANY: usize = 0;
+const ANY_ARRAY: usize = 1;
+const KEYWORDS: usize = 2;
+
+// Keywords that would change which documents validate but are not enforced
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "$ref",
+    "allOf",
+    "anyOf",
+    "oneOf",
+    "not",
+    "if",
+    "pattern",
+    "patternProperties",
+    "additionalItems",
+    "prefixItems",
+    "uniqueItems",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "multipleOf",
+    "minProperties",
+    "maxProperties",
+];
+
+#[derive(Debug)]
+enum Node {
+    // Any JSON value
+    Any,
+    Integer,
+    Number,
+    String {
+        min_length: usize,
+        max_length: Option<usize>,
+    },
+    // One of a fixed set of serialized JSON values (`enum`, `const`, booleans and null)
+    Literals(Vec<Vec<char>>),
+    Array {
+        items: usize,
+        min_items: usize,
+        max_items: Option<usize>,
+    },
+    // Object with the listed properties: (quoted key, value node, required)
+    Object(Vec<(Vec<char>, usize, bool)>),
+    // Object with arbitrary keys and values
+    AnyObject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberState {
+    Start,
+    Minus,
+    Zero,
+    Integer,
+    Dot,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    Backslash,
+    // Hex digits still expected after `\u`
+    Unicode(u8),
+}
+
+#[derive(Debug, Clone)]
+enum ObjectState {
+    Start,
+    // Matching a quoted key against the properties in `candidates`
+    Key { candidates: Vec<usize>, pos: usize },
+    Colon { property: usize },
+    AfterValue,
+    Comma,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListState {
+    Start,
+    // A key of an arbitrary object has been read
+    Colon,
+    AfterValue,
+    Comma,
+}
+
+#[derive(Debug, Clone)]
+enum Frame {
+    // Before a value: optional whitespace, then its first character
+    Value {
+        node: usize,
+    },
+    Literals {
+        node: usize,
+        candidates: Vec<usize>,
+        pos: usize,
+        // Whether one of the candidates has been matched in full
+        matched: bool,
+    },
+    Number {
+        integer: bool,
+        state: NumberState,
+        chars: usize,
+    },
+    String {
+        min_length: usize,
+        max_length: Option<usize>,
+        length: usize,
+        escape: Escape,
+    },
+    Array {
+        node: usize,
+        count: usize,
+        state: ListState,
+    },
+    Object {
+        node: usize,
+        next_property: usize,
+        state: ObjectState,
+    },
+    AnyObject {
+        state: ListState,
+    },
+}
+
+// Outcome of feeding a character to the innermost frame
+enum Step {
+    Consumed,
+    Whitespace,
+    // The frame's value ended with this character
+    Done,
+    // The frame's value ended before this character, which belongs to the parent
+    DoneReprocess,
+    // Replace the frame, having consumed the character or not
+    Replace(Frame, bool),
+    // Start a nested value, having consumed the character or not
+    Push(Frame, bool),
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+pub struct JsonMatcher {
+    nodes: Arc<Vec<Node>>,
+    stack: Vec<Frame>,
+    whitespace: usize,
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\n' | '\t' | '\r')
+}
+
+fn number_can_end(state: NumberState) -> bool {
+    matches!(
+        state,
+        NumberState::Zero
+            | NumberState::Integer
+            | NumberState::Fraction
+            | NumberState::ExponentDigits(_)
+    )
+}
+
+fn literal(value: &Value) -> Vec<char> {
+    value.to_string().chars().collect()
+}
+
+// Schema compiler appending nodes to the arena
+struct Compiler {
+    nodes: Vec<Node>,
+}
+
+impl Compiler {
+    fn new() -> Self {
+        Self {
+            nodes: vec![
+                Node::Any,
+                Node::Array {
+                    items: ANY,
+                    min_items: 0,
+                    max_items: None,
+                },
+                Node::Literals(vec![
+                    literal(&Value::Bool(true)),
+                    literal(&Value::Bool(false)),
+                    literal(&Value::Null),
+                ]),
+            ],
+        }
+    }
+
+    fn push(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn compile(&mut self, schema: &Value) -> Result<usize, String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(ANY),
+            Value::Object(schema) => schema,
+            _ => return Err(format!("unsupported schema {}", schema)),
+        };
+        if let Some(keyword) = UNSUPPORTED_KEYWORDS
+            .iter()
+            .find(|keyword| schema.contains_key(**keyword))
+        {
+            return Err(format!("unsupported schema keyword {:?}", keyword));
+        }
+
+        if let Some(value) = schema.get("const") {
+            return Ok(self.push(Node::Literals(vec![literal(value)])));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .filter(|values| !values.is_empty())
+                .ok_or("enum must be a non-empty array")?;
+            return Ok(self.push(Node::Literals(values.iter().map(literal).collect())));
+        }
+
+        let usize_keyword = |keyword: &str| -> Result<Option<usize>, String> {
+            match schema.get(keyword) {
+                None => Ok(None),
+                Some(value) => value
+                    .as_u64()
+                    .map(|value| Some(value as usize))
+                    .ok_or_else(|| format!("{} must be a non-negative integer", keyword)),
+            }
+        };
+
+        // A schema listing properties without a type describes an object
+        let object_type = Value::from("object");
+        let kind = match schema.get("type") {
+            None if schema.contains_key("properties") => Some(&object_type),
+            kind => kind,
+        };
+        let node = match kind.map(|kind| (kind, kind.as_str())) {
+            None => return Ok(ANY),
+            Some((_, Some("null"))) => Node::Literals(vec![literal(&Value::Null)]),
+            Some((_, Some("boolean"))) => Node::Literals(vec![
+                literal(&Value::Bool(true)),
+                literal(&Value::Bool(false)),
+            ]),
+            Some((_, Some("integer"))) => Node::Integer,
+            Some((_, Some("number"))) => Node::Number,
+            Some((_, Some("string"))) => {
+                let min_length = usize_keyword("minLength")?.unwrap_or(0);
+                let max_length = usize_keyword("maxLength")?;
+                if max_length.is_some_and(|max_length| max_length < min_length) {
+                    return Err("maxLength must not be less than minLength".to_string());
+                }
+                Node::String {
+                    min_length,
+                    max_length,
+                }
+            }
+            Some((_, Some("array"))) => {
+                let items = match schema.get("items") {
+                    Some(items) => self.compile(items)?,
+                    None => ANY,
+                };
+                let min_items = usize_keyword("minItems")?.unwrap_or(0);
+                let max_items = usize_keyword("maxItems")?;
+                if max_items.is_some_and(|max_items| max_items < min_items) {
+                    return Err("maxItems must not be less than minItems".to_string());
+                }
+                Node::Array {
+                    items,
+                    min_items,
+                    max_items,
+                }
+            }
+            Some((_, Some("object"))) => {
+                let required: Vec<&str> = match schema.get("required") {
+                    None => Vec::new(),
+                    Some(required) => required
+                        .as_array()
+                        .and_then(|names| names.iter().map(Value::as_str).collect())
+                        .ok_or("required must be an array of property names")?,
+                };
+                match schema.get("properties") {
+                    None if required.is_empty() => Node::AnyObject,
+                    // Only the required properties, with any values
+                    None => Node::Object(
+                        required
+                            .iter()
+                            .enumerate()
+                            .filter(|&(index, name)| !required[..index].contains(name))
+                            .map(|(_, name)| name)
+                            .map(|name| (literal(&Value::String(name.to_string())), ANY, true))
+                            .collect(),
+                    ),
+                    Some(properties) => {
+                        let properties = properties
+                            .as_object()
+                            .ok_or("properties must be an object")?;
+                        if let Some(name) = required
+                            .iter()
+                            .find(|name| !properties.contains_key(**name))
+                        {
+                            return Err(format!("required property {:?} is not defined", name));
+                        }
+                        let mut compiled = Vec::with_capacity(properties.len());
+                        for (name, property) in properties {
+                            let key = literal(&Value::String(name.clone()));
+                            let value = self.compile(property)?;
+                            compiled.push((key, value, required.contains(&name.as_str())));
+                        }
+                        Node::Object(compiled)
+                    }
+                }
+            }
+            Some((kind, _)) => return Err(format!("unsupported schema type {}", kind)),
+        };
+        Ok(self.push(node))
+    }
+}
+
+impl JsonMatcher {
+    // Matcher for JSON values validating against `schema`
+    pub fn new(schema: &Value) -> Result<Self, String> {
+        let mut compiler = Compiler::new();
+        let root = compiler.compile(schema)?;
+        Ok(Self::with_root(compiler.nodes, root))
+    }
+
+    // Matcher for any JSON object
+    pub fn any_object() -> Self {
+        let mut compiler = Compiler::new();
+        let root = compiler.push(Node::AnyObject);
+        Self::with_root(compiler.nodes, root)
+    }
+
+    fn with_root(nodes: Vec<Node>, root: usize) -> Self {
+        Self {
+            nodes: Arc::new(nodes),
+            stack: vec![Frame::Value { node: root }],
+            whitespace: 0,
+        }
+    }
+
+    pub fn advance(&mut self, c: char) -> bool {
+        loop {
+            let Some(frame) = self.stack.last_mut() else {
+                // The value is complete; nothing may follow it
+                return false;
+            };
+            match frame.step(&self.nodes, c) {
+                Step::Consumed => break,
+                Step::Whitespace => {
+                    self.whitespace += 1;
+                    return self.whitespace <= MAX_WHITESPACE;
+                }
+                Step::Done => {
+                    self.stack.pop();
+                    break;
+                }
+                Step::DoneReprocess => {
+                    self.stack.pop();
+                }
+                Step::Replace(frame, consumed) => {
+                    self.stack.pop();
+                    self.stack.push(frame);
+                    if consumed {
+                        break;
+                    }
+                }
+                Step::Push(frame, consumed) => {
+                    self.stack.push(frame);
+                    if consumed {
+                        break;
+                    }
+                }
+                Step::Reject => return false,
+            }
+        }
+        self.whitespace = 0;
+        true
+    }
+
+    // Complete once the root value is closed (or is a number or literal that may end here)
+    pub fn is_complete(&self) -> bool {
+        self.stack.iter().all(Frame::can_end)
+    }
+}
+
+impl Frame {
+    fn can_end(&self) -> bool {
+        match self {
+            Frame::Number { state, .. } => number_can_end(*state),
+            Frame::Literals { matched, .. } => *matched,
+            _ => false,
+        }
+    }
+
+    fn step(&mut self, nodes: &[Node], c: char) -> Step {
+        match self {
+            Frame::Value { node } => Self::start_value(nodes, *node, c),
+            Frame::Literals {
+                node,
+                candidates,
+                pos,
+                matched,
+            } => {
+                let Node::Literals(literals) = &nodes[*node] else {
+                    return Step::Reject;
+                };
+                candidates.retain(|&i| literals[i].get(*pos) == Some(&c));
+                if candidates.is_empty() {
+                    return match matched {
+                        true => Step::DoneReprocess,
+                        false => Step::Reject,
+                    };
+                }
+                *pos += 1;
+                *matched = candidates.iter().any(|&i| literals[i].len() == *pos);
+                match candidates.iter().all(|&i| literals[i].len() == *pos) {
+                    true => Step::Done,
+                    false => Step::Consumed,
+                }
+            }
+            Frame::Number {
+                integer,
+                state,
+                chars,
+            } => {
+                let next = match (*state, c) {
+                    (NumberState::Start, '-') => Some(NumberState::Minus),
+                    (NumberState::Start | NumberState::Minus, '0') => Some(NumberState::Zero),
+                    (NumberState::Start | NumberState::Minus, '1'..='9') => {
+                        Some(NumberState::Integer)
+                    }
+                    (NumberState::Integer, '0'..='9') => Some(NumberState::Integer),
+                    (NumberState::Zero | NumberState::Integer, '.') if !*integer => {
+                        Some(NumberState::Dot)
+                    }
+                    (NumberState::Dot | NumberState::Fraction, '0'..='9') => {
+                        Some(NumberState::Fraction)
+                    }
+                    (
+                        NumberState::Zero | NumberState::Integer | NumberState::Fraction,
+                        'e' | 'E',
+                    ) if !*integer => Some(NumberState::Exponent),
+                    (NumberState::Exponent, '+' | '-') => Some(NumberState::ExponentSign),
+                    (NumberState::Exponent | NumberState::ExponentSign, '0'..='9') => {
+                        Some(NumberState::ExponentDigits(1))
+                    }
+                    (NumberState::ExponentDigits(digits), '0'..='9')
+                        if digits < MAX_EXPONENT_DIGITS =>
+                    {
+                        Some(NumberState::ExponentDigits(digits + 1))
+                    }
+                    _ => None,
+                };
+                match next {
+                    Some(next) if *chars < MAX_NUMBER_CHARS => {
+                        *state = next;
+                        *chars += 1;
+                        Step::Consumed
+                    }
+                    _ if number_can_end(*state) => Step::DoneReprocess,
+                    _ => Step::Reject,
+                }
+            }
+            Frame::String {
+                min_length,
+                max_length,
+                length,
+                escape,
+            } => match (*escape, c) {
+                (Escape::None, '"') if *length >= *min_length => Step::Done,
+                (Escape::None, '"') => Step::Reject,
+                _ if *escape == Escape::None && max_length.is_some_and(|max| *length >= max) => {
+                    Step::Reject
+                }
+                (Escape::None, '\\') => {
+                    *escape = Escape::Backslash;
+                    Step::Consumed
+                }
+                (Escape::None, c) if c < ' ' => Step::Reject,
+                (Escape::None, _) => {
+                    *length += 1;
+                    Step::Consumed
+                }
+                (Escape::Backslash, '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't') => {
+                    *escape = Escape::None;
+                    *length += 1;
+                    Step::Consumed
+                }
+                (Escape::Backslash, 'u') => {
+                    *escape = Escape::Unicode(4);
+                    Step::Consumed
+                }
+                (Escape::Unicode(remaining), c) if c.is_ascii_hexdigit() => {
+                    *escape = match remaining {
+                        1 => {
+                            *length += 1;
+                            Escape::None
+                        }
+                        _ => Escape::Unicode(remaining - 1),
+                    };
+                    Step::Consumed
+                }
+                _ => Step::Reject,
+            },
+            Frame::Array { node, count, state } => {
+                let Node::Array {
+                    items,
+                    min_items,
+                    max_items,
+                } = nodes[*node]
+                else {
+                    return Step::Reject;
+                };
+                let more_allowed = max_items.is_none_or(|max| *count < max);
+                match (*state, c) {
+                    (_, c) if is_whitespace(c) => Step::Whitespace,
+                    (ListState::Start | ListState::AfterValue, ']') if *count >= min_items => {
+                        Step::Done
+                    }
+                    (ListState::AfterValue, ',') if more_allowed => {
+                        *state = ListState::Comma;
+                        Step::Consumed
+                    }
+                    (ListState::Start | ListState::Comma, _) if more_allowed => {
+                        *count += 1;
+                        *state = ListState::AfterValue;
+                        Step::Push(Frame::Value { node: items }, false)
+                    }
+                    _ => Step::Reject,
+                }
+            }
+            Frame::Object {
+                node,
+                next_property,
+                state,
+            } => {
+                let Node::Object(properties) = &nodes[*node] else {
+                    return Step::Reject;
+                };
+                Self::object_step(properties, next_property, state, c)
+            }
+            Frame::AnyObject { state } => match (*state, c) {
+                (_, c) if is_whitespace(c) => Step::Whitespace,
+                (ListState::Start | ListState::AfterValue, '}') => Step::Done,
+                (ListState::Start | ListState::Comma, '"') => {
+                    *state = ListState::Colon;
+                    Step::Push(Self::string(0, None), true)
+                }
+                (ListState::Colon, ':') => {
+                    *state = ListState::AfterValue;
+                    Step::Push(Frame::Value { node: ANY }, true)
+                }
+                (ListState::AfterValue, ',') => {
+                    *state = ListState::Comma;
+                    Step::Consumed
+                }
+                _ => Step::Reject,
+            },
+        }
+    }
+
+    fn start_value(nodes: &[Node], node: usize, c: char) -> Step {
+        if is_whitespace(c) {
+            return Step::Whitespace;
+        }
+        let literals = |node: usize| Frame::Literals {
+            node,
+            candidates: match &nodes[node] {
+                Node::Literals(literals) => (0..literals.len()).collect(),
+                _ => Vec::new(),
+            },
+            pos: 0,
+            matched: false,
+        };
+        let number = |integer: bool| Frame::Number {
+            integer,
+            state: NumberState::Start,
+            chars: 0,
+        };
+        match (&nodes[node], c) {
+            (Node::Any, '{') | (Node::AnyObject, '{') => Step::Replace(
+                Frame::AnyObject {
+                    state: ListState::Start,
+                },
+                true,
+            ),
+            (Node::Any, '[') => Step::Replace(
+                Frame::Array {
+                    node: ANY_ARRAY,
+                    count: 0,
+                    state: ListState::Start,
+                },
+                true,
+            ),
+            (Node::Any, '"') => Step::Replace(Self::string(0, None), true),
+            (Node::Any, '-' | '0'..='9') => Step::Replace(number(false), false),
+            (Node::Any, 't' | 'f' | 'n') => Step::Replace(literals(KEYWORDS), false),
+            (Node::Integer, _) => Step::Replace(number(true), false),
+            (Node::Number, _) => Step::Replace(number(false), false),
+            (Node::Literals(_), _) => Step::Replace(literals(node), false),
+            (
+                Node::String {
+                    min_length,
+                    max_length,
+                },
+                '"',
+            ) => Step::Replace(Self::string(*min_length, *max_length), true),
+            (Node::Array { .. }, '[') => Step::Replace(
+                Frame::Array {
+                    node,
+                    count: 0,
+                    state: ListState::Start,
+                },
+                true,
+            ),
+            (Node::Object(_), '{') => Step::Replace(
+                Frame::Object {
+                    node,
+                    next_property: 0,
+                    state: ObjectState::Start,
+                },
+                true,
+            ),
+            _ => Step::Reject,
+        }
+    }
+
+    fn string(min_length: usize, max_length: Option<usize>) -> Frame {
+        Frame::String {
+            min_length,
+            max_length,
+            length: 0,
+            escape: Escape::None,
+        }
+    }
+
+    fn object_step(
+        properties: &[(Vec<char>, usize, bool)],
+        next_property: &mut usize,
+        state: &mut ObjectState,
+        c: char,
+    ) -> Step {
+        // Properties that may come next: up to and including the first required one
+        let remaining = &properties[*next_property..];
+        let can_close = remaining.iter().all(|(_, _, required)| !required);
+        let key_candidates = || {
+            let last = remaining
+                .iter()
+                .position(|(_, _, required)| *required)
+                .unwrap_or(remaining.len().saturating_sub(1));
+            (*next_property..(*next_property + last + 1).min(properties.len())).collect()
+        };
+
+        match state {
+            _ if is_whitespace(c) && !matches!(state, ObjectState::Key { .. }) => Step::Whitespace,
+            ObjectState::Start | ObjectState::AfterValue if c == '}' && can_close => Step::Done,
+            ObjectState::Start | ObjectState::Comma if c == '"' && !remaining.is_empty() => {
+                *state = ObjectState::Key {
+                    candidates: key_candidates(),
+                    pos: 1,
+                };
+                Step::Consumed
+            }
+            ObjectState::Key { candidates, pos } => {
+                candidates.retain(|&i| properties[i].0.get(*pos) == Some(&c));
+                *pos += 1;
+                match candidates.as_slice() {
+                    [] => Step::Reject,
+                    [property] if properties[*property].0.len() == *pos => {
+                        *state = ObjectState::Colon {
+                            property: *property,
+                        };
+                        Step::Consumed
+                    }
+                    _ => Step::Consumed,
+                }
+            }
+            ObjectState::Colon { property } if c == ':' => {
+                let (_, value, _) = properties[*property];
+                *next_property = *property + 1;
+                *state = ObjectState::AfterValue;
+                Step::Push(Frame::Value { node: value }, true)
+            }
+            ObjectState::AfterValue if c == ',' && !remaining.is_empty() => {
+                *state = ObjectState::Comma;
+                Step::Consumed
+            }
+            _ => Step::Reject,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Whether `schema` accepts `text` as a complete output, which must then parse
+    fn accepts(schema: Value, text: &str) -> bool {
+        let mut matcher = JsonMatcher::new(&schema).unwrap();
+        let accepted = text.chars().all(|c| matcher.advance(c)) && matcher.is_complete();
+        if accepted {
+            serde_json::from_str::<Value>(text).unwrap();
+        }
+        accepted
+    }
+
+    // The characters of `candidates` that may follow `prefix`, which `schema` must accept
+    fn next(schema: &Value, prefix: &str, candidates: &str) -> String {
+        let mut matcher = JsonMatcher::new(schema).unwrap();
+        assert!(prefix.chars().all(|c| matcher.advance(c)), "{}", prefix);
+        candidates
+            .chars()
+            .filter(|&c| matcher.clone().advance(c))
+            .collect()
+    }
+
+    fn is_complete(schema: &Value, text: &str) -> bool {
+        let mut matcher = JsonMatcher::new(schema).unwrap();
+        assert!(text.chars().all(|c| matcher.advance(c)), "{}", text);
+        matcher.is_complete()
+    }
+
+    #[test]
+    fn nested_objects_produce_properties_in_order_and_may_skip_optional_ones() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "address": {
+                    "properties": {"city": {"type": "string"}, "zip": {"type": "string"}},
+                    "required": ["city"],
+                },
+                "age": {"type": "integer"},
+            },
+            "required": ["name"],
+        });
+        assert!(accepts(schema.clone(), r#"{"name": "Ada"}"#));
+        assert!(accepts(schema.clone(), r#"{"name":"Ada","age":36}"#));
+        assert!(accepts(
+            schema.clone(),
+            r#"{ "name": "Ada", "address": {"city": "London", "zip": "W1"}, "age": 36 }"#
+        ));
+        assert!(accepts(
+            schema.clone(),
+            r#"{"name": "Ada", "address": {"city": "London"}}"#
+        ));
+        assert!(!accepts(schema.clone(), "{}"));
+        assert!(!accepts(schema.clone(), r#"{"age": 36, "name": "Ada"}"#));
+        assert!(!accepts(
+            schema.clone(),
+            r#"{"name": "Ada", "age": 36, "address": {}}"#
+        ));
+        assert!(!accepts(
+            schema.clone(),
+            r#"{"name": "Ada", "address": {"zip": "W1"}}"#
+        ));
+        assert!(!accepts(
+            schema.clone(),
+            r#"{"name": "Ada", "email": "a@b"}"#
+        ));
+        assert!(!accepts(schema.clone(), r#"{"name": "Ada", "age": 36,}"#));
+
+        // Only the required property can open the object, and it must be there to close it
+        assert_eq!(next(&schema, "{", "\"}a "), "\" ");
+        assert_eq!(next(&schema, "{\"", "nae"), "n");
+        assert_eq!(next(&schema, r#"{"name": "Ada""#, ",} ]"), ",} ");
+        assert_eq!(next(&schema, r#"{"name": "Ada", ""#, "anz"), "a");
+        assert_eq!(next(&schema, r#"{"name": "Ada", "a"#, "dgz"), "dg");
+        assert_eq!(next(&schema, r#"{"name": "Ada", "age": 36"#, ",}"), "}");
+        assert!(!is_complete(&schema, r#"{"name": "Ada""#));
+    }
+
+    #[test]
+    fn enum_and_const_produce_one_of_their_values() {
+        let schema = json!({"enum": ["red", "green", 1, 12, null]});
+        for value in [r#""red""#, r#""green""#, "1", "12", "null"] {
+            assert!(accepts(schema.clone(), value), "{}", value);
+        }
+        for value in [r#""re""#, r#""blue""#, "13", "2", "true"] {
+            assert!(!accepts(schema.clone(), value), "{}", value);
+        }
+        // 1 is complete, but may also continue as 12
+        assert!(is_complete(&schema, "1"));
+        assert_eq!(next(&schema, "1", "123"), "2");
+        assert_eq!(next(&schema, "\"", "rgb"), "rg");
+
+        let schema = json!({"type": "array", "items": {"enum": [1, 12]}});
+        assert!(accepts(schema.clone(), "[1, 12, 1]"));
+        assert!(!accepts(schema, "[13]"));
+
+        let schema = json!({"const": {"a": [1, "b"]}});
+        assert!(accepts(schema.clone(), r#"{"a":[1,"b"]}"#));
+        assert!(!accepts(schema.clone(), r#"{"a": [1, "b"]}"#));
+        assert!(!accepts(schema, r#"{"a":[1]}"#));
+
+        let schema = json!({"type": "boolean"});
+        assert!(accepts(schema.clone(), "true") && accepts(schema.clone(), "false"));
+        assert!(!accepts(schema, "null"));
+    }
+
+    #[test]
+    fn numbers_follow_the_json_grammar() {
+        let number = json!({"type": "number"});
+        for value in [
+            "0", "-0", "7", "-12", "12.5", "0.25", "1e5", "1E+10", "-3.25e-2", "2e09",
+        ] {
+            assert!(accepts(number.clone(), value), "{}", value);
+        }
+        for value in [
+            "01", "-", "+1", ".5", "1.", "1.e2", "1e", "1e+", "1e100", "--1", "1.2.3",
+        ] {
+            assert!(!accepts(number.clone(), value), "{}", value);
+        }
+        assert_eq!(next(&number, "", "-0123+.e\""), "-0123");
+        assert_eq!(next(&number, "0", "0123.eE"), ".eE");
+        assert_eq!(next(&number, "1", "0.eE+"), "0.eE");
+        assert_eq!(next(&number, "1e", "+-05."), "+-05");
+        assert!(!is_complete(&number, "1.") && !is_complete(&number, "-"));
+        assert!(!accepts(number.clone(), &"1".repeat(MAX_NUMBER_CHARS + 1)));
+        assert!(accepts(number, &"1".repeat(MAX_NUMBER_CHARS)));
+
+        let integer = json!({"type": "integer"});
+        assert!(accepts(integer.clone(), "-42"));
+        assert!(!accepts(integer.clone(), "1.5"));
+        assert!(!accepts(integer.clone(), "1e3"));
+        assert_eq!(next(&integer, "4", "2.eE,"), "2");
+
+        // A number ends at the first character that cannot continue it
+        let array = json!({"type": "array", "items": {"type": "number"}});
+        assert!(accepts(array.clone(), "[1,-2.5e3, 0]"));
+        assert!(!accepts(array, "[01]"));
+    }
+
+    #[test]
+    fn strings_take_escapes_and_respect_their_length() {
+        let schema = json!({"type": "string", "minLength": 2, "maxLength": 3});
+        for value in [
+            r#""ab""#,
+            r#""abc""#,
+            r#""a\nb""#,
+            r#""\u00e9x""#,
+            r#""\"\\""#,
+        ] {
+            assert!(accepts(schema.clone(), value), "{}", value);
+        }
+        for value in [
+            r#""a""#,
+            r#""abcd""#,
+            r#""\x1""#,
+            r#""\u12g4""#,
+            "\"a\nb\"",
+            "ab",
+        ] {
+            assert!(!accepts(schema.clone(), value), "{}", value);
+        }
+        // An escape counts as one character
+        assert_eq!(next(&schema, r#""a"#, "\"b\\"), "b\\");
+        assert_eq!(next(&schema, r#""a\t"#, "\"b"), "\"b");
+        assert_eq!(next(&schema, r#""abc"#, "\"d\\"), "\"");
+        assert_eq!(next(&schema, r#""a\"#, "nu\"/x"), "nu\"/");
+        assert_eq!(next(&schema, r#""a\u0"#, "0aF\"g"), "0aF");
+
+        let any = json!({"type": "string"});
+        assert!(accepts(any.clone(), r#""""#));
+        assert!(accepts(any, r#""héllo wörld""#));
+    }
+
+    #[test]
+    fn arrays_hold_between_min_and_max_items() {
+        let schema = json!({
+            "type": "array",
+            "items": {"type": "integer"},
+            "minItems": 1,
+            "maxItems": 2,
+        });
+        assert!(accepts(schema.clone(), "[1]"));
+        assert!(accepts(schema.clone(), "[ 1 , 2 ]"));
+        assert!(!accepts(schema.clone(), "[]"));
+        assert!(!accepts(schema.clone(), "[1,2,3]"));
+        assert!(!accepts(schema.clone(), "[1,]"));
+        assert!(!accepts(schema.clone(), "[\"a\"]"));
+        assert_eq!(next(&schema, "[", "]1\""), "1");
+        assert_eq!(next(&schema, "[1", ",]"), ",]");
+        assert_eq!(next(&schema, "[1,2", ",]"), "]");
+
+        let nested = json!({"type": "array", "items": {"type": "array", "maxItems": 1}});
+        assert!(accepts(nested.clone(), r#"[[], [{"a": [true]}]]"#));
+        assert!(!accepts(nested, "[[1, 2]]"));
+    }
+
+    #[test]
+    fn any_object_takes_any_members_and_nothing_after_it() {
+        let mut matcher = JsonMatcher::any_object();
+        let text = r#"{"a": [1, {"b": null}], "c": "d", "e": -1.5e3, "f": false}"#;
+        assert!(text.chars().all(|c| matcher.advance(c)));
+        assert!(matcher.is_complete());
+        assert!(!matcher.clone().advance(' '));
+        assert!(!matcher.advance('{'));
+        assert!(!JsonMatcher::any_object().advance('['));
+        assert!(!is_complete(&json!({}), "[1"));
+    }
+
+    #[test]
+    fn whitespace_between_tokens_is_capped() {
+        let schema = json!({"type": "array"});
+        let spaced = format!("[{}1]", " ".repeat(MAX_WHITESPACE));
+        assert!(accepts(schema.clone(), &spaced));
+        let stalled = format!("[{}1]", " ".repeat(MAX_WHITESPACE + 1));
+        assert!(!accepts(schema, &stalled));
+    }
+
+    #[test]
+    fn rejects_schemas_it_cannot_enforce() {
+        for schema in [
+            json!({"type": "string", "pattern": "a+"}),
+            json!({"type": ["string", "null"]}),
+            json!({"type": "string", "minLength": 3, "maxLength": 2}),
+            json!({"type": "array", "minItems": -1}),
+            json!({"properties": {"a": {}}, "required": ["b"]}),
+            json!({"enum": []}),
+            json!({"anyOf": [{"type": "string"}]}),
+            json!("string"),
+        ] {
+            assert!(JsonMatcher::new(&schema).is_err(), "{}", schema);
+        }
+        // An empty schema allows any value
+        assert!(accepts(json!({}), r#"[1, "a", {"b": null}]"#));
+        assert!(accepts(json!({}), "true"));
+    }
+
+    #[test]
+    fn objects_with_only_required_properties_produce_them() {
+        let schema = json!({"type": "object", "required": ["id", "tags", "id"]});
+        assert!(accepts(
+            schema.clone(),
+            r#"{"id": 7, "tags": ["a", {"b": null}]}"#
+        ));
+        assert!(!accepts(schema.clone(), "{}"));
+        assert!(!accepts(schema.clone(), r#"{"id": 7}"#));
+        assert!(!accepts(schema.clone(), r#"{"tags": [], "id": 7}"#));
+        assert!(!accepts(schema, r#"{"id": 7, "tags": [], "id": 8}"#));
+        assert!(accepts(
+            json!({"type": "object", "required": []}),
+            r#"{"x": 1}"#
+        ));
+        assert!(JsonMatcher::new(&json!({"type": "object", "required": "id"})).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+/*
+Constrained decoding: restrict generation to outputs accepted by a character-level matcher.
+
+A `Matcher` consumes the generated text one character at a time and rejects characters that
+cannot lead to a valid output. At every step the logits of tokens whose text the matcher
+rejects are masked out, and EOS is only allowed once the matcher accepts the text generated so
+far. Candidate tokens are found by walking a character trie of the vocabulary with the
+matcher, so token texts sharing a prefix are checked once and rejected prefixes are pruned.
+*/
+use candle::Tensor;
+use serde::Deserialize;
+use std::sync::Arc;
+
+pub mod json_schema;
+pub mod regex;
+pub mod tool_call;
+
+use self::regex::RegexMatcher;
+use json_schema::JsonMatcher;
+use tool_call::ToolCallMatcher;
+
+/// Shape the generated text must have.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Unconstrained text.
+    Text,
+    /// Any JSON object.
+    JsonObject,
+    /// A JSON value validating against `schema`.
+    JsonSchema { schema: serde_json::Value },
+    /// Text matching `pattern` as a whole.
+    Regex { pattern: String },
+    /// Exactly one of `choices`.
+    Choice { choices: Vec<String> },
+}
+
+// Incremental matcher of the text generated so far
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Json(JsonMatcher),
+    Regex(RegexMatcher),
+    ToolCall(ToolCallMatcher),
+}
+
+impl ResponseFormat {
+    // Compile the format into a matcher, or `None` for unconstrained text
+    pub fn matcher(&self) -> Result<Option<Matcher>, String> {
+        match self {
+            ResponseFormat::Text => Ok(None),
+            ResponseFormat::JsonObject => Ok(Some(Matcher::Json(JsonMatcher::any_object()))),
+            ResponseFormat::JsonSchema { schema } => {
+                Ok(Some(Matcher::Json(JsonMatcher::new(schema)?)))
+            }
+            ResponseFormat::Regex { pattern } => {
+                Ok(Some(Matcher::Regex(RegexMatcher::new(pattern)?)))
+            }
+            ResponseFormat::Choice { choices } => {
+                Ok(Some(Matcher::Regex(RegexMatcher::choice(choices)?)))
+            }
+        }
+    }
+}
+
+impl Matcher {
+    // Consume `c`, returning false when no valid output continues with it
+    fn advance(&mut self, c: char) -> bool {
+        match self {
+            Matcher::Json(matcher) => matcher.advance(c),
+            Matcher::Regex(matcher) => matcher.advance(c),
+            Matcher::ToolCall(matcher) => matcher.advance(c),
+        }
+    }
+
+    // Whether the text consumed so far is a complete valid output
+    fn is_complete(&self) -> bool {
+        match self {
+            Matcher::Json(matcher) => matcher.is_complete(),
+            Matcher::Regex(matcher) => matcher.is_complete(),
+            Matcher::ToolCall(matcher) => matcher.is_complete(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    // Tokens whose text ends at this node
+    tokens: Vec<u32>,
+}
+
+// Text of every token that can appear in constrained output, arranged as a character trie
+pub struct TokenVocabulary {
+    nodes: Vec<TrieNode>,
+    texts: Vec<Option<String>>,
+}
+
+impl TokenVocabulary {
+    // Build from `(token id, text)` pairs; tokens without text are never generated under a
+    // constraint (apart from EOS)
+    pub fn new(vocab_size: usize, tokens: impl IntoIterator<Item = (u32, String)>) -> Self {
+        let mut vocabulary = Self {
+            nodes: vec![TrieNode::default()],
+            texts: vec![None; vocab_size],
+        };
+        for (token, text) in tokens {
+            if text.is_empty() || token as usize >= vocab_size {
+                continue;
+            }
+            let mut node = 0;
+            for c in text.chars() {
+                node = match vocabulary.nodes[node]
+                    .children
+                    .iter()
+                    .find(|(ch, _)| *ch == c)
+                {
+                    Some(&(_, child)) => child,
+                    None => {
+                        vocabulary.nodes.push(TrieNode::default());
+                        let child = vocabulary.nodes.len() - 1;
+                        vocabulary.nodes[node].children.push((c, child));
+                        child
+                    }
+                };
+            }
+            vocabulary.nodes[node].tokens.push(token);
+            vocabulary.texts[token as usize] = Some(text);
+        }
+        vocabulary
+    }
+
+    // Tokens whose whole text `matcher` accepts
+    fn allowed_tokens(&self, matcher: &Matcher) -> Vec<u32> {
+        let mut allowed = Vec::new();
+        let mut pending = vec![(0, matcher.clone())];
+        while let Some((node, matcher)) = pending.pop() {
+            for &(c, child) in &self.nodes[node].children {
+                let mut matcher = matcher.clone();
+                if matcher.advance(c) {
+                    allowed.extend_from_slice(&self.nodes[child].tokens);
+                    pending.push((child, matcher));
+                }
+            }
+        }
+        allowed
+    }
+}
+
+/// Per-sequence constraint state: the matcher plus the vocabulary used to mask logits.
+#[derive(Clone)]
+pub struct TokenConstraint {
+    vocabulary: Arc<TokenVocabulary>,
+    matcher: Matcher,
+    eos_token_id: Option<u32>,
+}
+
+impl TokenConstraint {
+    pub fn new(
+        vocabulary: Arc<TokenVocabulary>,
+        matcher: Matcher,
+        eos_token_id: Option<u32>,
+    ) -> Self {
+        Self {
+            vocabulary,
+            matcher,
+            eos_token_id,
+        }
+    }
+
+    // Set the logits of every token the constraint rejects to -inf
+    pub fn mask(&self, logits: &Tensor) -> anyhow::Result<Tensor> {
+        let mut allowed = self.vocabulary.allowed_tokens(&self.matcher);
+        if self.matcher.is_complete() {
+            allowed.extend(self.eos_token_id);
+        }
+        if allowed.is_empty() {
+            anyhow::bail!("no token can continue the constrained output");
+        }
+
+        let values = logits.to_vec1::<f32>()?;
+        let mut masked = vec![f32::NEG_INFINITY; values.len()];
+        for token in allowed {
+            if let Some(value) = values.get(token as usize) {
+                masked[token as usize] = *value;
+            }
+        }
+        Ok(Tensor::new(masked, logits.device())?)
+    }
+
+    // Feed the text of a generated token to the matcher
+    pub fn advance(&mut self, token: u32) -> anyhow::Result<()> {
+        if Some(token) == self.eos_token_id {
+            return Ok(());
+        }
+        let text = self
+            .vocabulary
+            .texts
+            .get(token as usize)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| anyhow::anyhow!("token {} is not allowed by the constraint", token))?;
+        for c in text.chars() {
+            if !self.matcher.advance(c) {
+                anyhow::bail!("token {} violates the constraint", token);
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the output generated so far satisfies the constraint
+    pub fn is_complete(&self) -> bool {
+        self.matcher.is_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    const EOS: u32 = 7;
+
+    fn constrained(matcher: Matcher) -> TokenConstraint {
+        let texts = ["{", "}", "\"a\"", ":", "1", "x", "{}"];
+        let vocabulary = TokenVocabulary::new(
+            8,
+            texts
+                .iter()
+                .enumerate()
+                .map(|(token, text)| (token as u32, text.to_string())),
+        );
+        TokenConstraint::new(Arc::new(vocabulary), matcher, Some(EOS))
+    }
+
+    // Tokens the constraint leaves unmasked
+    fn allowed(constraint: &TokenConstraint) -> Vec<u32> {
+        let logits = Tensor::zeros(8, candle::DType::F32, &Device::Cpu).unwrap();
+        let masked = constraint.mask(&logits).unwrap().to_vec1::<f32>().unwrap();
+        (0..8)
+            .filter(|&token| masked[token as usize] == 0.0)
+            .collect()
+    }
+
+    #[test]
+    fn masks_tokens_the_matcher_rejects_and_allows_eos_once_complete() {
+        let mut constraint = constrained(Matcher::Json(JsonMatcher::any_object()));
+        assert_eq!(allowed(&constraint), [0, 6]);
+        constraint.advance(0).unwrap();
+        assert_eq!(allowed(&constraint), [1, 2]);
+        for token in [2, 3, 4] {
+            constraint.advance(token).unwrap();
+        }
+        assert!(!constraint.is_complete());
+        assert_eq!(allowed(&constraint), [1, 4]);
+        constraint.advance(1).unwrap();
+        assert!(constraint.is_complete());
+        assert_eq!(allowed(&constraint), [EOS]);
+        constraint.advance(EOS).unwrap();
+    }
+
+    #[test]
+    fn rejects_tokens_outside_the_constraint() {
+        let mut constraint = constrained(Matcher::Json(JsonMatcher::any_object()));
+        assert!(constraint.clone().advance(5).is_err());
+        // A token whose text is accepted only in part
+        constraint.advance(0).unwrap();
+        assert!(constraint.clone().advance(6).is_err());
+        assert!(constraint.advance(8).is_err());
+
+        // No token of the vocabulary starts the only allowed value
+        let matcher = JsonMatcher::new(&serde_json::json!({"const": "b"})).unwrap();
+        let constraint = constrained(Matcher::Json(matcher));
+        let logits = Tensor::zeros(8, candle::DType::F32, &Device::Cpu).unwrap();
+        assert!(constraint.mask(&logits).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+/*
+Character-level matcher for regular expressions and fixed lists of choices.
+
+The pattern is compiled into an anchored dense DFA over UTF-8 bytes, so the matcher state is a
+single DFA state and characters are checked by following their bytes. The whole output must
+match the pattern: generation can stop only where a match ends at the end of the text. A list
+of choices is compiled as the alternation of the escaped strings.
+
+The DFA is built with every match reported (rather than leftmost-first), so an alternative
+that is a prefix of another (`a|ab`) does not cut the longer one off. Since a DFA only notices
+a dead end once it reads a byte no match can follow, the states from which a match at the end
+of the text is still reachable are computed up front, and a character is accepted only if it
+leads to one of them. Patterns whose DFA would exceed `MAX_DFA_BYTES` are rejected.
+*/
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// Memory allowed for the DFA of one pattern, and for building it
+const MAX_DFA_BYTES: usize = 10 * 1024 * 1024;
+
+#[derive(Debug)]
+struct CompiledPattern {
+    dfa: dense::DFA<Vec<u32>>,
+    // States from which some continuation matches at the end of the text
+    live: HashSet<StateID>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegexMatcher {
+    pattern: Arc<CompiledPattern>,
+    state: StateID,
+}
+
+impl RegexMatcher {
+    // Matcher of text matching `pattern` as a whole
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .match_kind(MatchKind::All)
+                    .start_kind(StartKind::Anchored)
+                    .dfa_size_limit(Some(MAX_DFA_BYTES))
+                    .determinize_size_limit(Some(MAX_DFA_BYTES)),
+            )
+            .build(pattern)
+            .map_err(|e| format!("invalid pattern: {}", error_chain(&e)))?;
+        let state = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|e| format!("invalid pattern: {}", e))?;
+        let live = live_states(&dfa, state);
+        if !live.contains(&state) {
+            return Err("pattern does not match any text".into());
+        }
+        Ok(Self {
+            pattern: Arc::new(CompiledPattern { dfa, live }),
+            state,
+        })
+    }
+
+    // Matcher of text equal to one of `choices`
+    pub fn choice(choices: &[String]) -> Result<Self, String> {
+        if choices.is_empty() {
+            return Err("choices must not be empty".into());
+        }
+        let alternatives: Vec<String> = choices
+            .iter()
+            .map(|choice| regex_syntax::escape(choice))
+            .collect();
+        Self::new(&format!("(?:{})", alternatives.join("|")))
+    }
+
+    pub fn advance(&mut self, c: char) -> bool {
+        let pattern = &*self.pattern;
+        let mut state = self.state;
+        for &byte in c.encode_utf8(&mut [0; 4]).as_bytes() {
+            state = pattern.dfa.next_state(state, byte);
+            if !pattern.live.contains(&state) {
+                return false;
+            }
+        }
+        self.state = state;
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        is_accepting(&self.pattern.dfa, self.state)
+    }
+}
+
+// Whether the text read so far matches; matches are reported one transition late, so this is
+// decided by the end-of-input transition
+fn is_accepting(dfa: &dense::DFA<Vec<u32>>, state: StateID) -> bool {
+    dfa.is_match_state(dfa.next_eoi_state(state))
+}
+
+// States reachable from `start` that can still reach an accepting state
+fn live_states(dfa: &dense::DFA<Vec<u32>>, start: StateID) -> HashSet<StateID> {
+    // Reachable states and, for each, the states with a transition into it
+    let mut predecessors: HashMap<StateID, Vec<StateID>> = HashMap::from([(start, Vec::new())]);
+    let mut pending = vec![start];
+    while let Some(state) = pending.pop() {
+        for byte in 0..=u8::MAX {
+            let next = dfa.next_state(state, byte);
+            if dfa.is_dead_state(next) || dfa.is_quit_state(next) {
+                continue;
+            }
+            let incoming = predecessors.entry(next).or_insert_with(|| {
+                pending.push(next);
+                Vec::new()
+            });
+            if incoming.last() != Some(&state) {
+                incoming.push(state);
+            }
+        }
+    }
+
+    let mut live = HashSet::new();
+    let mut pending: Vec<StateID> = predecessors
+        .keys()
+        .copied()
+        .filter(|&state| is_accepting(dfa, state))
+        .collect();
+    while let Some(state) = pending.pop() {
+        if live.insert(state) {
+            pending.extend(&predecessors[&state]);
+        }
+    }
+    live
+}
+
+// An error with its causes, which the DFA builder keeps out of its own message
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(matcher: &RegexMatcher, text: &str) -> bool {
+        let mut matcher = matcher.clone();
+        text.chars().all(|c| matcher.advance(c)) && matcher.is_complete()
+    }
+
+    // The characters of `candidates` that may follow `prefix`, which `matcher` must accept
+    fn next(matcher: &RegexMatcher, prefix: &str, candidates: &str) -> String {
+        let mut matcher = matcher.clone();
+        assert!(prefix.chars().all(|c| matcher.advance(c)), "{}", prefix);
+        candidates
+            .chars()
+            .filter(|&c| matcher.clone().advance(c))
+            .collect()
+    }
+
+    fn choice(choices: &[&str]) -> RegexMatcher {
+        let choices: Vec<String> = choices.iter().map(|choice| choice.to_string()).collect();
+        RegexMatcher::choice(&choices).unwrap()
+    }
+
+    #[test]
+    fn alternatives_sharing_a_prefix_are_both_matched() {
+        let matcher = RegexMatcher::new("a|ab").unwrap();
+        assert!(accepts(&matcher, "a"));
+        assert!(accepts(&matcher, "ab"));
+        assert!(!accepts(&matcher, "abb") && !accepts(&matcher, "") && !accepts(&matcher, "b"));
+        // "a" may stop or go on with "b", and nothing follows "ab"
+        assert_eq!(next(&matcher, "a", "abc"), "b");
+        assert_eq!(next(&matcher, "ab", "abc"), "");
+    }
+
+    #[test]
+    fn matches_the_whole_text_only() {
+        let matcher = RegexMatcher::new(r"\d{3}-[A-Z]+").unwrap();
+        assert!(accepts(&matcher, "123-AB"));
+        assert!(!accepts(&matcher, "123-"));
+        assert!(!accepts(&matcher, "x123-AB"));
+        assert!(!accepts(&matcher, "123-ABc"));
+        assert_eq!(next(&matcher, "12", "0a-"), "0");
+        assert_eq!(next(&matcher, "123", "0A-"), "-");
+
+        // Characters are checked by all their bytes
+        let matcher = RegexMatcher::new("(é|ü)+").unwrap();
+        assert!(accepts(&matcher, "éüé"));
+        assert!(!accepts(&matcher, "e"));
+        assert_eq!(next(&matcher, "é", "éeüu"), "éü");
+    }
+
+    #[test]
+    fn rejects_a_character_after_which_nothing_can_match() {
+        // The DFA does not die at "x", but only "y" can follow it and "y" cannot follow the end
+        let matcher = RegexMatcher::new("a(x$y)?").unwrap();
+        assert!(accepts(&matcher, "a"));
+        assert_eq!(next(&matcher, "a", "xy"), "");
+        let matcher = RegexMatcher::new("a(x$y|z)").unwrap();
+        assert_eq!(next(&matcher, "a", "xyz"), "z");
+    }
+
+    #[test]
+    fn choices_that_are_prefixes_of_each_other_are_all_allowed() {
+        let matcher = choice(&["yes", "yes please", "no", "n/a (.*)"]);
+        for text in ["yes", "yes please", "no", "n/a (.*)"] {
+            assert!(accepts(&matcher, text), "{}", text);
+        }
+        for text in ["ye", "yes p", "yes please!", "n/a (x)", "No"] {
+            assert!(!accepts(&matcher, text), "{}", text);
+        }
+        assert_eq!(next(&matcher, "", "ynN"), "yn");
+        assert_eq!(next(&matcher, "yes", " s!"), " ");
+        assert_eq!(next(&matcher, "n", "o/x"), "o/");
+        // Regex metacharacters in choices are literal
+        assert_eq!(next(&matcher, "n/a (", ".x"), ".");
+    }
+
+    #[test]
+    fn rejects_invalid_and_unmatchable_patterns() {
+        assert!(RegexMatcher::new("(a").is_err());
+        assert!(RegexMatcher::new("[^\\s\\S]").is_err());
+        assert!(RegexMatcher::choice(&[]).is_err());
+        // A DFA too large to build
+        assert!(RegexMatcher::new(r"\w{200}[a-z]{50}x").is_err());
+    }
+}
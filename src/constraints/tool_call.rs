@@ -0,0 +1,266 @@
+/*
+Character-level matcher for tool calls of the form
+
+    <tool_call>{"name": "get_weather", "arguments": {...}}</tool_call>
+
+The call header is matched against the names of the offered tools, and the arguments against
+the parameter schema of the tool being called. When the call is optional, text that does not
+start with `TOOL_CALL_START` is accepted as a plain reply; once the marker is complete the
+rest of the call must be valid.
+*/
+use super::json_schema::JsonMatcher;
+use std::sync::Arc;
+
+pub const TOOL_CALL_START: &str = "<tool_call>";
+pub const TOOL_CALL_END: &str = "</tool_call>";
+// Whitespace allowed before a required call
+const MAX_LEADING_WHITESPACE: usize = 4;
+
+// Text of a call up to its arguments
+pub fn tool_call_header(name: &str) -> String {
+    format!(
+        "{}{{\"name\": {}, \"arguments\": ",
+        TOOL_CALL_START,
+        serde_json::Value::from(name)
+    )
+}
+
+// Text of a call after its arguments
+fn tool_call_footer() -> String {
+    format!("}}{}", TOOL_CALL_END)
+}
+
+#[derive(Debug)]
+struct ToolSignature {
+    header: Vec<char>,
+    arguments: JsonMatcher,
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    // Leading whitespace and the part of a call header matched so far
+    Header { whitespace: usize, matched: String },
+    // A plain reply instead of a call
+    Text,
+    Arguments { matcher: JsonMatcher },
+    Footer { matched: usize },
+    Done,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolCallMatcher {
+    tools: Arc<Vec<ToolSignature>>,
+    footer: Arc<Vec<char>>,
+    // Whether a plain reply is accepted instead of a call
+    optional: bool,
+    state: State,
+}
+
+impl ToolCallMatcher {
+    // Matcher of a call to one of `tools`, given as (name, parameter schema) pairs; tools
+    // without a schema take any JSON object
+    pub fn new(
+        tools: &[(&str, Option<&serde_json::Value>)],
+        optional: bool,
+    ) -> Result<Self, String> {
+        if tools.is_empty() {
+            return Err("no tools to call".into());
+        }
+        let tools = tools
+            .iter()
+            .map(|&(name, parameters)| {
+                let arguments = match parameters {
+                    Some(schema) => JsonMatcher::new(schema)
+                        .map_err(|e| format!("invalid parameters of tool {:?}: {}", name, e))?,
+                    None => JsonMatcher::any_object(),
+                };
+                Ok(ToolSignature {
+                    header: tool_call_header(name).chars().collect(),
+                    arguments,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self {
+            tools: Arc::new(tools),
+            footer: Arc::new(tool_call_footer().chars().collect()),
+            optional,
+            state: State::Header {
+                whitespace: 0,
+                matched: String::new(),
+            },
+        })
+    }
+
+    pub fn advance(&mut self, c: char) -> bool {
+        match &mut self.state {
+            State::Header {
+                whitespace,
+                matched,
+            } => {
+                if matched.is_empty() && c.is_whitespace() {
+                    *whitespace += 1;
+                    return self.optional || *whitespace <= MAX_LEADING_WHITESPACE;
+                }
+                let position = matched.chars().count();
+                let candidates: Vec<&ToolSignature> = self
+                    .tools
+                    .iter()
+                    .filter(|tool| tool.header.get(position) == Some(&c))
+                    .filter(|tool| {
+                        tool.header
+                            .iter()
+                            .zip(matched.chars())
+                            .all(|(a, b)| *a == b)
+                    })
+                    .collect();
+                match candidates.as_slice() {
+                    [] if self.optional && position < TOOL_CALL_START.len() => {
+                        self.state = State::Text;
+                        true
+                    }
+                    [] => false,
+                    [tool] if tool.header.len() == position + 1 => {
+                        self.state = State::Arguments {
+                            matcher: tool.arguments.clone(),
+                        };
+                        true
+                    }
+                    _ => {
+                        matched.push(c);
+                        true
+                    }
+                }
+            }
+            State::Text => true,
+            State::Arguments { matcher } => {
+                if matcher.advance(c) {
+                    return true;
+                }
+                if !matcher.is_complete() {
+                    return false;
+                }
+                self.state = State::Footer { matched: 0 };
+                self.advance(c)
+            }
+            State::Footer { matched } => {
+                if self.footer.get(*matched) != Some(&c) {
+                    return false;
+                }
+                *matched += 1;
+                if *matched == self.footer.len() {
+                    self.state = State::Done;
+                }
+                true
+            }
+            State::Done => false,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match &self.state {
+            // Before the marker is complete an optional call is still a plain reply
+            State::Header { matched, .. } => self.optional && matched.len() < TOOL_CALL_START.len(),
+            State::Text | State::Done => true,
+            State::Arguments { .. } | State::Footer { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matcher(optional: bool) -> ToolCallMatcher {
+        let weather = json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}, "days": {"type": "integer"}},
+            "required": ["city"],
+        });
+        ToolCallMatcher::new(&[("get_weather", Some(&weather)), ("get", None)], optional).unwrap()
+    }
+
+    fn accepts(matcher: &ToolCallMatcher, text: &str) -> bool {
+        let mut matcher = matcher.clone();
+        text.chars().all(|c| matcher.advance(c)) && matcher.is_complete()
+    }
+
+    // The characters of `candidates` that may follow `prefix`, which `matcher` must accept
+    fn next(matcher: &ToolCallMatcher, prefix: &str, candidates: &str) -> String {
+        let mut matcher = matcher.clone();
+        assert!(prefix.chars().all(|c| matcher.advance(c)), "{}", prefix);
+        candidates
+            .chars()
+            .filter(|&c| matcher.clone().advance(c))
+            .collect()
+    }
+
+    fn call(name: &str, arguments: &str) -> String {
+        format!(
+            "{}{}{}",
+            tool_call_header(name),
+            arguments,
+            tool_call_footer()
+        )
+    }
+
+    #[test]
+    fn required_calls_match_a_tool_and_its_parameters() {
+        let required = matcher(false);
+        assert!(accepts(
+            &required,
+            &call("get_weather", r#"{"city": "Paris"}"#)
+        ));
+        assert!(accepts(
+            &required,
+            &call("get_weather", r#"{"city": "Oslo", "days": 3}"#)
+        ));
+        assert!(accepts(
+            &required,
+            &format!("\n {}", call("get", r#"{"any": [1]}"#))
+        ));
+        assert!(!accepts(&required, &call("get_weather", r#"{"days": 3}"#)));
+        assert!(!accepts(&required, &call("get_weather", r#"{"city": 3}"#)));
+        assert!(!accepts(&required, &call("set", "{}")));
+        assert!(!accepts(&required, "It is sunny."));
+        assert!(!accepts(&required, ""));
+        assert!(!accepts(
+            &required,
+            &format!("{}{}", call("get", "{}"), " ")
+        ));
+
+        assert_eq!(next(&required, "", "<I "), "< ");
+        let spaces = " ".repeat(MAX_LEADING_WHITESPACE);
+        assert_eq!(next(&required, &spaces, "< "), "<");
+        // "get" and "get_weather" share a prefix until the name is closed
+        let name = format!("{}{{\"name\": \"get", TOOL_CALL_START);
+        assert_eq!(next(&required, &name, "\"_x"), "\"_");
+        // The arguments end with their object, then only the footer follows
+        let arguments = format!("{}{{\"city\": \"Rome\"", tool_call_header("get_weather"));
+        assert_eq!(next(&required, &arguments, ",}\""), ",}");
+        assert_eq!(next(&required, &format!("{}}}", arguments), "}<,"), "}");
+    }
+
+    #[test]
+    fn optional_calls_accept_a_plain_reply_until_the_marker_is_complete() {
+        let auto = matcher(true);
+        assert!(accepts(&auto, "It is sunny."));
+        assert!(accepts(&auto, ""));
+        assert!(accepts(&auto, "  <b>bold</b>"));
+        assert!(accepts(&auto, "<tool"));
+        assert!(accepts(&auto, &call("get_weather", r#"{"city": "Paris"}"#)));
+        // Once the marker is complete the reply must be a valid call
+        assert!(!accepts(&auto, TOOL_CALL_START));
+        assert!(!accepts(&auto, &format!("{}hello", TOOL_CALL_START)));
+        assert!(!accepts(&auto, &call("get_weather", "{}")));
+        assert_eq!(next(&auto, "<tool_call", ">x"), ">x");
+        assert_eq!(next(&auto, TOOL_CALL_START, "{x"), "{");
+    }
+
+    #[test]
+    fn rejects_tools_it_cannot_constrain() {
+        assert!(ToolCallMatcher::new(&[], true).is_err());
+        let invalid = json!({"type": "string", "pattern": "a"});
+        assert!(ToolCallMatcher::new(&[("f", Some(&invalid))], false).is_err());
+    }
+}
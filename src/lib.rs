@@ -1 +1,7 @@
+pub mod chat;
+pub mod chunking;
+pub mod constraints;
 pub mod models;
+pub mod rag;
+pub mod search;
+pub mod sessions;
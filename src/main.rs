@@ -1,42 +1,114 @@
 // Web server entry point
-use crate::api::{find_similar, generate_text, generate_text_stream};
+use crate::api::documents::{delete_document, get_document, upsert_document};
+use crate::api::embeddings::create_embeddings;
+use crate::api::rag::rag;
+use crate::api::sessions::{
+    append_message, create_session, delete_session, generate_reply, get_session, restore_session,
+    snapshot_session,
+};
+use crate::api::tokenize::{detokenize, tokenize};
+use crate::api::{find_similar, generate_text, generate_text_stream, speculative_metrics};
 use crate::state::AppState;
 use actix_files as fs;
 use actix_web::{web, App, HttpServer};
 use candle::Device;
-use env_logger;
 use inference_server::models::bert::BertInferenceModel;
 use inference_server::models::llama::LlamaInferenceModel;
-use std::fs::File;
-use std::sync::Arc;
+use inference_server::search::corpus::Corpus;
+use inference_server::search::store::{EmbeddingModel, IndexDir};
+use inference_server::search::{IndexKind, SearchParams};
+use inference_server::sessions::SessionStore;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
 
 mod api;
-mod models;
 mod state;
 
+// Index directory written by the embedding generator: documents, chunks and their embeddings
+const INDEX_DIR: &str = "index";
+// Environment variable naming the index searched by /find_similar and /rag ("exact", "hnsw" or
+// "ivf_pq"); falls back to exact search over the embeddings when the embedding generator did not
+// build it (with --hnsw or --ivfpq)
+const VECTOR_INDEX_VAR: &str = "VECTOR_INDEX";
+const DEFAULT_VECTOR_INDEX: IndexKind = IndexKind::Hnsw;
+// Environment variables setting the ef_search (HNSW) and nprobe (IVF-PQ) of queries that do not
+// set their own
+const EF_SEARCH_VAR: &str = "VECTOR_INDEX_EF_SEARCH";
+const NPROBE_VAR: &str = "VECTOR_INDEX_NPROBE";
+// Environment variable overriding the pooling of the BERT model repo ("mean", "max" or "cls");
+// it must match the pooling the index was built with (embedding_generator --pooling)
+const POOLING_VAR: &str = "EMBEDDING_POOLING";
+// How often documents changed through the API are compacted into a new index
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Memory budget for prompt KV states shared across requests
+const PREFIX_CACHE_BYTES: usize = 512 * 1024 * 1024;
+// Chat sessions idle for longer than this are dropped together with their KV cache
+const SESSION_TTL: Duration = Duration::from_secs(30 * 60);
+// Each session can hold a full context window of KV states, so their number is capped
+const MAX_SESSIONS: usize = 32;
+// Where session snapshots (history plus KV cache) are written and restored from
+const SESSION_SNAPSHOT_DIR: &str = "sessions";
+// Speculative decoding pairs: (target model, draft model, tokens proposed per step). The draft
+// must share the target's tokenizer vocabulary.
+const SPECULATIVE_PAIRS: &[(&str, &str, usize)] = &[(
+    "TinyLlama/TinyLlama-1.1B-Chat-v1.0",
+    "Felladrin/Llama-68M-Chat-v1",
+    4,
+)];
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    // Load the BERT model
-    let bert_model = BertInferenceModel::load(
+    // Open the index, checking its files against the manifest
+    let index_dir = IndexDir::open(INDEX_DIR).unwrap_or_else(|e| {
+        panic!(
+            "Failed to open the index {}: {:?}\nBuild it first with \
+             `cargo run --bin embedding_generator assets/news.csv --hnsw`",
+            INDEX_DIR, e
+        )
+    });
+    println!(
+        "Opened index {} ({} documents, {} chunks, built with {})",
+        INDEX_DIR,
+        index_dir.manifest.documents,
+        index_dir.manifest.count,
+        index_dir.manifest.model.model_id
+    );
+
+    // Load the BERT model; the corpus searches the embeddings of the index
+    let mut bert_model = BertInferenceModel::load(
         "sentence-transformers/all-MiniLM-L6-v2",
         // "main",
         "refs/pr/21",
-        "embeddings.bin",
-        "my_embedding",
         Device::Cpu,
     )
     .expect("Failed to load BertInferenceModel");
+    if let Some(pooling) = env_setting(POOLING_VAR) {
+        bert_model.set_pooling(pooling);
+    }
+    // Query embeddings are only comparable with the index built by the same model
+    index_dir
+        .check_model(&EmbeddingModel::of(&bert_model))
+        .expect("The index was built with a different embedding model");
     println!("Loaded BERT model");
 
-    // Load text map from binary file
-    let mut text_map_file = File::open("text_map.bin").expect("Failed to open text_map.bin");
-    let text_map: Vec<String> =
-        bincode::decode_from_std_read(&mut text_map_file, bincode::config::standard())
-            .expect("Failed to decode text_map.bin");
+    // Load the documents, the chunk each embedding was computed from and the vector index, with
+    // the changes logged since the index was written
+    let vector_index = env_setting(VECTOR_INDEX_VAR).unwrap_or(DEFAULT_VECTOR_INDEX);
+    let corpus =
+        Corpus::open(&index_dir, vector_index).expect("Failed to load the index documents");
+    let search_params = SearchParams {
+        ef_search: env_setting(EF_SEARCH_VAR),
+        nprobe: env_setting(NPROBE_VAR),
+        rerank: None,
+    };
+    println!("Loaded {} documents", corpus.read().len());
 
-    let llama_model = LlamaInferenceModel::load_from_hub(
+    let mut llama_model = LlamaInferenceModel::load_from_hub(
         // "meta-llama/Llama-2-7b-chat-hf",
         "TinyLlama/TinyLlama-1.1B-Chat-v1.0",
         Device::Cpu,
@@ -44,29 +116,138 @@ async fn main() -> std::io::Result<()> {
         None,
     )
     .expect("Failed to load LLAMA model");
+    llama_model.enable_prefix_cache(PREFIX_CACHE_BYTES);
     println!("Loaded LLAMA model");
 
+    // Load the draft model configured for this target, if any; serve without it on failure
+    let speculative_pair = SPECULATIVE_PAIRS
+        .iter()
+        .find(|(target, _, _)| *target == llama_model.model_id);
+    if let Some(&(_, draft_model_id, num_speculative_tokens)) = speculative_pair {
+        let enabled = LlamaInferenceModel::load_from_hub(draft_model_id, Device::Cpu, None, None)
+            .and_then(|draft| {
+                llama_model.enable_speculative_decoding(Arc::new(draft), num_speculative_tokens)
+            });
+        match enabled {
+            Ok(()) => println!("Enabled speculative decoding with {}", draft_model_id),
+            Err(e) => eprintln!("Speculative decoding disabled: {:?}", e),
+        }
+    }
+
     // Set up shared application state
     let shared_state = AppState {
         bert_model: Arc::new(bert_model),
-        text_map,
+        corpus: Arc::new(corpus),
+        search_params,
         llama_model: Arc::new(llama_model),
+        sessions: Arc::new(SessionStore::new(
+            SESSION_TTL,
+            MAX_SESSIONS,
+            PathBuf::from(SESSION_SNAPSHOT_DIR),
+        )),
     };
 
+    // Periodically release expired sessions, even when no requests touch them
+    let sessions = Arc::clone(&shared_state.sessions);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let evicted = sessions.evict_expired();
+            if evicted > 0 {
+                println!("Evicted {} expired sessions", evicted);
+            }
+        }
+    });
+
+    // Periodically write logged document changes into a new index, so that restarts do not
+    // replay them
+    let corpus = Arc::clone(&shared_state.corpus);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(COMPACTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let corpus = Arc::clone(&corpus);
+            match web::block(move || corpus.compact()).await {
+                Ok(Ok(Some(manifest))) => println!(
+                    "Compacted index {} ({} documents, {} chunks)",
+                    INDEX_DIR, manifest.documents, manifest.count
+                ),
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => eprintln!("Failed to compact index {}: {:?}", INDEX_DIR, e),
+                Err(e) => eprintln!("Failed to compact index {}: {:?}", INDEX_DIR, e),
+            }
+        }
+    });
+
     // Start the HTTP server
     println!("Starting HTTP server on 0.0.0.0:8080...");
+    let server_state = shared_state.clone();
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(shared_state.clone()))
+            .app_data(web::Data::new(server_state.clone()))
             .route("/find_similar", web::post().to(find_similar)) // API endpoint for finding similar topics
             .route("/generate_text", web::post().to(generate_text)) // API endpoint for generating text (non-streaming)
             .route(
                 "/generate_text_stream",
                 web::post().to(generate_text_stream),
             )
+            .route("/v1/embeddings", web::post().to(create_embeddings)) // OpenAI-compatible sentence embeddings
+            .route("/tokenize", web::post().to(tokenize)) // API endpoints for counting and inspecting tokens
+            .route("/detokenize", web::post().to(detokenize))
+            .route("/rag", web::post().to(rag)) // API endpoint for answering questions from retrieved passages
+            .route("/documents/{id}", web::get().to(get_document)) // API endpoints for changing the indexed documents
+            .route("/documents/{id}", web::put().to(upsert_document))
+            .route("/documents/{id}", web::delete().to(delete_document))
+            .route("/metrics/speculative", web::get().to(speculative_metrics))
+            .route("/sessions", web::post().to(create_session)) // API endpoints for stateful chat sessions
+            .route("/sessions/{session_id}", web::get().to(get_session))
+            .route("/sessions/{session_id}", web::delete().to(delete_session))
+            .route(
+                "/sessions/{session_id}/messages",
+                web::post().to(append_message),
+            )
+            .route(
+                "/sessions/{session_id}/generate",
+                web::post().to(generate_reply),
+            )
+            .route(
+                "/sessions/{session_id}/snapshot",
+                web::post().to(snapshot_session),
+            )
+            .route(
+                "/sessions/{session_id}/restore",
+                web::post().to(restore_session),
+            )
             .service(fs::Files::new("/", "./frontend/build").index_file("index.html"))
     })
     .bind(("0.0.0.0", 8080))?
     .run()
-    .await
+    .await?;
+
+    // Snapshot live sessions so they can be restored after a restart
+    for (session_id, session) in shared_state.sessions.entries() {
+        let session = session.lock().unwrap_or_else(PoisonError::into_inner);
+        let saved = shared_state
+            .sessions
+            .snapshot_path(&session_id)
+            .and_then(|path| session.save(&shared_state.llama_model, &path));
+        match saved {
+            Ok(()) => println!("Saved session {}", session_id),
+            Err(e) => eprintln!("Failed to save session {}: {:?}", session_id, e),
+        }
+    }
+    Ok(())
+}
+
+// The setting in the environment variable `name`, or None when it is unset
+fn env_setting<T: DeserializeOwned>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    // Names of enum variants parse as JSON strings, numbers as themselves
+    let setting = serde_json::from_value(Value::String(value.clone()))
+        .or_else(|_| serde_json::from_str(&value));
+    match setting {
+        Ok(setting) => Some(setting),
+        Err(e) => panic!("Invalid {}={:?}: {}", name, value, e),
+    }
 }
@@ -1,41 +1,104 @@
 // BERT model
-use candle::{safetensors, Device, Tensor};
+use candle::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config, DTYPE};
 use hf_hub::api::sync::Api;
 use hf_hub::{Repo, RepoType};
-use tokenizers::Tokenizer;
+use serde::{Deserialize, Serialize};
+use tokenizers::{
+    Encoding, PaddingParams, PaddingStrategy, Tokenizer, TruncationParams, TruncationStrategy,
+};
+
+// The parts of the model config that candle's `Config` keeps private
+#[derive(Deserialize)]
+struct InputConfig {
+    max_position_embeddings: usize,
+    hidden_size: usize,
+    #[serde(default)]
+    pad_token_id: u32,
+}
+
+// Pooling modes of a sentence-transformers `1_Pooling/config.json`
+#[derive(Deserialize)]
+struct PoolingConfig {
+    #[serde(default)]
+    pooling_mode_cls_token: bool,
+    #[serde(default)]
+    pooling_mode_mean_tokens: bool,
+    #[serde(default)]
+    pooling_mode_max_tokens: bool,
+}
+
+/// How token embeddings are combined into a sentence embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    /// Average of the token embeddings.
+    Mean,
+    /// Element-wise maximum of the token embeddings.
+    Max,
+    /// Embedding of the leading [CLS] token.
+    Cls,
+}
+
+impl Pooling {
+    // The mode a pooling config enables; mean pooling when it enables several or none
+    fn from_config(config: &PoolingConfig) -> Self {
+        match (
+            config.pooling_mode_mean_tokens,
+            config.pooling_mode_cls_token,
+            config.pooling_mode_max_tokens,
+        ) {
+            (false, true, _) => Pooling::Cls,
+            (false, false, true) => Pooling::Max,
+            _ => Pooling::Mean,
+        }
+    }
+
+    /*
+    Pools `embeddings` ([batch, tokens, hidden]) into [batch, hidden].
+
+    `attention_mask` ([batch, tokens], 1 for real tokens and 0 for padding) keeps padding out
+    of the mean and the maximum.
+    */
+    pub fn apply(self, embeddings: &Tensor, attention_mask: &Tensor) -> anyhow::Result<Tensor> {
+        let mask = attention_mask.to_dtype(embeddings.dtype())?.unsqueeze(2)?;
+        let pooled = match self {
+            Pooling::Mean => {
+                let sum = embeddings.broadcast_mul(&mask)?.sum(1)?;
+                let count = mask.sum(1)?.clamp(1e-9, f64::MAX)?;
+                sum.broadcast_div(&count)?
+            }
+            Pooling::Max => {
+                // Push padding far below any real activation
+                let padding = ((mask - 1.0)? * 1e9)?;
+                embeddings.broadcast_add(&padding)?.max(1)?
+            }
+            Pooling::Cls => embeddings.narrow(1, 0, 1)?.squeeze(1)?,
+        };
+        Ok(pooled)
+    }
+}
 
 pub struct BertInferenceModel {
     model: BertModel,
     tokenizer: Tokenizer,
+    // `tokenizer` set up to truncate inputs to `max_input_tokens` and pad batches to their
+    // longest input
+    embedding_tokenizer: Tokenizer,
     device: Device,
-    embedding_tensor: Tensor,
+    pub model_id: String,
+    pub model_revision: String,
+    pooling: Pooling,
+    // Longest input (in tokens, including special tokens) the position embeddings cover
+    max_input_tokens: usize,
+    // Special tokens added around every input, e.g. [CLS] and [SEP]
+    special_tokens: usize,
+    hidden_size: usize,
 }
 
 impl BertInferenceModel {
-    pub fn load(
-        model_name: &str,
-        model_revision: &str,
-        embeddings_file: &str,
-        embeddings_key: &str,
-        device: Device,
-    ) -> anyhow::Result<Self> {
-        let embedding_tensor = match embeddings_file.is_empty() {
-            true => {
-                println!("no file name provided, embaddings return an empty tensor");
-                Tensor::new(&[0.0], &device)?
-            }
-            false => {
-                let tensor_file = safetensors::load(embeddings_file, &device)?;
-                tensor_file
-                    .get(embeddings_key)
-                    .expect("Failed to retrieve embedding key")
-                    .clone()
-            }
-        };
-        println!("loaded embedding shape: {:?}", embedding_tensor.shape());
-
+    pub fn load(model_name: &str, model_revision: &str, device: Device) -> anyhow::Result<Self> {
         // start loading the model from the repo
         let repo = Repo::with_revision(
             model_name.parse()?,
@@ -47,11 +110,36 @@ impl BertInferenceModel {
         let tokenizer_path = api.get("tokenizer.json")?;
         let weights_path = api.get("model.safetensors")?;
 
+        // sentence-transformers models name their pooling; others default to mean pooling
+        let pooling = match api.get("1_Pooling/config.json") {
+            Ok(path) => {
+                let config: PoolingConfig = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+                Pooling::from_config(&config)
+            }
+            Err(e) => {
+                println!("no pooling config ({}), using mean pooling", e);
+                Pooling::Mean
+            }
+        };
+        println!("using {:?} pooling", pooling);
+
         // load the model config
-        let config: Config = serde_json::from_str(&std::fs::read_to_string(config_path)?)?;
+        let config_json = std::fs::read_to_string(config_path)?;
+        let config: Config = serde_json::from_str(&config_json)?;
+        let input_config: InputConfig = serde_json::from_str(&config_json)?;
 
         //load the tokenizer
-        let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)?;
+        let mut tokenizer = Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)?;
+        let embedding_tokenizer = Self::embedding_tokenizer(&tokenizer, &input_config)?;
+        // Token counts and chunking need whole texts, whatever the tokenizer file configures
+        tokenizer
+            .with_padding(None)
+            .with_truncation(None)
+            .map_err(anyhow::Error::msg)?;
+        let special_tokens = tokenizer
+            .encode("", true)
+            .map_err(anyhow::Error::msg)?
+            .len();
 
         // load the model
         let variable_builder =
@@ -60,72 +148,306 @@ impl BertInferenceModel {
         Ok(Self {
             model,
             tokenizer,
+            embedding_tokenizer,
             device,
-            embedding_tensor,
+            model_id: model_name.to_string(),
+            model_revision: model_revision.to_string(),
+            pooling,
+            max_input_tokens: input_config.max_position_embeddings,
+            special_tokens,
+            hidden_size: input_config.hidden_size,
         })
     }
 
-    pub fn infer_sentence_embedding(&self, sentence: &str) -> anyhow::Result<Tensor> {
+    /*
+    Copy of `tokenizer` for batch embedding: inputs longer than the position embeddings are cut
+    (keeping the special tokens) and every batch is padded to its longest input. Keeps padding
+    the tokenizer file already configures, such as its pad token.
+    */
+    fn embedding_tokenizer(
+        tokenizer: &Tokenizer,
+        config: &InputConfig,
+    ) -> anyhow::Result<Tokenizer> {
+        let mut embedding_tokenizer = tokenizer.clone();
+        let padding = match tokenizer.get_padding() {
+            Some(padding) => PaddingParams {
+                strategy: PaddingStrategy::BatchLongest,
+                ..padding.clone()
+            },
+            None => PaddingParams {
+                strategy: PaddingStrategy::BatchLongest,
+                pad_id: config.pad_token_id,
+                pad_token: tokenizer
+                    .id_to_token(config.pad_token_id)
+                    .unwrap_or_else(|| "[PAD]".to_string()),
+                ..Default::default()
+            },
+        };
+        embedding_tokenizer
+            .with_padding(Some(padding))
+            .with_truncation(Some(TruncationParams {
+                max_length: config.max_position_embeddings,
+                strategy: TruncationStrategy::LongestFirst,
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+        Ok(embedding_tokenizer)
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    pub fn pooling(&self) -> Pooling {
+        self.pooling
+    }
+
+    // Override the pooling read from the model repo; embeddings are only comparable when
+    // computed with the same pooling
+    pub fn set_pooling(&mut self, pooling: Pooling) {
+        self.pooling = pooling;
+    }
+
+    // Dimension of the embeddings
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    pub fn max_input_tokens(&self) -> usize {
+        self.max_input_tokens
+    }
+
+    // Longest text (in tokens, without special tokens) that embeds without truncation
+    pub fn max_chunk_tokens(&self) -> usize {
+        self.max_input_tokens.saturating_sub(self.special_tokens)
+    }
+
+    // Number of tokens in `sentence`, including special tokens, before any truncation
+    pub fn count_tokens(&self, sentence: &str) -> anyhow::Result<usize> {
         let tokens = self
             .tokenizer
             .encode(sentence, true)
             .map_err(anyhow::Error::msg)?;
-        let token_ids = Tensor::new(tokens.get_ids(), &self.device)?.unsqueeze(0)?;
-        let token_type_ids = token_ids.zeros_like()?;
+        Ok(tokens.get_ids().len())
+    }
 
-        let embeddings = self.model.forward(&token_ids, &token_type_ids, None)?;
-        let pooled_embedding = Self::apply_max_pooling(&embeddings)?;
-        Ok(Self::l2_normalize(&pooled_embedding)?)
+    // Inputs longer than `max_input_tokens` are truncated
+    pub fn infer_sentence_embedding(&self, sentence: &str) -> anyhow::Result<Tensor> {
+        let tokens = self
+            .embedding_tokenizer
+            .encode(sentence, true)
+            .map_err(anyhow::Error::msg)?;
+        self.embed_encodings(&[tokens])
     }
 
+    /*
+    Embeds `sentences` in one forward pass, one row per sentence.
+
+    Sentences are truncated to `max_input_tokens` and padded to the longest one; the attention
+    mask keeps padding out of both the attention and the pooling, so a sentence embeds the same
+    whatever batch it is in.
+    */
     pub fn create_embeddings(&self, sentences: Vec<String>) -> anyhow::Result<Tensor> {
         println!("Generating embeddings for {} sentences", sentences.len());
         let tokens = self
-            .tokenizer
+            .embedding_tokenizer
             .encode_batch(sentences, true)
             .map_err(anyhow::Error::msg)?;
-        let token_ids = tokens
+        self.embed_encodings(&tokens)
+    }
+
+    // Pooled, normalized embeddings of encodings of equal (padded) length
+    fn embed_encodings(&self, encodings: &[Encoding]) -> anyhow::Result<Tensor> {
+        let token_ids = encodings
             .iter()
-            .map(|tokens| {
-                let token_vec = tokens.get_ids().to_vec();
-                Ok(Tensor::new(token_vec.as_slice(), &self.device)?)
-            })
+            .map(|encoding| Ok(Tensor::new(encoding.get_ids(), &self.device)?))
             .collect::<anyhow::Result<Vec<_>>>()?;
         let token_ids = Tensor::stack(&token_ids, 0)?;
         let token_type_ids = token_ids.zeros_like()?;
-        println!("Stacking tokens completed");
-
-        let embeddings = self.model.forward(&token_ids, &token_type_ids, None)?;
-        let pooled_embeddings = Self::apply_max_pooling(&embeddings)?; // apply pooling (avg or max
-        Ok(Self::l2_normalize(&pooled_embeddings)?)
-    }
-
-    pub fn score_vector_similarity(
-        &self,
-        query_vector: Tensor,
-        top_k: usize,
-    ) -> anyhow::Result<Vec<(usize, f32)>> {
-        let vec_len = self.embedding_tensor.dim(0)?;
-        let mut scores = vec![(0, 0.0); vec_len];
-        for (embedding_index, score_tuple) in scores.iter_mut().enumerate() {
-            let current_embedding = self.embedding_tensor.get(embedding_index)?.unsqueeze(0)?;
-            // because its normalized we can use cosine similarity
-            let cosine_similarity = (&current_embedding * &query_vector)?
-                .sum_all()?
-                .to_scalar::<f32>()?;
-            *score_tuple = (embedding_index, cosine_similarity);
-        }
-        // sort scores by cosine_similarity
-        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-        scores.truncate(top_k);
-        Ok(scores)
+        let attention_mask = self.attention_mask(encodings)?;
+
+        let embeddings = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+        let pooled_embeddings = self.pooling.apply(&embeddings, &attention_mask)?;
+        Self::l2_normalize(&pooled_embeddings)
     }
 
-    pub fn apply_max_pooling(embeddings: &Tensor) -> anyhow::Result<Tensor> {
-        Ok(embeddings.max(1)?)
+    // [batch, tokens] mask of the real (non-padding) tokens of `encodings`
+    fn attention_mask(&self, encodings: &[Encoding]) -> anyhow::Result<Tensor> {
+        let masks = encodings
+            .iter()
+            .map(|encoding| Ok(Tensor::new(encoding.get_attention_mask(), &self.device)?))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Tensor::stack(&masks, 0)?.to_dtype(DType::F32)?)
     }
 
     pub fn l2_normalize(embeddings: &Tensor) -> anyhow::Result<Tensor> {
         Ok(embeddings.broadcast_div(&embeddings.sqr()?.sum_keepdim(1)?.sqrt()?)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two sentences of three positions, the second with its last position padded
+    fn padded_batch() -> (Tensor, Tensor) {
+        let embeddings = Tensor::new(
+            &[
+                [[1f32, -1.0], [3.0, 0.0], [5.0, 4.0]],
+                [[2.0, 2.0], [4.0, -2.0], [100.0, 100.0]],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let attention_mask =
+            Tensor::new(&[[1f32, 1.0, 1.0], [1.0, 1.0, 0.0]], &Device::Cpu).unwrap();
+        (embeddings, attention_mask)
+    }
+
+    fn pooled(pooling: Pooling) -> Vec<Vec<f32>> {
+        let (embeddings, attention_mask) = padded_batch();
+        pooling
+            .apply(&embeddings, &attention_mask)
+            .unwrap()
+            .to_vec2()
+            .unwrap()
+    }
+
+    // Word-level tokenizer wrapping inputs in [CLS] ... [SEP], as BERT tokenizers do
+    fn word_tokenizer() -> Tokenizer {
+        let json = r#"{
+            "version": "1.0",
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": {
+                "type": "TemplateProcessing",
+                "single": [
+                    {"SpecialToken": {"id": "[CLS]", "type_id": 0}},
+                    {"Sequence": {"id": "A", "type_id": 0}},
+                    {"SpecialToken": {"id": "[SEP]", "type_id": 0}}
+                ],
+                "pair": [
+                    {"Sequence": {"id": "A", "type_id": 0}},
+                    {"Sequence": {"id": "B", "type_id": 1}}
+                ],
+                "special_tokens": {
+                    "[CLS]": {"id": "[CLS]", "ids": [2], "tokens": ["[CLS]"]},
+                    "[SEP]": {"id": "[SEP]", "ids": [3], "tokens": ["[SEP]"]}
+                }
+            },
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[PAD]": 0, "[UNK]": 1, "[CLS]": 2, "[SEP]": 3, "a": 4, "b": 5, "c": 6},
+                "unk_token": "[UNK]"
+            }
+        }"#;
+        json.parse().unwrap()
+    }
+
+    fn input_config(max_position_embeddings: usize) -> InputConfig {
+        InputConfig {
+            max_position_embeddings,
+            hidden_size: 2,
+            pad_token_id: 0,
+        }
+    }
+
+    fn encode_batch(tokenizer: &Tokenizer, sentences: &[&str]) -> Vec<(Vec<u32>, Vec<u32>)> {
+        tokenizer
+            .encode_batch(sentences.to_vec(), true)
+            .unwrap()
+            .iter()
+            .map(|encoding| {
+                (
+                    encoding.get_ids().to_vec(),
+                    encoding.get_attention_mask().to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pooling_ignores_padded_positions() {
+        assert_eq!(pooled(Pooling::Mean), vec![vec![3.0, 1.0], vec![3.0, 0.0]]);
+        assert_eq!(pooled(Pooling::Max), vec![vec![5.0, 4.0], vec![4.0, 2.0]]);
+        assert_eq!(pooled(Pooling::Cls), vec![vec![1.0, -1.0], vec![2.0, 2.0]]);
+    }
+
+    #[test]
+    fn max_pooling_keeps_negative_activations() {
+        let embeddings = Tensor::new(&[[[-3f32], [-1.0], [0.0]]], &Device::Cpu).unwrap();
+        let attention_mask = Tensor::new(&[[1f32, 1.0, 0.0]], &Device::Cpu).unwrap();
+        let pooled = Pooling::Max.apply(&embeddings, &attention_mask).unwrap();
+        assert_eq!(pooled.to_vec2::<f32>().unwrap(), vec![vec![-1.0]]);
+    }
+
+    #[test]
+    fn embedding_batches_are_padded_to_their_longest_input() {
+        let tokenizer =
+            BertInferenceModel::embedding_tokenizer(&word_tokenizer(), &input_config(8)).unwrap();
+        assert_eq!(
+            encode_batch(&tokenizer, &["a b c", "b"]),
+            vec![
+                (vec![2, 4, 5, 6, 3], vec![1, 1, 1, 1, 1]),
+                (vec![2, 5, 3, 0, 0], vec![1, 1, 1, 0, 0]),
+            ]
+        );
+        // A single input is not padded
+        assert_eq!(
+            encode_batch(&tokenizer, &["b"]),
+            vec![(vec![2, 5, 3], vec![1, 1, 1])]
+        );
+    }
+
+    #[test]
+    fn embedding_inputs_are_truncated_keeping_the_special_tokens() {
+        let tokenizer =
+            BertInferenceModel::embedding_tokenizer(&word_tokenizer(), &input_config(4)).unwrap();
+        assert_eq!(
+            encode_batch(&tokenizer, &["a b c a b", "c"]),
+            vec![
+                (vec![2, 4, 5, 3], vec![1, 1, 1, 1]),
+                (vec![2, 6, 3, 0], vec![1, 1, 1, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn embedding_tokenizer_keeps_the_configured_pad_token() {
+        let mut tokenizer = word_tokenizer();
+        tokenizer.with_padding(Some(PaddingParams {
+            pad_id: 1,
+            pad_token: "[UNK]".to_string(),
+            ..Default::default()
+        }));
+        let tokenizer =
+            BertInferenceModel::embedding_tokenizer(&tokenizer, &input_config(8)).unwrap();
+        assert_eq!(
+            encode_batch(&tokenizer, &["a b", "c"]),
+            vec![
+                (vec![2, 4, 5, 3], vec![1, 1, 1, 1]),
+                (vec![2, 6, 3, 1], vec![1, 1, 1, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pooling_follows_the_sentence_transformers_config() {
+        let pooling = |cls, mean, max| {
+            Pooling::from_config(&PoolingConfig {
+                pooling_mode_cls_token: cls,
+                pooling_mode_mean_tokens: mean,
+                pooling_mode_max_tokens: max,
+            })
+        };
+        assert_eq!(pooling(true, false, false), Pooling::Cls);
+        assert_eq!(pooling(false, false, true), Pooling::Max);
+        assert_eq!(pooling(false, true, false), Pooling::Mean);
+        assert_eq!(pooling(false, false, false), Pooling::Mean);
+        assert_eq!(pooling(true, true, true), Pooling::Mean);
+    }
+}
@@ -0,0 +1,350 @@
+/*
+Multiple ranked completions per request: independent samples and beam search.
+
+Both start from a single prefill of the prompt. The resulting KV cache and the logits of the
+first generated position are shared by every candidate, which then continues on its own copy
+of the cache. Candidates are decoded one token per step (without speculative decoding) so
+the log-probability of every chosen token is known; log-probabilities are those of the model
+itself, before temperature scaling.
+*/
+use super::llama::{GenerationState, LlamaInferenceModel, Usage};
+use candle::{Tensor, D};
+use serde::{Deserialize, Serialize};
+
+/// Beam-search parameters.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct BeamSearch {
+    pub num_beams: usize,
+    /// Exponent applied to the candidate length when ranking finished beams; values above 1
+    /// favour longer candidates, values below 1 shorter ones.
+    #[serde(default = "BeamSearch::default_length_penalty")]
+    pub length_penalty: f64,
+    /// Stop as soon as `num_beams` candidates have finished instead of when no live beam can
+    /// still beat them.
+    #[serde(default)]
+    pub early_stopping: bool,
+}
+
+/// One generated candidate.
+#[derive(Debug, Clone, Serialize)]
+pub struct Candidate {
+    pub text: String,
+    pub tokens: Vec<u32>,
+    /// Sum of the log-probabilities of the generated tokens.
+    pub log_prob: f64,
+    /// Ranking score: `log_prob` for samples, the length-normalized `log_prob` for beams.
+    pub score: f64,
+}
+
+pub struct Candidates {
+    /// Candidates ordered from best to worst score.
+    pub candidates: Vec<Candidate>,
+    pub usage: Usage,
+}
+
+// A live beam: its decoding state and the log-probability of its generated tokens
+struct Beam {
+    state: GenerationState,
+    generated: Vec<u32>,
+    log_prob: f64,
+}
+
+impl BeamSearch {
+    fn default_length_penalty() -> f64 {
+        1.0
+    }
+
+    pub fn validate(&self, max_beams: usize) -> Result<(), String> {
+        if self.num_beams == 0 || self.num_beams > max_beams {
+            return Err(format!(
+                "num_beams must be between 1 and {}, got {}",
+                max_beams, self.num_beams
+            ));
+        }
+        if !self.length_penalty.is_finite() {
+            return Err(format!(
+                "length_penalty must be a finite number, got {}",
+                self.length_penalty
+            ));
+        }
+        Ok(())
+    }
+
+    fn score(&self, log_prob: f64, length: usize) -> f64 {
+        log_prob / (length.max(1) as f64).powf(self.length_penalty)
+    }
+
+    /*
+    Whether the search can end, given the best finished candidates (sorted, at most
+    `num_beams`) and the `(log_prob, length)` of every live beam.
+    */
+    fn is_done(&self, finished: &[Candidate], live: &[(f64, usize)], max_length: usize) -> bool {
+        match finished.len() == self.num_beams {
+            false => live.is_empty(),
+            true if self.early_stopping => true,
+            // Live beams' log-probabilities only decrease, so their best reachable score is at
+            // the current length, or at `max_length` with a positive length penalty
+            true => live.iter().all(|&(log_prob, length)| {
+                let length = match self.length_penalty > 0.0 {
+                    true => max_length,
+                    false => length,
+                };
+                self.score(log_prob, length) <= finished[self.num_beams - 1].score
+            }),
+        }
+    }
+}
+
+impl LlamaInferenceModel {
+    /*
+    Draws `n` independent samples of up to `max_length` tokens after `state`'s window.
+
+    Sample `i` uses the seed `seed + i`; with greedy decoding (no temperature) all samples are
+    identical. Candidates are ranked by their cumulative log-probability.
+    */
+    pub fn generate_samples(
+        &self,
+        state: &mut GenerationState,
+        n: usize,
+        max_length: usize,
+    ) -> anyhow::Result<Candidates> {
+        let eos_token_id = self.eos_token_id();
+        let first_logits = self.next_logits(state)?;
+        let first_log_probs = log_probs(&first_logits)?;
+
+        let mut candidates = Vec::with_capacity(n);
+        let mut completion_tokens = 0;
+        for i in 0..n {
+            let mut sample = state.fork(state.seed.wrapping_add(i as u64));
+            let mut generated = Vec::new();
+            let mut log_prob = 0.0;
+            let (mut logits, mut token_log_probs) = (first_logits.clone(), first_log_probs.clone());
+            loop {
+                let token = sample.sample_token(&logits)?;
+                sample.tokens.push(token);
+                generated.push(token);
+                log_prob += f64::from(token_log_probs[token as usize]);
+                if Some(token) == eos_token_id || generated.len() == max_length {
+                    break;
+                }
+                logits = self.next_logits(&mut sample)?;
+                token_log_probs = log_probs(&logits)?;
+            }
+            completion_tokens += generated.len();
+            candidates.push(self.candidate(generated, log_prob, log_prob));
+        }
+
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(Candidates {
+            candidates,
+            usage: state.usage(completion_tokens),
+        })
+    }
+
+    /*
+    Beam search over continuations of `state`'s window, returning the best
+    `num_return_sequences` finished candidates.
+
+    Every step expands each live beam by its most likely tokens and keeps the `num_beams` best
+    continuations by cumulative log-probability. Continuations ending in EOS become finished
+    candidates ranked by `log_prob / length^length_penalty`. The search ends at `max_length`,
+    when `num_beams` candidates have finished with `early_stopping`, or otherwise when no live
+    beam can still beat the worst of the `num_beams` best finished candidates.
+    */
+    pub fn beam_search(
+        &self,
+        state: &mut GenerationState,
+        params: BeamSearch,
+        num_return_sequences: usize,
+        max_length: usize,
+    ) -> anyhow::Result<Candidates> {
+        let eos_token_id = self.eos_token_id();
+        let num_beams = params.num_beams;
+        let mut finished: Vec<Candidate> = Vec::new();
+        let mut completion_tokens = 0;
+
+        let root_logits = self.next_logits(state)?;
+        let mut beams = vec![Beam {
+            state: state.fork(state.seed),
+            generated: Vec::new(),
+            log_prob: 0.0,
+        }];
+        let mut beam_log_probs = vec![log_probs(&root_logits)?];
+
+        for step in 0..max_length {
+            // Best continuations over all beams; 2 * num_beams so that enough of them remain
+            // after the ones ending in EOS are set aside
+            let mut expansions: Vec<(usize, u32, f64)> = Vec::new();
+            for (index, (beam, token_log_probs)) in beams.iter().zip(&beam_log_probs).enumerate() {
+                for (token, token_log_prob) in top_k(token_log_probs, 2 * num_beams) {
+                    expansions.push((index, token, beam.log_prob + f64::from(token_log_prob)));
+                }
+            }
+            expansions.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut next_beams: Vec<Beam> = Vec::with_capacity(num_beams);
+            for (index, token, log_prob) in expansions {
+                let parent = &beams[index];
+                let mut generated = parent.generated.clone();
+                generated.push(token);
+                if Some(token) == eos_token_id {
+                    completion_tokens += generated.len();
+                    let score = params.score(log_prob, generated.len());
+                    finished.push(self.candidate(generated, log_prob, score));
+                    continue;
+                }
+                let mut child = parent.state.fork(parent.state.seed);
+                child.tokens.push(token);
+                next_beams.push(Beam {
+                    state: child,
+                    generated,
+                    log_prob,
+                });
+                if next_beams.len() == num_beams {
+                    break;
+                }
+            }
+            finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+            finished.truncate(num_beams);
+            beams = next_beams;
+
+            let live: Vec<(f64, usize)> = beams
+                .iter()
+                .map(|beam| (beam.log_prob, beam.generated.len()))
+                .collect();
+            let done = params.is_done(&finished, &live, max_length);
+            if done || step + 1 == max_length {
+                break;
+            }
+
+            beam_log_probs = beams
+                .iter_mut()
+                .map(|beam| log_probs(&self.next_logits(&mut beam.state)?))
+                .collect::<anyhow::Result<_>>()?;
+        }
+
+        // Beams still running at `max_length` compete with the finished ones
+        for beam in beams {
+            completion_tokens += beam.generated.len();
+            let score = params.score(beam.log_prob, beam.generated.len());
+            finished.push(self.candidate(beam.generated, beam.log_prob, score));
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(num_return_sequences);
+        Ok(Candidates {
+            candidates: finished,
+            usage: state.usage(completion_tokens),
+        })
+    }
+
+    fn candidate(&self, tokens: Vec<u32>, log_prob: f64, score: f64) -> Candidate {
+        let eos_token_id = self.eos_token_id();
+        let text = tokens
+            .iter()
+            .filter(|&&token| Some(token) != eos_token_id)
+            .map(|&token| self.decode_token(token))
+            .collect();
+        Candidate {
+            text,
+            tokens,
+            log_prob,
+            score,
+        }
+    }
+}
+
+fn log_probs(logits: &Tensor) -> anyhow::Result<Vec<f32>> {
+    Ok(candle_nn::ops::log_softmax(logits, D::Minus1)?.to_vec1::<f32>()?)
+}
+
+// The `k` most likely tokens with their log-probabilities, most likely first
+fn top_k(log_probs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut indexed: Vec<(u32, f32)> = log_probs
+        .iter()
+        .enumerate()
+        .map(|(token, &log_prob)| (token as u32, log_prob))
+        .collect();
+    let k = k.min(indexed.len());
+    if k < indexed.len() {
+        indexed.select_nth_unstable_by(k, |a, b| b.1.total_cmp(&a.1));
+        indexed.truncate(k);
+    }
+    indexed.sort_by(|a, b| b.1.total_cmp(&a.1));
+    indexed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(num_beams: usize, length_penalty: f64, early_stopping: bool) -> BeamSearch {
+        BeamSearch {
+            num_beams,
+            length_penalty,
+            early_stopping,
+        }
+    }
+
+    fn finished(scores: &[f64]) -> Vec<Candidate> {
+        scores
+            .iter()
+            .map(|&score| Candidate {
+                text: String::new(),
+                tokens: Vec::new(),
+                log_prob: score,
+                score,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn length_penalty_decides_between_short_and_long_candidates() {
+        let (short, long) = ((-3.0, 2), (-4.0, 4));
+        let rank =
+            |params: BeamSearch| params.score(short.0, short.1) > params.score(long.0, long.1);
+        // Without normalization the higher log-probability wins
+        assert!(rank(params(2, 0.0, false)));
+        // Per-token normalization favours the longer candidate here
+        assert!(!rank(params(2, 1.0, false)));
+        assert_eq!(params(2, 1.0, false).score(-4.0, 4), -1.0);
+        assert_eq!(params(2, 2.0, false).score(-4.0, 4), -0.25);
+        // An empty candidate is not divided by zero
+        assert_eq!(params(2, 1.0, false).score(-1.0, 0), -1.0);
+    }
+
+    #[test]
+    fn search_stops_once_no_live_beam_can_beat_the_finished_ones() {
+        let live = [(-2.0, 2)];
+        // Not enough finished candidates: keep going while beams are alive
+        assert!(!params(2, 1.0, false).is_done(&finished(&[-0.5]), &live, 10));
+        assert!(params(2, 1.0, false).is_done(&finished(&[-0.5]), &[], 10));
+
+        // Early stopping ends as soon as `num_beams` have finished
+        assert!(params(2, 1.0, true).is_done(&finished(&[-0.5, -3.0]), &live, 10));
+
+        // The live beam could still reach -2 / 10 = -0.2 by `max_length`
+        assert!(!params(2, 1.0, false).is_done(&finished(&[-0.1, -0.3]), &live, 10));
+        assert!(params(2, 1.0, false).is_done(&finished(&[-0.1, -0.15]), &live, 10));
+
+        // Without a positive length penalty its best score is the current one
+        assert!(params(2, 0.0, false).is_done(&finished(&[-1.0, -2.0]), &live, 10));
+        assert!(!params(2, 0.0, false).is_done(&finished(&[-1.0, -2.5]), &live, 10));
+        assert!(!params(2, -1.0, false).is_done(&finished(&[-1.0, -4.5]), &live, 10));
+    }
+
+    #[test]
+    fn top_k_returns_the_most_likely_tokens_first() {
+        let log_probs = [-3.0, -0.5, -2.0, -1.0];
+        assert_eq!(top_k(&log_probs, 2), vec![(1, -0.5), (3, -1.0)]);
+        assert_eq!(top_k(&log_probs, 10).len(), 4);
+        assert_eq!(top_k(&log_probs, 10)[3], (0, -3.0));
+    }
+
+    #[test]
+    fn beam_parameters_are_validated() {
+        assert!(params(4, 1.0, false).validate(8).is_ok());
+        assert!(params(0, 1.0, false).validate(8).is_err());
+        assert!(params(9, 1.0, false).validate(8).is_err());
+        assert!(params(4, f64::NAN, false).validate(8).is_err());
+    }
+}
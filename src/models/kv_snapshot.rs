@@ -0,0 +1,133 @@
+/*
+On-disk snapshots of a sequence's KV cache and token ids.
+
+A snapshot is a safetensors file holding the context window as a `tokens` U32 tensor and, for
+every attention layer, its `layers.{i}.key` / `layers.{i}.value` tensors shaped
+(1, kv_heads, positions, head_dim). The header metadata records the format version, the model
+the states were computed with and its attention shape, so a snapshot is only ever restored
+into a compatible model; callers can attach extra metadata such as a chat history.
+*/
+use super::llama_model::{Cache, LayerKv};
+use candle::{Device, Tensor};
+use candle_transformers::models::llama::Config;
+use safetensors::SafeTensors;
+use std::collections::HashMap;
+use std::path::Path;
+
+const FORMAT: &str = "kv-snapshot";
+const FORMAT_VERSION: &str = "1";
+const TOKENS_KEY: &str = "tokens";
+
+pub struct KvSnapshot {
+    pub tokens: Vec<u32>,
+    pub kvs: Vec<Option<LayerKv>>,
+    // Caller-provided metadata stored next to the model description
+    pub metadata: HashMap<String, String>,
+}
+
+// Metadata describing the model a snapshot belongs to
+fn model_metadata(model_id: &str, config: &Config) -> HashMap<String, String> {
+    HashMap::from([
+        ("format".to_string(), FORMAT.to_string()),
+        ("format_version".to_string(), FORMAT_VERSION.to_string()),
+        ("model_id".to_string(), model_id.to_string()),
+        (
+            "num_hidden_layers".to_string(),
+            config.num_hidden_layers.to_string(),
+        ),
+        (
+            "num_key_value_heads".to_string(),
+            config.num_key_value_heads.to_string(),
+        ),
+        (
+            "head_dim".to_string(),
+            (config.hidden_size / config.num_attention_heads).to_string(),
+        ),
+    ])
+}
+
+/*
+Writes `tokens` and the KV states held by `cache` to `path`.
+
+The file is written next to its destination and renamed into place, so a reader (or another
+replica sharing the directory) never observes a partially written snapshot.
+*/
+pub fn save(
+    path: &Path,
+    tokens: &[u32],
+    cache: &Cache,
+    model_id: &str,
+    config: &Config,
+    extra_metadata: HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut tensors = vec![(TOKENS_KEY.to_string(), Tensor::new(tokens, &Device::Cpu)?)];
+    for (layer, kv) in cache.kvs().iter().enumerate() {
+        if let Some((k, v)) = kv {
+            tensors.push((format!("layers.{}.key", layer), k.contiguous()?));
+            tensors.push((format!("layers.{}.value", layer), v.contiguous()?));
+        }
+    }
+
+    let mut metadata = extra_metadata;
+    metadata.extend(model_metadata(model_id, config));
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("safetensors.tmp");
+    safetensors::serialize_to_file(tensors, &Some(metadata), &tmp_path)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Read a snapshot written by `save`, checking that it matches `model_id` and `config`
+pub fn load(
+    path: &Path,
+    model_id: &str,
+    config: &Config,
+    device: &Device,
+) -> anyhow::Result<KvSnapshot> {
+    let buffer = std::fs::read(path)?;
+    let (_, header) = SafeTensors::read_metadata(&buffer)?;
+    let mut metadata = header.metadata().clone().unwrap_or_default();
+
+    for (key, expected) in model_metadata(model_id, config) {
+        match metadata.remove(&key) {
+            Some(found) if found == expected => {}
+            found => anyhow::bail!(
+                "snapshot {} does not match the loaded model: {} is {:?}, expected {:?}",
+                path.display(),
+                key,
+                found,
+                expected
+            ),
+        }
+    }
+
+    let mut tensors = candle::safetensors::load_buffer(&buffer, device)?;
+    let tokens = match tensors.remove(TOKENS_KEY) {
+        Some(tokens) => tokens.to_vec1::<u32>()?,
+        None => anyhow::bail!("snapshot {} has no {} tensor", path.display(), TOKENS_KEY),
+    };
+    let kvs = (0..config.num_hidden_layers)
+        .map(|layer| {
+            let k = tensors.remove(&format!("layers.{}.key", layer));
+            let v = tensors.remove(&format!("layers.{}.value", layer));
+            match (k, v) {
+                (Some(k), Some(v)) => Ok(Some((k, v))),
+                (None, None) => Ok(None),
+                _ => Err(anyhow::anyhow!(
+                    "snapshot {} has an incomplete layer {}",
+                    path.display(),
+                    layer
+                )),
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(KvSnapshot {
+        tokens,
+        kvs,
+        metadata,
+    })
+}
@@ -1,21 +1,293 @@
+use super::kv_snapshot;
+use super::llama_model as model;
+use super::prefix_cache::PrefixCache;
+use super::speculative::{
+    DraftState, PromptLookup, SpeculativeDecoder, SpeculativeMetrics, SpeculativeMode,
+};
+use crate::constraints::{Matcher, ResponseFormat, TokenConstraint, TokenVocabulary};
 use actix_web::web;
 use candle::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::models::llama as model;
+use candle_transformers::models::llama::{Config, LlamaConfig};
 use hf_hub::api::sync::ApiBuilder;
 use hf_hub::{Repo, RepoType};
-use model::{Config, Llama, LlamaConfig};
-use std::sync::{Arc, Mutex};
+use model::Llama;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
 use tokenizers::Tokenizer;
 
 const EOS_TOKEN: &str = "</s>";
+const BOS_TOKEN: &str = "<s>";
+
+/// Which end of an over-long prompt gets dropped to make it fit the context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncate {
+    /// Drop the oldest tokens, keeping the end of the prompt (and a leading BOS token).
+    Left,
+    /// Drop the newest tokens, keeping the beginning of the prompt.
+    Right,
+}
+
+impl Truncate {
+    // Shrink `tokens` to at most `limit` entries, never removing a leading BOS token
+    pub fn apply(self, tokens: &mut Vec<u32>, limit: usize, bos_token_id: Option<u32>) {
+        if tokens.len() <= limit {
+            return;
+        }
+        match self {
+            Truncate::Right => tokens.truncate(limit),
+            Truncate::Left => {
+                let keep_bos =
+                    limit > 0 && bos_token_id.is_some() && tokens.first() == bos_token_id.as_ref();
+                let start = if keep_bos { 1 } else { 0 };
+                let excess = tokens.len() - limit;
+                tokens.drain(start..start + excess);
+            }
+        }
+    }
+}
+
+/// Opt-in sliding-window mode for generations that outgrow the context window.
+///
+/// When the window fills up, the first `preserve_prefix` tokens (typically BOS plus the
+/// system prompt) are kept, the oldest tokens after them are dropped and the KV cache is
+/// rebuilt from the remaining window so generation can continue.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ContextShift {
+    /// Number of leading tokens that are never dropped.
+    #[serde(default)]
+    pub preserve_prefix: usize,
+    /// Number of tokens dropped per shift; defaults to half of the tokens after the prefix.
+    #[serde(default)]
+    pub discard: Option<usize>,
+}
+
+impl ContextShift {
+    // Check that a shift always frees space in a window of `context_length` tokens
+    pub fn validate(&self, context_length: usize) -> Result<(), String> {
+        if self.preserve_prefix > context_length / 2 {
+            return Err(format!(
+                "context_shift.preserve_prefix must be at most {} tokens, got {}",
+                context_length / 2,
+                self.preserve_prefix
+            ));
+        }
+        if let Some(discard) = self.discard {
+            let shiftable = context_length - self.preserve_prefix;
+            if discard == 0 || discard >= shiftable {
+                return Err(format!(
+                    "context_shift.discard must be between 1 and {}, got {}",
+                    shiftable - 1,
+                    discard
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Drop the oldest tokens after the preserved prefix
+    fn apply(&self, tokens: &mut Vec<u32>) -> anyhow::Result<()> {
+        let preserve = self.preserve_prefix.min(tokens.len());
+        let shiftable = tokens.len() - preserve;
+        if shiftable < 2 {
+            anyhow::bail!("context shift cannot free space: the preserved prefix fills the window");
+        }
+        let discard = self
+            .discard
+            .unwrap_or(shiftable / 2)
+            .clamp(1, shiftable - 1);
+        tokens.drain(preserve..preserve + discard);
+        Ok(())
+    }
+}
+
+/*
+Per-sequence decoding state shared by the blocking and streaming generation paths.
+
+`tokens` holds the current context window (prompt plus generated tokens), of which the
+first `index_pos` are already stored in `cache`; the remaining ones are fed to the model on
+the next step.
+*/
+pub struct GenerationState {
+    pub(super) tokens: Vec<u32>,
+    pub(super) cache: model::Cache,
+    pub(super) index_pos: usize,
+    logits_processor: LogitsProcessor,
+    pub(super) seed: u64,
+    // Sampling temperature, `None` for greedy decoding
+    pub(super) temperature: Option<f64>,
+    context_shift: Option<ContextShift>,
+    prompt_tokens: usize,
+    // Prompt tokens restored from the prefix cache instead of being prefilled
+    cached_tokens: usize,
+    // Whether the prompt KV states still have to be offered to the prefix cache
+    cache_prompt: bool,
+    // Source of speculative proposals and the randomness used to sample and verify them
+    speculative_mode: SpeculativeMode,
+    pub(super) rng: StdRng,
+    // Draft-model state for speculative decoding, created on the first speculative step
+    pub(super) draft: Option<DraftState>,
+    pub(super) draft_tokens: usize,
+    pub(super) accepted_draft_tokens: usize,
+    // Restricts sampling to tokens that keep the output valid (structured output)
+    constraint: Option<TokenConstraint>,
+}
+
+impl GenerationState {
+    // Tokens of the current context window
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /*
+    Appends the tokens of a new conversation turn to the window.
+
+    Everything generated so far stays in the KV cache, so the next step only prefills the
+    appended tokens; usage is reset to describe the new turn.
+    */
+    pub fn begin_turn(&mut self, tokens: &[u32]) {
+        self.tokens.extend_from_slice(tokens);
+        self.prompt_tokens = self.tokens.len();
+        self.cached_tokens = self.index_pos;
+        self.draft_tokens = 0;
+        self.accepted_draft_tokens = 0;
+    }
+
+    // Close the current turn with `eos_token_id` unless generation already stopped on it
+    pub fn end_turn(&mut self, eos_token_id: Option<u32>) {
+        if let Some(eos_token_id) = eos_token_id {
+            if self.tokens.last() != Some(&eos_token_id) {
+                self.tokens.push(eos_token_id);
+            }
+        }
+    }
+
+    pub fn set_context_shift(&mut self, context_shift: Option<ContextShift>) {
+        self.context_shift = context_shift;
+    }
+
+    // Sample with `temperature` instead of greedily (`None` or 0 keeps greedy decoding)
+    pub fn set_temperature(&mut self, temperature: Option<f64>) {
+        self.temperature = temperature.filter(|&temperature| temperature > 0.0);
+        self.logits_processor = LogitsProcessor::new(self.seed, self.temperature, None);
+    }
+
+    pub fn set_speculative_mode(&mut self, speculative_mode: SpeculativeMode) {
+        self.speculative_mode = speculative_mode;
+    }
+
+    pub fn set_constraint(&mut self, constraint: Option<TokenConstraint>) {
+        self.constraint = constraint;
+    }
+
+    // Whether the output generated so far satisfies the state's constraint, if any
+    pub fn constraint_satisfied(&self) -> bool {
+        self.constraint
+            .as_ref()
+            .is_none_or(TokenConstraint::is_complete)
+    }
+
+    // Sample a token from `logits` with the state's sampler (without appending it)
+    pub(super) fn sample_token(&mut self, logits: &Tensor) -> anyhow::Result<u32> {
+        Ok(self.logits_processor.sample(logits)?)
+    }
+
+    // Independent copy of the sequence that shares the KV states computed so far; the copy
+    // samples with its own `seed`
+    pub(super) fn fork(&self, seed: u64) -> GenerationState {
+        GenerationState {
+            tokens: self.tokens.clone(),
+            cache: self.cache.clone(),
+            index_pos: self.index_pos,
+            logits_processor: LogitsProcessor::new(seed, self.temperature, None),
+            seed,
+            temperature: self.temperature,
+            context_shift: self.context_shift,
+            prompt_tokens: self.prompt_tokens,
+            cached_tokens: self.cached_tokens,
+            cache_prompt: false,
+            speculative_mode: self.speculative_mode,
+            rng: StdRng::seed_from_u64(seed),
+            draft: None,
+            draft_tokens: 0,
+            accepted_draft_tokens: 0,
+            constraint: self.constraint.clone(),
+        }
+    }
+
+    // Token accounting for a sequence that has produced `completion_tokens` tokens
+    pub fn usage(&self, completion_tokens: usize) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens,
+            cached_tokens: self.cached_tokens,
+            speculative: (self.draft_tokens > 0).then_some(SpeculativeUsage {
+                draft_tokens: self.draft_tokens,
+                accepted_draft_tokens: self.accepted_draft_tokens,
+            }),
+        }
+    }
+
+    // Store the prompt KV states in the prefix cache after the prompt's first forward pass
+    pub(super) fn offer_prompt_to_prefix_cache(
+        &mut self,
+        model: &LlamaInferenceModel,
+    ) -> anyhow::Result<()> {
+        if !self.cache_prompt {
+            return Ok(());
+        }
+        self.cache_prompt = false;
+        if let Some(prefix_cache) = &model.prefix_cache {
+            let mut prefix_cache = prefix_cache
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock prefix cache: {:?}", e))?;
+            prefix_cache.insert(&self.tokens, &self.cache)?;
+            log::debug!("Prefix cache holds {} bytes", prefix_cache.used_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// Token accounting reported alongside generated text.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Prompt tokens whose KV states were reused from the prefix cache.
+    pub cached_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speculative: Option<SpeculativeUsage>,
+}
+
+/// Draft tokens proposed and accepted while generating a response.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SpeculativeUsage {
+    pub draft_tokens: usize,
+    pub accepted_draft_tokens: usize,
+}
+
+pub struct Generation {
+    pub text: String,
+    pub usage: Usage,
+}
 
 pub struct LlamaInferenceModel {
     pub model: Llama,
     pub tokenizer: Tokenizer,
     pub device: Device,
     pub config: Config,
+    pub model_id: String,
+    prefix_cache: Option<Mutex<PrefixCache>>,
+    speculative: Option<SpeculativeDecoder>,
+    prompt_lookup: PromptLookup,
+    // Token texts for constrained decoding, built on first use
+    constraint_vocabulary: OnceLock<Arc<TokenVocabulary>>,
 }
 
 impl LlamaInferenceModel {
@@ -65,22 +337,58 @@ impl LlamaInferenceModel {
             tokenizer,
             device,
             config,
+            model_id: model_id.to_string(),
+            prefix_cache: None,
+            speculative: None,
+            prompt_lookup: PromptLookup::default(),
+            constraint_vocabulary: OnceLock::new(),
         })
     }
 
+    // Reuse prompt KV states across requests, keeping at most `capacity_bytes` of them
+    pub fn enable_prefix_cache(&mut self, capacity_bytes: usize) {
+        self.prefix_cache = Some(Mutex::new(PrefixCache::new(capacity_bytes)));
+    }
+
+    // Let `draft` propose up to `num_speculative_tokens` tokens per step for this model to verify
+    pub fn enable_speculative_decoding(
+        &mut self,
+        draft: Arc<LlamaInferenceModel>,
+        num_speculative_tokens: usize,
+    ) -> anyhow::Result<()> {
+        self.speculative = Some(SpeculativeDecoder::new(
+            draft,
+            num_speculative_tokens,
+            &self.config,
+        )?);
+        Ok(())
+    }
+
+    // Acceptance metrics of the draft model (when one is configured) and of prompt lookup
+    pub fn speculative_stats(&self) -> SpeculativeMetrics {
+        SpeculativeMetrics {
+            draft: self.speculative.as_ref().map(SpeculativeDecoder::stats),
+            prompt_lookup: self.prompt_lookup.stats(),
+        }
+    }
+
     pub fn generate_text(&self, prompt: &str, max_length: usize) -> anyhow::Result<String> {
-        // Use cache to speed up the generation, first parameter 'true' means to use key-value cache
-        let mut cache = self.create_cache()?;
-        let mut tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(|e| anyhow::anyhow!(e))?
-            .get_ids()
-            .to_vec();
-        println!("Tokens: {:?}", tokens);
+        let tokens = self.encode_prompt(prompt)?;
+        Ok(self
+            .generate_from_tokens(tokens, max_length, None, None, SpeculativeMode::default())?
+            .text)
+    }
 
-        let eos_token_id = self.tokenizer.token_to_id(EOS_TOKEN);
-        println!("EOS token ID: {:?}", eos_token_id);
+    // Generate text from an already encoded (and validated) prompt
+    pub fn generate_from_tokens(
+        &self,
+        tokens: Vec<u32>,
+        max_length: usize,
+        context_shift: Option<ContextShift>,
+        temperature: Option<f64>,
+        speculative_mode: SpeculativeMode,
+    ) -> anyhow::Result<Generation> {
+        log::debug!("Prompt tokens: {:?}", tokens);
 
         let seed = 42;
         // let temperature = Some(0.8);
@@ -89,55 +397,174 @@ impl LlamaInferenceModel {
 
         // Use simple sampling
         // let mut logits_processor = LogitsProcessor::new(seed, None, None);
-        let mut logits_processor = self.create_logits_processor(seed);
+        let mut state = self.start_generation(tokens, seed, context_shift)?;
+        state.set_temperature(temperature);
+        state.set_speculative_mode(speculative_mode);
+        self.generate(&mut state, max_length)
+    }
+
+    // Decode up to `max_length` tokens from `state`, stopping early at EOS (which is not
+    // included in the returned text)
+    pub fn generate(
+        &self,
+        state: &mut GenerationState,
+        max_length: usize,
+    ) -> anyhow::Result<Generation> {
+        let eos_token_id = self.eos_token_id();
         let start_gen = std::time::Instant::now();
-        let mut index_pos = 0;
+        let mut generated_tokens = 0;
         let mut generated_text = String::new();
 
-        for index in (0..max_length) {
-            // Determine the context size and index for the current token
-            let (context_size, context_index) = if index > 0 {
-                (1, index_pos)
-            } else {
-                (tokens.len(), 0)
-            };
+        'generation: while generated_tokens < max_length {
+            for next_token in self.next_tokens(state, max_length - generated_tokens)? {
+                generated_tokens += 1;
 
-            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
+                if Some(next_token) == eos_token_id {
+                    break 'generation;
+                }
 
-            let logits = self
-                .model
-                .forward(&input, context_index, &mut cache)?
-                .squeeze(0)?;
+                if let Some(text) = self.tokenizer.id_to_token(next_token) {
+                    let formatted_text = text.replace('▁', " ").replace("<0x0A>", "\n");
+                    generated_text.push_str(&formatted_text);
+                }
+            }
+        }
 
-            // Update the index position
-            index_pos += ctxt.len();
+        let dt = start_gen.elapsed();
+        log::info!(
+            "{} tokens generated ({} token/s)",
+            generated_tokens,
+            generated_tokens as f64 / dt.as_secs_f64(),
+        );
+        log::debug!("Generated text: {:?}", generated_text);
 
-            let next_token = logits_processor.sample(&logits)?;
-            println!("next_token: {:?}", next_token);
-            tokens.push(next_token);
+        Ok(Generation {
+            text: generated_text,
+            usage: state.usage(generated_tokens),
+        })
+    }
 
-            if let Some(text) = self.tokenizer.id_to_token(next_token) {
-                let formatted_text = text.replace('▁', " ").replace("<0x0A>", "\n");
-                println!("formatted_text: {:?}", formatted_text);
-                generated_text.push_str(&formatted_text);
+    // Set up the KV cache and sampler for decoding after `tokens`, starting from the longest
+    // cached prompt prefix when the prefix cache is enabled
+    pub fn start_generation(
+        &self,
+        tokens: Vec<u32>,
+        seed: u64,
+        context_shift: Option<ContextShift>,
+    ) -> anyhow::Result<GenerationState> {
+        // Use cache to speed up the generation, first parameter 'true' means to use key-value cache
+        let mut cache = self.create_cache()?;
+        let mut cached_tokens = 0;
+        if let Some(prefix_cache) = &self.prefix_cache {
+            let hit = prefix_cache
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock prefix cache: {:?}", e))?
+                .lookup(&tokens);
+            if let Some((matched, kvs)) = hit {
+                cache.set_kvs(kvs)?;
+                cached_tokens = matched;
+                log::debug!(
+                    "Prefix cache hit: reusing {} of {} prompt tokens",
+                    matched,
+                    tokens.len()
+                );
             }
+        }
 
-            if Some(next_token) == eos_token_id {
-                println!("EOS token found");
-                break;
-            }
+        Ok(GenerationState {
+            prompt_tokens: tokens.len(),
+            tokens,
+            cache,
+            index_pos: cached_tokens,
+            logits_processor: self.create_logits_processor(seed),
+            seed,
+            temperature: None,
+            context_shift,
+            cached_tokens,
+            cache_prompt: self.prefix_cache.is_some(),
+            speculative_mode: SpeculativeMode::default(),
+            rng: StdRng::seed_from_u64(seed),
+            draft: None,
+            draft_tokens: 0,
+            accepted_draft_tokens: 0,
+            constraint: None,
+        })
+    }
+
+    /*
+    Runs one decoding step: feeds every token not yet in the KV cache through the model,
+    samples the next token and appends it to the window.
+
+    If the pending tokens would run past the context length, the window is shifted according
+    to the state's `ContextShift` and the cache is rebuilt by prefilling the shifted window;
+    without a context shift this is an error.
+    */
+    pub fn next_token(&self, state: &mut GenerationState) -> anyhow::Result<u32> {
+        let mut logits = self.next_logits(state)?;
+        if let Some(constraint) = &state.constraint {
+            logits = constraint.mask(&logits)?;
         }
+        let next_token = state.sample_token(&logits)?;
+        if let Some(constraint) = &mut state.constraint {
+            constraint.advance(next_token)?;
+        }
+        state.tokens.push(next_token);
+        Ok(next_token)
+    }
 
-        let dt = start_gen.elapsed();
-        println!(
-            "\n\n{} tokens generated ({} token/s)\n",
-            tokens.len(),
-            tokens.len() as f64 / dt.as_secs_f64(),
-        );
-        println!("Generated text: {:?}", generated_text);
+    // Feed the pending tokens of `state` through the model and return the logits of the
+    // next position, leaving the choice of the token to the caller
+    pub(super) fn next_logits(&self, state: &mut GenerationState) -> anyhow::Result<Tensor> {
+        let context_length = self.context_length();
+        if state.tokens.len() > context_length {
+            let Some(context_shift) = state.context_shift else {
+                anyhow::bail!("context window of {} tokens exhausted", context_length);
+            };
+            let before = state.tokens.len();
+            context_shift.apply(&mut state.tokens)?;
+            state.cache = self.create_cache()?;
+            state.index_pos = 0;
+            state.cache_prompt = false;
+            state.draft = None;
+            log::info!(
+                "Context shift: dropped {} tokens, re-prefilling {}",
+                before - state.tokens.len(),
+                state.tokens.len()
+            );
+        }
+
+        let ctxt = &state.tokens[state.index_pos..];
+        let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
+        let logits = self
+            .model
+            .forward(&input, state.index_pos, &mut state.cache)?
+            .squeeze(0)?;
+        state.index_pos = state.tokens.len();
+        state.offer_prompt_to_prefix_cache(self)?;
+        Ok(logits)
+    }
 
-        Ok(generated_text)
+    // Compile `format` into a constraint for one sequence, or `None` for unconstrained text
+    pub fn constraint(&self, format: &ResponseFormat) -> Result<Option<TokenConstraint>, String> {
+        Ok(format
+            .matcher()?
+            .map(|matcher| self.matcher_constraint(matcher)))
+    }
+
+    // Constraint restricting one sequence to the text `matcher` accepts
+    pub fn matcher_constraint(&self, matcher: Matcher) -> TokenConstraint {
+        let vocabulary = self.constraint_vocabulary.get_or_init(|| {
+            // Special and raw byte tokens have no text of their own (except the newline byte,
+            // which `decode_token` renders)
+            let tokens = (0..self.config.vocab_size as u32).filter_map(|token| {
+                let piece = self.tokenizer.id_to_token(token)?;
+                let special = [BOS_TOKEN, EOS_TOKEN, "<unk>"].contains(&piece.as_str());
+                let byte = piece.starts_with("<0x") && piece != "<0x0A>";
+                (!special && !byte).then(|| (token, self.decode_token(token)))
+            });
+            Arc::new(TokenVocabulary::new(self.config.vocab_size, tokens))
+        });
+        TokenConstraint::new(Arc::clone(vocabulary), matcher, self.eos_token_id())
     }
 
     // Method to encode the prompt into initial tokens
@@ -148,12 +575,130 @@ impl LlamaInferenceModel {
             .map(|encoded| encoded.get_ids().to_vec())
     }
 
+    // Encode text that continues an existing sequence, without adding a BOS token
+    pub fn encode_continuation(&self, text: &str) -> anyhow::Result<Vec<u32>> {
+        self.tokenizer
+            .encode(text, false)
+            .map_err(|e| anyhow::anyhow!(e))
+            .map(|encoded| encoded.get_ids().to_vec())
+    }
+
     // Method to create a cache
     pub fn create_cache(&self) -> anyhow::Result<model::Cache> {
         model::Cache::new(true, DType::F32, &self.config, &self.device)
             .map_err(|e| anyhow::anyhow!(e))
     }
 
+    /*
+    Runs one decoding step and returns the tokens it appended to the window.
+
+    A speculative step (see `SpeculativeMode`) of an unconstrained sequence can yield several
+    tokens (the accepted proposals plus one token sampled from this model), but never more than
+    `max_new_tokens`.
+    Without a draft model, when prompt lookup finds no match or when the window has no room
+    for proposals, it is a single `next_token` step.
+    */
+    pub fn next_tokens(
+        &self,
+        state: &mut GenerationState,
+        max_new_tokens: usize,
+    ) -> anyhow::Result<Vec<u32>> {
+        // Proposals are not checked against constraints, so constrained steps are plain ones
+        let speculative_mode = match state.constraint {
+            Some(_) => SpeculativeMode::Off,
+            None => state.speculative_mode,
+        };
+        match speculative_mode {
+            SpeculativeMode::Draft => {
+                if let Some(speculative) = &self.speculative {
+                    let num_proposals = speculative
+                        .max_proposals(state.tokens.len(), self.context_length())
+                        .min(max_new_tokens.saturating_sub(1));
+                    if num_proposals > 0 {
+                        return speculative.step(self, state, num_proposals);
+                    }
+                }
+            }
+            SpeculativeMode::PromptLookup => {
+                if let Some(tokens) = self.prompt_lookup.step(self, state, max_new_tokens)? {
+                    return Ok(tokens);
+                }
+            }
+            SpeculativeMode::Off => {}
+        }
+        Ok(vec![self.next_token(state)?])
+    }
+
+    // Persist the KV cache and context window of `state` (plus `metadata`) to `path`
+    pub fn save_generation(
+        &self,
+        state: &GenerationState,
+        path: &Path,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        kv_snapshot::save(
+            path,
+            &state.tokens[..],
+            &state.cache,
+            &self.model_id,
+            &self.config,
+            metadata,
+        )
+    }
+
+    /*
+    Rebuild a generation state from a snapshot written by `save_generation`.
+
+    The restored cache holds the same positions as when it was saved, so decoding continues
+    by prefilling only the tokens that were not yet in the cache. Returns the snapshot's
+    caller-provided metadata alongside the state.
+    */
+    pub fn restore_generation(
+        &self,
+        path: &Path,
+        seed: u64,
+        context_shift: Option<ContextShift>,
+    ) -> anyhow::Result<(GenerationState, HashMap<String, String>)> {
+        let snapshot = kv_snapshot::load(path, &self.model_id, &self.config, &self.device)?;
+        let mut cache = self.create_cache()?;
+        cache.set_kvs(snapshot.kvs)?;
+
+        let index_pos = cache.seq_len();
+        let head_dim = self.config.hidden_size / self.config.num_attention_heads;
+        let expected_shape = [1, self.config.num_key_value_heads, index_pos, head_dim];
+        let consistent = cache.kvs().iter().all(|kv| match kv {
+            Some((k, v)) => k.dims() == expected_shape && v.dims() == expected_shape,
+            None => index_pos == 0,
+        });
+        if !consistent || index_pos > snapshot.tokens.len() || index_pos > self.context_length() {
+            anyhow::bail!(
+                "snapshot {} holds inconsistent KV states for {} tokens",
+                path.display(),
+                snapshot.tokens.len()
+            );
+        }
+
+        let state = GenerationState {
+            prompt_tokens: snapshot.tokens.len(),
+            tokens: snapshot.tokens,
+            cache,
+            index_pos,
+            logits_processor: self.create_logits_processor(seed),
+            seed,
+            temperature: None,
+            context_shift,
+            cached_tokens: index_pos,
+            cache_prompt: false,
+            speculative_mode: SpeculativeMode::default(),
+            rng: StdRng::seed_from_u64(seed),
+            draft: None,
+            draft_tokens: 0,
+            accepted_draft_tokens: 0,
+            constraint: None,
+        };
+        Ok((state, snapshot.metadata))
+    }
+
     // Method to create a logits processor
     pub fn create_logits_processor(&self, seed: u64) -> LogitsProcessor {
         LogitsProcessor::new(seed, None, None)
@@ -168,67 +713,147 @@ impl LlamaInferenceModel {
             .replace("<0x0A>", "\n")
     }
 
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
     // Method to get the EOS token ID
     pub fn eos_token_id(&self) -> Option<u32> {
-        self.tokenizer.token_to_id("</s>")
+        self.tokenizer.token_to_id(EOS_TOKEN)
+    }
+
+    // Method to get the BOS token ID
+    pub fn bos_token_id(&self) -> Option<u32> {
+        self.tokenizer.token_to_id(BOS_TOKEN)
+    }
+
+    // Maximum number of positions (prompt + generated tokens) the model can attend to
+    pub fn context_length(&self) -> usize {
+        self.config.max_position_embeddings
     }
 }
 /*
-Asynchronously generates the next token using the provided model and context.
+Asynchronously generates the next tokens using the provided model and generation state.
 
  # Arguments
  - `model`: An `Arc` to the `LlamaInferenceModel`. Ownership is taken to allow the model
    to be safely shared across threads during the blocking operation.
- - `ctxt`: A `Vec<u32>` representing the current context. Ownership is taken to transfer
-   the data into the separate thread without requiring synchronization mechanisms.
- - `context_index`: The index within the context to process.
- - `cache`: An `Arc<Mutex<model::Cache>>` for caching model computations. Ownership is
-   taken to ensure safe, concurrent access within the separate thread.
- - `logits_processor`: An `Arc<Mutex<LogitsProcessor>>` for processing logits. Ownership
-   is taken to allow safe, concurrent processing within the separate thread.
+ - `state`: An `Arc<Mutex<GenerationState>>` holding the context window, KV cache and
+   logits processor of the sequence. Ownership is taken to ensure safe, concurrent access
+   within the separate thread.
+ - `max_new_tokens`: Upper bound on the number of tokens returned by this step.
 
  # Returns
- - `Ok(u32)`: The next token generated.
+ - `Ok(Vec<u32>)`: The tokens generated by one decoding step; more than one when
+   speculative decoding accepted draft proposals.
  - `Err(anyhow::Error)`: An error occurred during token generation.
 
  This function offloads the blocking operation to a separate thread using `web::block`.
  By taking ownership of the arguments, it ensures that all necessary data is moved into
  the new thread, preventing potential data races and ensuring thread safety.
 */
-pub async fn generate_next_token(
+pub async fn generate_next_tokens(
     model: Arc<LlamaInferenceModel>,
-    ctxt: Vec<u32>,
-    context_index: usize,
-    cache: Arc<Mutex<model::Cache>>,
-    logits_processor: Arc<Mutex<LogitsProcessor>>,
-) -> anyhow::Result<u32> {
-    let device = model.device.clone();
-
+    state: Arc<Mutex<GenerationState>>,
+    max_new_tokens: usize,
+) -> anyhow::Result<Vec<u32>> {
     // Offload the blocking operation to a separate thread
-    let next_token = web::block(move || {
-        let input = Tensor::new(ctxt.as_slice(), &device)
-            .and_then(|t| t.unsqueeze(0))
-            .map_err(|e| anyhow::anyhow!("Failed to create input tensor: {:?}", e))?;
-
-        let mut cache = cache
-            .lock()
-            .map_err(|e| anyhow::anyhow!("Failed to lock cache: {:?}", e))?;
-
-        let logits = model
-            .model
-            .forward(&input, context_index, &mut cache)
-            .and_then(|t| t.squeeze(0))
-            .map_err(|e| anyhow::anyhow!("Failed to perform forward pass: {:?}", e))?;
-
-        let mut logits_processor = logits_processor
+    let next_tokens = web::block(move || {
+        let mut state = state
             .lock()
-            .map_err(|e| anyhow::anyhow!("Failed to lock logits processor: {:?}", e))?;
+            .map_err(|e| anyhow::anyhow!("Failed to lock generation state: {:?}", e))?;
 
-        logits_processor
-            .sample(&logits)
-            .map_err(|e| anyhow::anyhow!("Failed to sample next token: {:?}", e))
+        model
+            .next_tokens(&mut state, max_new_tokens)
+            .map_err(|e| anyhow::anyhow!("Failed to generate next tokens: {:?}", e))
     })
     .await??;
 
-    Ok(next_token)
+    Ok(next_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOS: u32 = 1;
+
+    fn truncated(truncate: Truncate, tokens: &[u32], limit: usize, bos: Option<u32>) -> Vec<u32> {
+        let mut tokens = tokens.to_vec();
+        truncate.apply(&mut tokens, limit, bos);
+        tokens
+    }
+
+    #[test]
+    fn truncation_drops_one_end_of_the_prompt() {
+        let prompt = [BOS, 10, 11, 12, 13, 14];
+        assert_eq!(
+            truncated(Truncate::Left, &prompt, 4, Some(BOS)),
+            [BOS, 12, 13, 14]
+        );
+        assert_eq!(
+            truncated(Truncate::Right, &prompt, 4, Some(BOS)),
+            [BOS, 10, 11, 12]
+        );
+        // Prompts that fit are left alone
+        assert_eq!(truncated(Truncate::Left, &prompt, 6, Some(BOS)), prompt);
+        assert_eq!(truncated(Truncate::Right, &prompt, 9, Some(BOS)), prompt);
+    }
+
+    #[test]
+    fn left_truncation_keeps_a_leading_bos_token_only() {
+        let prompt = [BOS, 10, 11, 12];
+        assert_eq!(truncated(Truncate::Left, &prompt, 1, Some(BOS)), [BOS]);
+        assert_eq!(truncated(Truncate::Left, &prompt, 2, None), [11, 12]);
+        assert_eq!(
+            truncated(Truncate::Left, &[10, BOS, 11], 2, Some(BOS)),
+            [BOS, 11]
+        );
+        assert!(truncated(Truncate::Left, &prompt, 0, Some(BOS)).is_empty());
+    }
+
+    fn shifted(shift: ContextShift, tokens: &[u32]) -> anyhow::Result<Vec<u32>> {
+        let mut tokens = tokens.to_vec();
+        shift.apply(&mut tokens)?;
+        Ok(tokens)
+    }
+
+    #[test]
+    fn context_shift_keeps_the_prefix_and_drops_the_oldest_tokens_after_it() {
+        let window: Vec<u32> = (0..10).collect();
+        let half = ContextShift {
+            preserve_prefix: 2,
+            discard: None,
+        };
+        assert_eq!(shifted(half, &window).unwrap(), [0, 1, 6, 7, 8, 9]);
+        let three = ContextShift {
+            preserve_prefix: 2,
+            discard: Some(3),
+        };
+        assert_eq!(shifted(three, &window).unwrap(), [0, 1, 5, 6, 7, 8, 9]);
+        // At least one token is kept after the prefix, so the window still continues
+        let all = ContextShift {
+            preserve_prefix: 7,
+            discard: Some(8),
+        };
+        assert_eq!(shifted(all, &window).unwrap(), [0, 1, 2, 3, 4, 5, 6, 9]);
+        let full = ContextShift {
+            preserve_prefix: 9,
+            discard: None,
+        };
+        assert!(shifted(full, &window).is_err());
+    }
+
+    #[test]
+    fn context_shift_settings_must_free_space_in_the_window() {
+        let shift = |preserve_prefix, discard| ContextShift {
+            preserve_prefix,
+            discard,
+        };
+        assert!(shift(0, None).validate(2048).is_ok());
+        assert!(shift(1024, Some(1023)).validate(2048).is_ok());
+        assert!(shift(1025, None).validate(2048).is_err());
+        assert!(shift(1024, Some(1024)).validate(2048).is_err());
+        assert!(shift(0, Some(0)).validate(2048).is_err());
+    }
 }
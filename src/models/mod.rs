@@ -1,2 +1,7 @@
 pub mod bert;
+pub mod candidates;
+pub mod kv_snapshot;
 pub mod llama;
+pub mod llama_model;
+pub mod prefix_cache;
+pub mod speculative;
@@ -0,0 +1,259 @@
+/*
+Prompt prefix KV-cache shared across requests.
+
+After a prompt has been prefilled, its per-layer key/value states are stored together with the
+prompt token ids. A new sequence looks up the entry sharing the longest token prefix with its
+prompt and starts decoding from those states, so only the remaining tokens have to be
+prefilled. Entries are evicted least-recently-used first once the stored tensors exceed the
+configured memory cap.
+*/
+use super::llama_model::{Cache, LayerKv};
+
+// Shorter matches are not worth restoring (every prompt shares at least the BOS token)
+const MIN_PREFIX_TOKENS: usize = 16;
+
+struct PrefixEntry {
+    tokens: Vec<u32>,
+    kvs: Vec<Option<LayerKv>>,
+    size_in_bytes: usize,
+    last_used: u64,
+}
+
+pub struct PrefixCache {
+    entries: Vec<PrefixEntry>,
+    capacity_bytes: usize,
+    used_bytes: usize,
+    // Monotonic counter used as the LRU timestamp
+    clock: u64,
+}
+
+impl PrefixCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity_bytes,
+            used_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /*
+    Returns the number of leading `tokens` covered by a cached entry and the KV states for
+    exactly those positions.
+
+    The match is capped at `tokens.len() - 1` so the caller always has at least one token
+    left to feed the model and obtain logits from.
+    */
+    pub fn lookup(&mut self, tokens: &[u32]) -> Option<(usize, Vec<Option<LayerKv>>)> {
+        let max_len = tokens.len().saturating_sub(1);
+        let (index, matched) = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (index, common_prefix_len(&entry.tokens, tokens).min(max_len)))
+            .max_by_key(|&(_, matched)| matched)?;
+        if matched < MIN_PREFIX_TOKENS {
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = &mut self.entries[index];
+        entry.last_used = self.clock;
+        let kvs = entry
+            .kvs
+            .iter()
+            .map(|kv| match kv {
+                Some((k, v)) => Ok(Some((k.narrow(2, 0, matched)?, v.narrow(2, 0, matched)?))),
+                None => Ok(None),
+            })
+            .collect::<candle::Result<Vec<_>>>()
+            .ok()?;
+        Some((matched, kvs))
+    }
+
+    // Store the KV states of `tokens`, which must be the first positions held by `cache`
+    pub fn insert(&mut self, tokens: &[u32], cache: &Cache) -> anyhow::Result<()> {
+        if tokens.len() < MIN_PREFIX_TOKENS || cache.seq_len() < tokens.len() {
+            return Ok(());
+        }
+        // An existing entry already covers this prefix
+        if self
+            .entries
+            .iter()
+            .any(|entry| entry.tokens.starts_with(tokens))
+        {
+            return Ok(());
+        }
+
+        let mut stored = cache.clone();
+        stored.truncate(tokens.len())?;
+        let size_in_bytes = stored.size_in_bytes();
+        if size_in_bytes > self.capacity_bytes {
+            return Ok(());
+        }
+
+        // Entries that are a prefix of the new one are superseded by it
+        self.remove_where(|entry| tokens.starts_with(&entry.tokens));
+        while self.used_bytes + size_in_bytes > self.capacity_bytes {
+            self.evict_least_recently_used();
+        }
+
+        self.clock += 1;
+        self.used_bytes += size_in_bytes;
+        self.entries.push(PrefixEntry {
+            tokens: tokens.to_vec(),
+            kvs: stored.kvs().to_vec(),
+            size_in_bytes,
+            last_used: self.clock,
+        });
+        Ok(())
+    }
+
+    // Memory currently held by cached KV states
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    fn remove_where(&mut self, predicate: impl Fn(&PrefixEntry) -> bool) {
+        let mut freed = 0;
+        self.entries.retain(|entry| {
+            let remove = predicate(entry);
+            if remove {
+                freed += entry.size_in_bytes;
+            }
+            !remove
+        });
+        self.used_bytes -= freed;
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some(index) = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(index, _)| index)
+        {
+            let entry = self.entries.swap_remove(index);
+            self.used_bytes -= entry.size_in_bytes;
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::{DType, Device, Tensor};
+    use candle_transformers::models::llama::Config;
+
+    const LAYERS: usize = 2;
+    // Two layers of one key and one value tensor holding two f32 values per position
+    const BYTES_PER_TOKEN: usize = LAYERS * 2 * 2 * 4;
+
+    // A cache holding `tokens.len()` positions, the value of each being its token id
+    fn filled_cache(tokens: &[u32]) -> Cache {
+        let config = Config {
+            num_hidden_layers: LAYERS,
+            max_position_embeddings: 64,
+            ..Config::config_7b_v1(false)
+        };
+        let mut cache = Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        let values: Vec<f32> = tokens.iter().flat_map(|&t| [t as f32; 2]).collect();
+        let kv = Tensor::from_vec(values, (1, 1, tokens.len(), 2), &Device::Cpu).unwrap();
+        cache.set_kvs(vec![Some((kv.clone(), kv)); LAYERS]).unwrap();
+        cache
+    }
+
+    fn prompt(first: u32, len: usize) -> Vec<u32> {
+        (first..first + len as u32).collect()
+    }
+
+    fn insert(prefix_cache: &mut PrefixCache, tokens: &[u32]) {
+        prefix_cache.insert(tokens, &filled_cache(tokens)).unwrap();
+    }
+
+    #[test]
+    fn lookup_restores_the_longest_shared_prefix() {
+        let mut prefix_cache = PrefixCache::new(usize::MAX);
+        let stored = prompt(0, 24);
+        insert(&mut prefix_cache, &stored);
+        assert_eq!(prefix_cache.used_bytes(), 24 * BYTES_PER_TOKEN);
+
+        // Diverges after 20 tokens
+        let mut tokens = prompt(0, 20);
+        tokens.extend([900, 901]);
+        let (matched, kvs) = prefix_cache.lookup(&tokens).unwrap();
+        assert_eq!(matched, 20);
+        let (k, _) = kvs[0].as_ref().unwrap();
+        assert_eq!(k.dims(), &[1, 1, 20, 2]);
+        assert_eq!(k.flatten_all().unwrap().to_vec1::<f32>().unwrap()[38], 19.0);
+
+        // The whole prompt is cached, but one token is left to compute logits from
+        assert_eq!(prefix_cache.lookup(&stored).unwrap().0, 23);
+
+        // Too short a match
+        let mut tokens = prompt(0, MIN_PREFIX_TOKENS - 1);
+        tokens.push(900);
+        assert!(prefix_cache.lookup(&tokens).is_none());
+    }
+
+    #[test]
+    fn insert_skips_prefixes_already_covered_and_replaces_shorter_ones() {
+        let mut prefix_cache = PrefixCache::new(usize::MAX);
+        insert(&mut prefix_cache, &prompt(0, 20));
+        insert(&mut prefix_cache, &prompt(0, 30));
+        assert_eq!(prefix_cache.entries.len(), 1);
+        assert_eq!(prefix_cache.used_bytes(), 30 * BYTES_PER_TOKEN);
+
+        insert(&mut prefix_cache, &prompt(0, 25));
+        assert_eq!(prefix_cache.entries.len(), 1);
+
+        // Prompts shorter than the minimum are not worth storing
+        insert(&mut prefix_cache, &prompt(100, MIN_PREFIX_TOKENS - 1));
+        assert_eq!(prefix_cache.entries.len(), 1);
+
+        // Only the positions of the prompt are stored, not everything in the cache
+        let tokens = prompt(200, 20);
+        let mut longer = tokens.clone();
+        longer.extend(prompt(300, 10));
+        prefix_cache
+            .insert(&tokens, &filled_cache(&longer))
+            .unwrap();
+        assert_eq!(prefix_cache.used_bytes(), 50 * BYTES_PER_TOKEN);
+    }
+
+    #[test]
+    fn eviction_drops_the_least_recently_used_entries_to_stay_under_the_cap() {
+        let mut prefix_cache = PrefixCache::new(2 * 20 * BYTES_PER_TOKEN);
+        let (a, b, c) = (prompt(0, 20), prompt(100, 20), prompt(200, 20));
+        insert(&mut prefix_cache, &a);
+        insert(&mut prefix_cache, &b);
+        // Using `a` makes `b` the least recently used entry
+        assert!(prefix_cache.lookup(&a).is_some());
+        insert(&mut prefix_cache, &c);
+
+        assert_eq!(prefix_cache.used_bytes(), 2 * 20 * BYTES_PER_TOKEN);
+        assert!(prefix_cache.lookup(&b).is_none());
+        assert!(prefix_cache.lookup(&a).is_some());
+        assert!(prefix_cache.lookup(&c).is_some());
+
+        // A larger entry evicts as many entries as it needs
+        insert(&mut prefix_cache, &prompt(300, 40));
+        assert_eq!(prefix_cache.entries.len(), 1);
+        assert_eq!(prefix_cache.used_bytes(), 40 * BYTES_PER_TOKEN);
+    }
+
+    #[test]
+    fn entries_larger_than_the_cap_are_not_stored() {
+        let mut prefix_cache = PrefixCache::new(20 * BYTES_PER_TOKEN);
+        insert(&mut prefix_cache, &prompt(0, 20));
+        insert(&mut prefix_cache, &prompt(100, 21));
+        assert_eq!(prefix_cache.used_bytes(), 20 * BYTES_PER_TOKEN);
+        assert!(prefix_cache.lookup(&prompt(0, 20)).is_some());
+        assert!(prefix_cache.lookup(&prompt(100, 21)).is_none());
+    }
+}
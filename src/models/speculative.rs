@@ -0,0 +1,554 @@
+/*
+Speculative decoding: cheap proposals verified by the target model in a single forward pass.
+
+Proposals come either from a small draft model, which proposes up to `num_speculative_tokens`
+tokens autoregressively, or from prompt lookup, which copies the tokens that followed an
+earlier occurrence of the window's last n-gram (retrieved passages the model quotes verbatim).
+
+The target model scores all proposals at once and they are accepted with the standard
+rejection rule (accept `x` with probability `min(1, p(x) / q(x))`, otherwise resample from the
+normalized residual `max(0, p - q)`), so the generated tokens follow exactly the target model's
+distribution. Prompt-lookup proposals are deterministic, i.e. `q` is one-hot. When every
+proposal is accepted the target's distribution after the last one yields a bonus token. With
+greedy decoding this reduces to keeping the proposals that match the target's argmax.
+*/
+use super::llama::{GenerationState, LlamaInferenceModel};
+use super::llama_model as model;
+use candle::Tensor;
+use candle_transformers::models::llama::Config;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Longest n-gram prompt lookup tries to match; shorter ones are tried when it finds nothing
+const PROMPT_LOOKUP_MAX_NGRAM: usize = 3;
+// Tokens copied from the matched continuation per step
+const PROMPT_LOOKUP_NUM_TOKENS: usize = 8;
+
+/// Where a sequence takes its speculative proposals from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeculativeMode {
+    /// The draft model configured for the target, if any.
+    #[default]
+    Draft,
+    /// Continuations of n-grams that already occur in the context window.
+    PromptLookup,
+    /// Plain one-token-per-step decoding.
+    Off,
+}
+
+// Acceptance counters accumulated over every speculative step since startup
+#[derive(Default)]
+pub struct AcceptanceCounters {
+    steps: AtomicU64,
+    proposed_tokens: AtomicU64,
+    accepted_tokens: AtomicU64,
+}
+
+/// Acceptance metrics of one proposal source.
+#[derive(Debug, Clone, Serialize)]
+pub struct AcceptanceStats {
+    pub steps: u64,
+    pub proposed_tokens: u64,
+    pub accepted_tokens: u64,
+    pub acceptance_rate: f64,
+    /// Average number of tokens emitted per target forward pass.
+    pub tokens_per_step: f64,
+}
+
+/// Acceptance metrics of the draft model.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpeculativeStats {
+    pub draft_model_id: String,
+    pub num_speculative_tokens: usize,
+    #[serde(flatten)]
+    pub acceptance: AcceptanceStats,
+}
+
+/// Acceptance metrics of every proposal source.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpeculativeMetrics {
+    /// `None` when no draft model is configured.
+    pub draft: Option<SpeculativeStats>,
+    pub prompt_lookup: AcceptanceStats,
+}
+
+pub struct SpeculativeDecoder {
+    pub draft: Arc<LlamaInferenceModel>,
+    pub num_speculative_tokens: usize,
+    counters: AcceptanceCounters,
+}
+
+// Draft-free proposals copied from the context window
+#[derive(Default)]
+pub struct PromptLookup {
+    counters: AcceptanceCounters,
+}
+
+// Draft-model decoding state kept alongside a sequence's `GenerationState`
+pub struct DraftState {
+    cache: model::Cache,
+    index_pos: usize,
+}
+
+// Next-token distribution: greedy decoding keeps only the argmax
+enum TokenDistribution {
+    Greedy(u32),
+    Sampled(Vec<f32>),
+}
+
+impl TokenDistribution {
+    fn from_logits(logits: &Tensor, temperature: Option<f64>) -> anyhow::Result<Self> {
+        let logits = logits.to_vec1::<f32>()?;
+        match temperature {
+            Some(temperature) => Ok(Self::Sampled(softmax(&logits, temperature))),
+            None => Ok(Self::Greedy(argmax(&logits))),
+        }
+    }
+
+    fn probability(&self, token: u32) -> f32 {
+        match self {
+            Self::Greedy(argmax) => f32::from(u8::from(*argmax == token)),
+            Self::Sampled(probs) => probs.get(token as usize).copied().unwrap_or(0.0),
+        }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> anyhow::Result<u32> {
+        match self {
+            Self::Greedy(argmax) => Ok(*argmax),
+            Self::Sampled(probs) => Ok(WeightedIndex::new(probs)?.sample(rng) as u32),
+        }
+    }
+}
+
+impl AcceptanceCounters {
+    fn record(&self, proposed_tokens: usize, accepted_tokens: usize) {
+        self.steps.fetch_add(1, Ordering::Relaxed);
+        self.proposed_tokens
+            .fetch_add(proposed_tokens as u64, Ordering::Relaxed);
+        self.accepted_tokens
+            .fetch_add(accepted_tokens as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> AcceptanceStats {
+        let steps = self.steps.load(Ordering::Relaxed);
+        let proposed_tokens = self.proposed_tokens.load(Ordering::Relaxed);
+        let accepted_tokens = self.accepted_tokens.load(Ordering::Relaxed);
+        AcceptanceStats {
+            steps,
+            proposed_tokens,
+            accepted_tokens,
+            acceptance_rate: ratio(accepted_tokens, proposed_tokens),
+            // Every step emits the accepted proposals plus one target-sampled token
+            tokens_per_step: ratio(accepted_tokens + steps, steps),
+        }
+    }
+}
+
+impl SpeculativeDecoder {
+    pub fn new(
+        draft: Arc<LlamaInferenceModel>,
+        num_speculative_tokens: usize,
+        target_config: &Config,
+    ) -> anyhow::Result<Self> {
+        if draft.config.vocab_size != target_config.vocab_size {
+            anyhow::bail!(
+                "draft model {} has a vocabulary of {} tokens, the target has {}",
+                draft.model_id,
+                draft.config.vocab_size,
+                target_config.vocab_size
+            );
+        }
+        if num_speculative_tokens == 0 {
+            anyhow::bail!("num_speculative_tokens must be at least 1");
+        }
+        Ok(Self {
+            draft,
+            num_speculative_tokens,
+            counters: AcceptanceCounters::default(),
+        })
+    }
+
+    pub fn stats(&self) -> SpeculativeStats {
+        SpeculativeStats {
+            draft_model_id: self.draft.model_id.clone(),
+            num_speculative_tokens: self.num_speculative_tokens,
+            acceptance: self.counters.stats(),
+        }
+    }
+
+    // Largest number of proposals that fits both context windows after `window_len` tokens
+    pub(super) fn max_proposals(&self, window_len: usize, target_context_length: usize) -> usize {
+        let context_length = target_context_length.min(self.draft.context_length());
+        max_proposals(self.num_speculative_tokens, window_len, context_length)
+    }
+
+    /*
+    Runs one speculative step on `state`, appending between 1 and `num_proposals + 1` tokens
+    and returning them.
+
+    The target cache ends up holding every appended token except the last one (as after a
+    regular step), and the draft cache is rolled back to the accepted prefix.
+    */
+    pub(super) fn step(
+        &self,
+        target: &LlamaInferenceModel,
+        state: &mut GenerationState,
+        num_proposals: usize,
+    ) -> anyhow::Result<Vec<u32>> {
+        let mut draft = match state.draft.take() {
+            Some(draft) => draft,
+            None => DraftState {
+                cache: self.draft.create_cache()?,
+                index_pos: 0,
+            },
+        };
+
+        // Draft: catch up on the window, then propose tokens one at a time
+        let mut proposals = Vec::with_capacity(num_proposals);
+        let mut draft_distributions = Vec::with_capacity(num_proposals);
+        let mut draft_input = state.tokens[draft.index_pos..].to_vec();
+        for _ in 0..num_proposals {
+            let input = Tensor::new(draft_input.as_slice(), &self.draft.device)?.unsqueeze(0)?;
+            let logits = self
+                .draft
+                .model
+                .forward(&input, draft.index_pos, &mut draft.cache)?
+                .squeeze(0)?;
+            draft.index_pos += draft_input.len();
+            let distribution = TokenDistribution::from_logits(&logits, state.temperature)?;
+            let token = distribution.sample(&mut state.rng)?;
+            proposals.push(token);
+            draft_distributions.push(distribution);
+            draft_input = vec![token];
+        }
+
+        let (accepted, num_accepted) = verify(target, state, &proposals, &draft_distributions)?;
+        self.counters.record(num_proposals, num_accepted);
+
+        // Roll the draft cache back to the positions the target kept
+        draft.index_pos = draft.index_pos.min(state.index_pos);
+        draft.cache.truncate(draft.index_pos)?;
+        state.draft = Some(draft);
+        Ok(accepted)
+    }
+}
+
+impl PromptLookup {
+    pub fn stats(&self) -> AcceptanceStats {
+        self.counters.stats()
+    }
+
+    /*
+    Runs one prompt-lookup step on `state`, appending the accepted part of the continuation
+    copied from the window plus one token from the target model.
+
+    Returns `None`, leaving `state` untouched, when the window's last n-gram has no earlier
+    occurrence or there is no room for proposals.
+    */
+    pub(super) fn step(
+        &self,
+        target: &LlamaInferenceModel,
+        state: &mut GenerationState,
+        max_new_tokens: usize,
+    ) -> anyhow::Result<Option<Vec<u32>>> {
+        let num_proposals = max_proposals(
+            PROMPT_LOOKUP_NUM_TOKENS,
+            state.tokens.len(),
+            target.context_length(),
+        )
+        .min(max_new_tokens.saturating_sub(1));
+        let proposals = lookup_continuation(&state.tokens, PROMPT_LOOKUP_MAX_NGRAM, num_proposals);
+        if proposals.is_empty() {
+            return Ok(None);
+        }
+
+        let distributions: Vec<_> = proposals
+            .iter()
+            .map(|&token| TokenDistribution::Greedy(token))
+            .collect();
+        let (accepted, num_accepted) = verify(target, state, &proposals, &distributions)?;
+        self.counters.record(proposals.len(), num_accepted);
+        Ok(Some(accepted))
+    }
+}
+
+/*
+Scores `proposals` with the target model and appends the accepted prefix plus one token
+sampled from the target to `state`, returning the appended tokens and how many proposals were
+accepted. `draft_distributions[i]` is the distribution proposal `i` was drawn from.
+*/
+fn verify(
+    target: &LlamaInferenceModel,
+    state: &mut GenerationState,
+    proposals: &[u32],
+    draft_distributions: &[TokenDistribution],
+) -> anyhow::Result<(Vec<u32>, usize)> {
+    let window_len = state.tokens.len();
+    let temperature = state.temperature;
+
+    // Target: score the pending tokens and every proposal in one pass
+    let mut target_input = state.tokens[state.index_pos..].to_vec();
+    target_input.extend_from_slice(proposals);
+    let input = Tensor::new(target_input.as_slice(), &target.device)?.unsqueeze(0)?;
+    let logits = target
+        .model
+        .forward_all(&input, state.index_pos, &mut state.cache)?
+        .squeeze(0)?;
+    let first_row = target_input.len() - proposals.len() - 1;
+    let target_distribution = |offset: usize| -> anyhow::Result<TokenDistribution> {
+        TokenDistribution::from_logits(&logits.get(first_row + offset)?, temperature)
+    };
+    state.offer_prompt_to_prefix_cache(target)?;
+
+    // Verify proposals left to right
+    let (mut accepted, num_accepted) = accept_proposals(
+        proposals,
+        draft_distributions,
+        target_distribution,
+        &mut state.rng,
+    )?;
+    state.draft_tokens += proposals.len();
+    state.accepted_draft_tokens += num_accepted;
+
+    // Nothing after an accepted EOS belongs to the sequence
+    if let Some(eos_token_id) = target.eos_token_id() {
+        if let Some(position) = accepted.iter().position(|&token| token == eos_token_id) {
+            accepted.truncate(position + 1);
+        }
+    }
+
+    // Keep only positions of the window plus the kept proposals in the cache
+    let valid = window_len + accepted.len() - 1;
+    state.cache.truncate(valid)?;
+    state.index_pos = valid;
+    state.tokens.extend_from_slice(&accepted);
+    Ok((accepted, num_accepted))
+}
+
+/*
+Rejection sampling over `proposals`, left to right: returns the accepted prefix followed by
+the token replacing the first rejected proposal (or a bonus token when all were accepted), and
+how many proposals were accepted. `target_distribution(i)` is the target's distribution at
+proposal `i`.
+*/
+fn accept_proposals(
+    proposals: &[u32],
+    draft_distributions: &[TokenDistribution],
+    target_distribution: impl Fn(usize) -> anyhow::Result<TokenDistribution>,
+    rng: &mut StdRng,
+) -> anyhow::Result<(Vec<u32>, usize)> {
+    let mut accepted = Vec::with_capacity(proposals.len() + 1);
+    let mut final_token = None;
+    for (offset, (&token, q)) in proposals.iter().zip(draft_distributions).enumerate() {
+        let p = target_distribution(offset)?;
+        let (p_token, q_token) = (p.probability(token), q.probability(token));
+        let accept = match &p {
+            TokenDistribution::Greedy(argmax) => *argmax == token,
+            TokenDistribution::Sampled(_) => {
+                q_token > 0.0 && rng.gen::<f32>() < (p_token / q_token).min(1.0)
+            }
+        };
+        if accept {
+            accepted.push(token);
+            continue;
+        }
+        final_token = Some(match &p {
+            TokenDistribution::Sampled(p) => sample_residual(p, q, rng)?,
+            TokenDistribution::Greedy(argmax) => *argmax,
+        });
+        break;
+    }
+    let num_accepted = accepted.len();
+    let final_token = match final_token {
+        Some(token) => token,
+        // Every proposal was accepted: sample a bonus token after the last one
+        None => target_distribution(proposals.len())?.sample(rng)?,
+    };
+    accepted.push(final_token);
+    Ok((accepted, num_accepted))
+}
+
+// Proposals that fit the context window after `window_len` tokens, leaving room for the
+// target-sampled token
+fn max_proposals(num_speculative_tokens: usize, window_len: usize, context_length: usize) -> usize {
+    num_speculative_tokens.min(context_length.saturating_sub(window_len + 1))
+}
+
+/*
+Finds the most recent earlier occurrence of the last `n` tokens of `tokens`, trying
+`max_ngram` down to 1, and returns up to `limit` tokens that followed it.
+*/
+fn lookup_continuation(tokens: &[u32], max_ngram: usize, limit: usize) -> Vec<u32> {
+    if limit == 0 {
+        return Vec::new();
+    }
+    for n in (1..=max_ngram.min(tokens.len().saturating_sub(1))).rev() {
+        let ngram = &tokens[tokens.len() - n..];
+        let found = (0..tokens.len() - n)
+            .rev()
+            .find(|&start| &tokens[start..start + n] == ngram);
+        if let Some(start) = found {
+            let continuation = &tokens[start + n..];
+            return continuation[..continuation.len().min(limit)].to_vec();
+        }
+    }
+    Vec::new()
+}
+
+// Sample from max(0, p - q), falling back to p when the distributions coincide
+fn sample_residual(p: &[f32], q: &TokenDistribution, rng: &mut StdRng) -> anyhow::Result<u32> {
+    let residual: Vec<f32> = p
+        .iter()
+        .enumerate()
+        .map(|(token, p)| (p - q.probability(token as u32)).max(0.0))
+        .collect();
+    let weights = match residual.iter().sum::<f32>() > 0.0 {
+        true => &residual[..],
+        false => p,
+    };
+    Ok(WeightedIndex::new(weights)?.sample(rng) as u32)
+}
+
+fn softmax(logits: &[f32], temperature: f64) -> Vec<f32> {
+    let temperature = temperature as f32;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits
+        .iter()
+        .map(|logit| ((logit - max) / temperature).exp())
+        .collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+fn argmax(logits: &[f32]) -> u32 {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index as u32)
+}
+
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    match denominator {
+        0 => 0.0,
+        _ => numerator as f64 / denominator as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn greedy(tokens: &[u32]) -> Vec<TokenDistribution> {
+        tokens
+            .iter()
+            .map(|&t| TokenDistribution::Greedy(t))
+            .collect()
+    }
+
+    #[test]
+    fn greedy_verification_keeps_proposals_matching_the_target() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let target = |offset: usize| Ok(TokenDistribution::Greedy([5, 6, 7, 8][offset]));
+
+        // The first mismatch is replaced by the target's token
+        let proposals = [5, 6, 9];
+        let result = accept_proposals(&proposals, &greedy(&proposals), target, &mut rng).unwrap();
+        assert_eq!(result, (vec![5, 6, 7], 2));
+
+        // Every proposal accepted: the target adds a bonus token
+        let proposals = [5, 6, 7];
+        let result = accept_proposals(&proposals, &greedy(&proposals), target, &mut rng).unwrap();
+        assert_eq!(result, (vec![5, 6, 7, 8], 3));
+
+        let result = accept_proposals(&[4], &greedy(&[4]), target, &mut rng).unwrap();
+        assert_eq!(result, (vec![5], 0));
+    }
+
+    #[test]
+    fn sampled_verification_follows_the_target_distribution() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let p = [0.6, 0.3, 0.1];
+        let q = [0.2, 0.5, 0.3];
+        let trials = 20_000;
+        let mut counts = [0usize; 3];
+        for _ in 0..trials {
+            let draft = TokenDistribution::Sampled(q.to_vec());
+            let proposal = draft.sample(&mut rng).unwrap();
+            let target = |_| Ok(TokenDistribution::Sampled(p.to_vec()));
+            let (accepted, _) = accept_proposals(&[proposal], &[draft], target, &mut rng).unwrap();
+            counts[accepted[0] as usize] += 1;
+        }
+        for (count, p) in counts.iter().zip(p) {
+            let frequency = *count as f32 / trials as f32;
+            assert!((frequency - p).abs() < 0.02, "{counts:?}");
+        }
+
+        // A deterministic proposal the target never produces is always rejected
+        let target = |_| Ok(TokenDistribution::Sampled(vec![0.5, 0.5, 0.0]));
+        for _ in 0..100 {
+            let (accepted, num_accepted) =
+                accept_proposals(&[2], &greedy(&[2]), target, &mut rng).unwrap();
+            assert_eq!(num_accepted, 0);
+            assert_ne!(accepted[0], 2);
+        }
+    }
+
+    #[test]
+    fn residual_sampling_only_picks_tokens_the_draft_underweights() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let p = [0.5, 0.2, 0.3];
+        let q = TokenDistribution::Sampled(vec![0.2, 0.5, 0.3]);
+        for _ in 0..100 {
+            assert_eq!(sample_residual(&p, &q, &mut rng).unwrap(), 0);
+        }
+        // A one-hot draft removes its token from the residual
+        let q = TokenDistribution::Greedy(0);
+        for _ in 0..100 {
+            assert_ne!(sample_residual(&p, &q, &mut rng).unwrap(), 0);
+        }
+        // Identical distributions leave no residual: fall back to the target
+        let q = TokenDistribution::Sampled(p.to_vec());
+        let mut seen = [false; 3];
+        for _ in 0..200 {
+            seen[sample_residual(&p, &q, &mut rng).unwrap() as usize] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+
+    #[test]
+    fn prompt_lookup_copies_what_followed_the_latest_ngram() {
+        // "3 4" occurs twice earlier; the most recent occurrence wins
+        let tokens = [1, 3, 4, 5, 6, 3, 4, 7, 8, 9, 3, 4];
+        assert_eq!(lookup_continuation(&tokens, 3, 8), vec![7, 8, 9, 3, 4]);
+        assert_eq!(lookup_continuation(&tokens, 3, 2), vec![7, 8]);
+        assert_eq!(lookup_continuation(&tokens, 3, 0), Vec::<u32>::new());
+
+        // The longest matching n-gram is preferred over a more recent shorter one
+        let tokens = [1, 2, 3, 10, 9, 3, 11, 1, 2, 3];
+        assert_eq!(lookup_continuation(&tokens, 3, 1), vec![10]);
+        assert_eq!(lookup_continuation(&tokens, 1, 1), vec![11]);
+
+        // Falls back to shorter n-grams
+        let tokens = [5, 6, 7, 8, 6];
+        assert_eq!(lookup_continuation(&tokens, 3, 8), vec![7, 8, 6]);
+
+        assert!(lookup_continuation(&[1, 2, 3], 3, 8).is_empty());
+        assert!(lookup_continuation(&[1], 3, 8).is_empty());
+        assert!(lookup_continuation(&[], 3, 8).is_empty());
+    }
+
+    #[test]
+    fn proposals_leave_room_for_the_target_token() {
+        assert_eq!(max_proposals(4, 10, 2048), 4);
+        assert_eq!(max_proposals(4, 2045, 2048), 2);
+        assert_eq!(max_proposals(4, 2047, 2048), 0);
+        assert_eq!(max_proposals(4, 2048, 2048), 0);
+    }
+}
@@ -0,0 +1,54 @@
+// Retrieval-augmented generation: grounded prompts over retrieved passages and their citations
+use crate::chat::{ChatMessage, ChatTemplate, Role};
+use serde::Serialize;
+
+const SYSTEM_PROMPT: &str = "Answer the question using only the numbered passages. Cite the \
+                             passages you use by their number in square brackets, like [1] or \
+                             [2][3]. If the passages do not answer the question, say so.";
+
+/// A retrieved passage, numbered from 1 by rank.
+#[derive(Debug, Clone, Serialize)]
+pub struct Passage {
+    pub number: usize,
+    pub item: String,
+    pub score: f32,
+}
+
+// Chat prompt asking the question over `passages`
+pub fn build_prompt(question: &str, passages: &[Passage]) -> String {
+    let context = passages
+        .iter()
+        .map(|passage| format!("[{}] {}", passage.number, passage.item))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let messages = [
+        ChatMessage::new(Role::System, SYSTEM_PROMPT),
+        ChatMessage::new(
+            Role::User,
+            format!("Passages:\n{}\n\nQuestion: {}", context, question),
+        ),
+    ];
+    ChatTemplate::render(&messages, true)
+}
+
+// Passages cited in `answer` as `[n]` or `[n, m]`, in order of first citation
+pub fn cited_passages<'a>(answer: &str, passages: &'a [Passage]) -> Vec<&'a Passage> {
+    let mut cited: Vec<&Passage> = Vec::new();
+    for (start, _) in answer.match_indices('[') {
+        let Some(end) = answer[start..].find(']') else {
+            break;
+        };
+        for number in answer[start + 1..start + end].split(',') {
+            let Ok(number) = number.trim().parse::<usize>() else {
+                continue;
+            };
+            let passage = passages.iter().find(|passage| passage.number == number);
+            if let Some(passage) = passage {
+                if !cited.iter().any(|cited| cited.number == number) {
+                    cited.push(passage);
+                }
+            }
+        }
+    }
+    cited
+}
@@ -0,0 +1,187 @@
+/*
+BM25 ranking of chunks by the query terms they contain.
+
+Text is lowercased and split into runs of letters and digits, so tickers and names like "MMTC"
+match exactly, whatever the embedding model makes of them. Rows can be added and removed as
+documents change: a removed row stops counting in the term statistics, but keeps its postings,
+so searches skip it through `allowed` like the vector indexes do.
+*/
+use super::top_k;
+use bincode::{Decode, Encode};
+use std::collections::{HashMap, HashSet};
+
+// Term frequency saturation and document length normalization, the usual defaults
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+#[derive(Debug, Clone, Default, Encode, Decode)]
+pub struct Bm25Index {
+    terms: HashMap<String, Term>,
+    // Number of terms in each row
+    lengths: Vec<u32>,
+    live_rows: u64,
+    live_length: u64,
+}
+
+// Rows containing a term, with its count in each, and how many of them are not removed
+#[derive(Debug, Clone, Default, Encode, Decode)]
+struct Term {
+    postings: Vec<(u32, u32)>,
+    live: u32,
+}
+
+// Count of each term of `text`
+fn term_counts(text: &str) -> HashMap<String, u32> {
+    let mut counts = HashMap::new();
+    for term in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+    {
+        *counts.entry(term.to_lowercase()).or_insert(0) += 1;
+    }
+    counts
+}
+
+impl Bm25Index {
+    // An index with one row per text
+    pub fn build<'a>(texts: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut index = Self::default();
+        for text in texts {
+            index.push(text);
+        }
+        index
+    }
+
+    // Rows, including removed ones
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    // Adds `text` as the next row
+    pub fn push(&mut self, text: &str) {
+        let row = self.lengths.len() as u32;
+        let counts = term_counts(text);
+        let length: u32 = counts.values().sum();
+        for (term, count) in counts {
+            let entry = self.terms.entry(term).or_default();
+            entry.postings.push((row, count));
+            entry.live += 1;
+        }
+        self.lengths.push(length);
+        self.live_rows += 1;
+        self.live_length += length as u64;
+    }
+
+    // Stops counting `row`, which was added for `text`, in the term statistics
+    pub fn remove(&mut self, row: usize, text: &str) {
+        for term in term_counts(text).keys() {
+            if let Some(entry) = self.terms.get_mut(term) {
+                entry.live = entry.live.saturating_sub(1);
+            }
+        }
+        self.live_rows = self.live_rows.saturating_sub(1);
+        self.live_length = self.live_length.saturating_sub(self.lengths[row] as u64);
+    }
+
+    /*
+    The `k` rows ranking highest for the terms of `query`, as (row, BM25 score) pairs, best
+    first; only rows flagged in `allowed`, when given. Rows without any query term are not
+    returned.
+    */
+    pub fn search(&self, query: &str, k: usize, allowed: Option<&[bool]>) -> Vec<(usize, f32)> {
+        if self.live_rows == 0 {
+            return Vec::new();
+        }
+        let rows = self.live_rows as f32;
+        let average_length = (self.live_length as f32 / rows).max(1.0);
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        let query_terms: HashSet<String> = term_counts(query).into_keys().collect();
+        for term in &query_terms {
+            let Some(entry) = self.terms.get(term) else {
+                continue;
+            };
+            let live = entry.live as f32;
+            let idf = (1.0 + (rows - live + 0.5) / (live + 0.5)).ln();
+            for &(row, count) in &entry.postings {
+                let row = row as usize;
+                if allowed.is_some_and(|allowed| !allowed[row]) {
+                    continue;
+                }
+                let count = count as f32;
+                let length = self.lengths[row] as f32;
+                let saturation =
+                    count * (K1 + 1.0) / (count + K1 * (1.0 - B + B * length / average_length));
+                *scores.entry(row).or_insert(0.0) += idf * saturation;
+            }
+        }
+        top_k(scores, k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADLINES: &[&str] = &[
+        "Sensex rises as metal stocks gain",
+        "MMTC Q2 net loss at Rs 10.4 crore",
+        "Hudco bonds open for subscription",
+        "Metal and mining stocks lead the market higher",
+    ];
+
+    fn rows(results: &[(usize, f32)]) -> Vec<usize> {
+        results.iter().map(|&(row, _)| row).collect()
+    }
+
+    #[test]
+    fn ranks_rows_with_the_exact_term_first() {
+        let index = Bm25Index::build(HEADLINES.iter().copied());
+        assert_eq!(rows(&index.search("mmtc results", 10, None)), [1]);
+        assert_eq!(rows(&index.search("HUDCO", 10, None)), [2]);
+        // Rarer terms weigh more: "sensex" decides between the two rows with "metal stocks"
+        assert_eq!(rows(&index.search("sensex metal stocks", 10, None)), [0, 3]);
+        assert!(index.search("gold", 10, None).is_empty());
+        assert!(index.search("", 10, None).is_empty());
+    }
+
+    #[test]
+    fn removed_rows_stop_counting_in_the_term_statistics() {
+        let mut index = Bm25Index::build(HEADLINES.iter().copied());
+        index.remove(3, HEADLINES[3]);
+        index.push("Gold prices steady");
+        let allowed = [true, true, true, false, true];
+
+        // The same scores as an index that never had the removed row
+        let expected = Bm25Index::build([
+            HEADLINES[0],
+            HEADLINES[1],
+            HEADLINES[2],
+            "Gold prices steady",
+        ]);
+        for query in ["metal stocks", "mmtc", "gold market"] {
+            let results = index.search(query, 10, Some(&allowed));
+            let expected = expected.search(query, 10, None);
+            let renumbered: Vec<usize> = rows(&expected)
+                .into_iter()
+                .map(|row| if row == 3 { 4 } else { row })
+                .collect();
+            assert_eq!(rows(&results), renumbered, "{:?}", query);
+            for (&(_, score), &(_, expected)) in results.iter().zip(&expected) {
+                assert!((score - expected).abs() < 1e-6, "{:?}", query);
+            }
+        }
+    }
+
+    #[test]
+    fn only_returns_allowed_rows() {
+        let index = Bm25Index::build(HEADLINES.iter().copied());
+        let allowed = [false, true, true, true];
+        assert_eq!(rows(&index.search("metal stocks", 10, Some(&allowed))), [3]);
+        assert!(index.search("sensex", 10, Some(&allowed)).is_empty());
+        assert_eq!(rows(&index.search("metal stocks", 1, None)), [0]);
+    }
+}
@@ -0,0 +1,872 @@
+/*
+The documents served by similarity search: those of an on-disk index plus the changes made since.
+
+Documents are upserted and deleted by id. The chunks of new documents are appended after the
+index's own rows, with their embeddings kept in memory and scanned exactly, and added to the
+lexical index; chunks of replaced or deleted documents are removed from searches like rows a
+filter rejects. Every change is written to a log in the index directory before it is applied,
+and replayed when the index is opened.
+
+Compaction writes the current documents as a new index, which replaces the old one together with
+its log, and the documents are then served from it. The new index is built while searches and
+changes continue; changes made meanwhile are carried over into its log.
+*/
+use super::bm25::Bm25Index;
+use super::filter::MetadataFilter;
+use super::hnsw::{HnswIndex, HnswParams};
+use super::ivfpq::{IvfPqIndex, IvfPqParams};
+use super::store::{
+    DocumentMeta, IndexDir, IndexWriter, Manifest, EMBEDDINGS_FILE, EMBEDDINGS_KEY, HNSW_FILE,
+    IVFPQ_FILE,
+};
+use super::wal::{WalRecord, WriteAheadLog};
+use super::{dot, top_k, IndexKind, MappedVectors, SearchParams, VectorIndex};
+use crate::chunking::{Chunk, ChunkStrategy};
+use crate::models::bert::BertInferenceModel;
+use anyhow::{bail, ensure};
+use candle::{DType, Device, Tensor};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const WAL_FILE: &str = "wal.log";
+
+pub struct Corpus {
+    dir: PathBuf,
+    // Approximate index searched over the rows of the on-disk index, when it has one
+    kind: IndexKind,
+    state: RwLock<CorpusState>,
+    // Held while a change is logged and applied, so changes apply in log order
+    log: Mutex<Log>,
+}
+
+struct Log {
+    wal: WriteAheadLog,
+    // Changes logged since compaction copied the documents, while it runs
+    since_snapshot: Option<Vec<WalRecord>>,
+}
+
+pub struct CorpusState {
+    manifest: Manifest,
+    // Texts and metadata by document; replaced and deleted documents keep their slot
+    pub documents: Vec<String>,
+    pub metadata: Vec<DocumentMeta>,
+    // The chunk of a document each row was computed from
+    pub chunks: Vec<Chunk>,
+    // Rows of each document
+    document_rows: Vec<Range<usize>>,
+    // Rows of replaced or deleted documents
+    removed: Vec<bool>,
+    num_removed: usize,
+    // Current document of each id
+    ids: HashMap<String, usize>,
+    // Lexical index of every row's text
+    lexical: Bm25Index,
+    // Rows of the on-disk index, with their embeddings and the approximate index over them if
+    // any; the embeddings of later rows are in `added`
+    index_rows: usize,
+    vectors: MappedVectors,
+    vector_index: Option<VectorIndex>,
+    added: Vec<f32>,
+    dim: usize,
+}
+
+// The current documents, numbered anew, with their chunks and embeddings
+struct Snapshot {
+    manifest: Manifest,
+    documents: Vec<String>,
+    metadata: Vec<DocumentMeta>,
+    chunks: Vec<Chunk>,
+    embeddings: Vec<f32>,
+}
+
+impl Corpus {
+    /*
+    The documents of `index` with the changes of its log applied.
+
+    `kind` is the approximate index searched over the index rows; the embeddings are scanned
+    exactly when it is `Exact` or the index was written without one.
+    */
+    pub fn open(index: &IndexDir, kind: IndexKind) -> anyhow::Result<Self> {
+        let mut state = CorpusState::load(index, kind)?;
+        let (wal, records) = WriteAheadLog::open(index.path.join(WAL_FILE))?;
+        state.replay(records)?;
+        if !wal.is_empty() {
+            println!(
+                "Replayed {} logged changes to index {}",
+                wal.len(),
+                index.path.display()
+            );
+        }
+        Ok(Self {
+            dir: index.path.clone(),
+            kind,
+            state: RwLock::new(state),
+            log: Mutex::new(Log {
+                wal,
+                since_snapshot: None,
+            }),
+        })
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, CorpusState> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, CorpusState> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_log(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Byte ranges of the chunks `text` is embedded in, split like the index's documents
+    pub fn chunk(
+        &self,
+        model: &BertInferenceModel,
+        text: &str,
+    ) -> anyhow::Result<Vec<Range<usize>>> {
+        let chunking = self.read().manifest.chunking;
+        let strategy = chunking.unwrap_or(ChunkStrategy::Paragraph {
+            max_tokens: model.max_chunk_tokens(),
+        });
+        strategy.split(model.tokenizer(), text)
+    }
+
+    /*
+    Adds `document` with `text`, replacing any document with the same id; returns whether the
+    id is new.
+
+    `chunks` are byte ranges of `text` and `embeddings` holds one row per chunk. The change is
+    on disk when this returns.
+    */
+    pub fn upsert(
+        &self,
+        document: DocumentMeta,
+        text: String,
+        chunks: &[Range<usize>],
+        embeddings: Vec<f32>,
+    ) -> anyhow::Result<bool> {
+        let mut log = self.lock_log();
+        // Only writers change the state, and they hold the log
+        let created = !self.read().ids.contains_key(&document.id);
+        let record = WalRecord::Upsert {
+            document,
+            text,
+            chunks: chunks
+                .iter()
+                .map(|range| (range.start, range.end))
+                .collect(),
+            embeddings,
+        };
+        self.read().check(&record)?;
+        log.append(record, &mut self.write())?;
+        Ok(created)
+    }
+
+    // Deletes the document `id`; returns whether there was one
+    pub fn delete(&self, id: &str) -> anyhow::Result<bool> {
+        let mut log = self.lock_log();
+        if !self.read().ids.contains_key(id) {
+            return Ok(false);
+        }
+        let record = WalRecord::Delete { id: id.to_string() };
+        log.append(record, &mut self.write())?;
+        Ok(true)
+    }
+
+    /*
+    Writes the current documents as a new index in place of the old one, with a new log, and
+    serves the documents from it; returns the new manifest, or None when nothing changed since
+    the index was written.
+
+    Approximate indexes are rebuilt when the old index had them. Searches and changes continue
+    while the new index is built; changes only wait while it is moved into place and opened.
+    */
+    pub fn compact(&self) -> anyhow::Result<Option<Manifest>> {
+        let snapshot = {
+            let mut log = self.lock_log();
+            if log.wal.is_empty() || log.since_snapshot.is_some() {
+                return Ok(None);
+            }
+            log.since_snapshot = Some(Vec::new());
+            self.read().snapshot()
+        };
+        let compacted = self.replace_index(snapshot);
+        if compacted.is_err() {
+            self.lock_log().since_snapshot = None;
+        }
+        compacted.map(Some)
+    }
+
+    // Writes `snapshot` as the new index, then opens it with the changes logged since applied
+    fn replace_index(&self, snapshot: Snapshot) -> anyhow::Result<Manifest> {
+        let Snapshot {
+            manifest,
+            documents,
+            metadata,
+            chunks,
+            embeddings,
+        } = snapshot;
+        let dim = manifest.model.dimension;
+        let mut writer = IndexWriter::create(&self.dir)?;
+        writer.write_documents(&documents, &metadata)?;
+        writer.write_chunks(&chunks)?;
+        writer.write_bm25(&Bm25Index::build(
+            chunks
+                .iter()
+                .map(|chunk| chunk.text(&documents).unwrap_or_default()),
+        ))?;
+        writer.write_embeddings(&Tensor::from_slice(
+            &embeddings,
+            (chunks.len(), dim),
+            &Device::Cpu,
+        )?)?;
+        let has = |name: &str| manifest.files.iter().any(|file| file.name == name);
+        if !chunks.is_empty() && has(HNSW_FILE) {
+            writer.write_hnsw(&HnswIndex::build(&embeddings, dim, HnswParams::default())?)?;
+        }
+        if !chunks.is_empty() && has(IVFPQ_FILE) {
+            let params = IvfPqParams::for_size(chunks.len(), dim);
+            writer.write_ivfpq(&IvfPqIndex::build(&embeddings, dim, params)?)?;
+        }
+
+        // Changes made while the index was built go into its log before it replaces the old
+        // one, so they are on disk with either index
+        let mut log = self.lock_log();
+        let changes = log.since_snapshot.take().unwrap_or_default();
+        let (mut wal, _) = WriteAheadLog::open(writer.path().join(WAL_FILE))?;
+        for record in &changes {
+            wal.append(record)?;
+        }
+        let new_manifest = writer.finish(manifest.model, manifest.chunking)?;
+        // The open log moved with its directory
+        log.wal = wal;
+
+        // Until the new index is open, the old state serves the same documents
+        let mut state = CorpusState::load(&IndexDir::open(&self.dir)?, self.kind)?;
+        state.replay(changes)?;
+        *self.write() = state;
+        Ok(new_manifest)
+    }
+}
+
+impl Log {
+    // Logs `record`, then applies it to `state`
+    fn append(&mut self, record: WalRecord, state: &mut CorpusState) -> anyhow::Result<()> {
+        self.wal.append(&record)?;
+        if let Some(changes) = &mut self.since_snapshot {
+            changes.push(record.clone());
+        }
+        state.apply(record);
+        Ok(())
+    }
+}
+
+impl CorpusState {
+    // The documents of `index`, without the changes of its log
+    fn load(index: &IndexDir, kind: IndexKind) -> anyhow::Result<Self> {
+        let documents = index.documents()?;
+        let metadata = index.metadata()?;
+        let chunks = index.chunks(&documents)?;
+        let dim = index.manifest.model.dimension;
+        let vectors = MappedVectors::open(index.path.join(EMBEDDINGS_FILE), EMBEDDINGS_KEY)?;
+        ensure!(
+            vectors.len() == chunks.len() && (vectors.is_empty() || vectors.dim() == dim),
+            "index {} holds {} embeddings of {} dimensions for {} chunks of {}",
+            index.path.display(),
+            vectors.len(),
+            vectors.dim(),
+            chunks.len(),
+            dim
+        );
+        let vector_index = open_vector_index(index, kind)?;
+        if let Some(vector_index) = &vector_index {
+            ensure!(
+                vector_index.len() == chunks.len() && vector_index.dim() == dim,
+                "index {} holds a {:?} index of {} vectors of {} dimensions for {} chunks of {}",
+                index.path.display(),
+                kind,
+                vector_index.len(),
+                vector_index.dim(),
+                chunks.len(),
+                dim
+            );
+        }
+
+        let mut document_rows = vec![0..0; documents.len()];
+        for (row, chunk) in chunks.iter().enumerate() {
+            let rows = &mut document_rows[chunk.document];
+            if rows.start == rows.end {
+                *rows = row..row + 1;
+            } else if rows.end == row {
+                rows.end += 1;
+            } else {
+                bail!(
+                    "index {} has chunks out of document order",
+                    index.path.display()
+                );
+            }
+        }
+        // Indexes written before lexical search have their chunk texts indexed now
+        let lexical = match index.bm25()? {
+            Some(lexical) => lexical,
+            None => Bm25Index::build(
+                chunks
+                    .iter()
+                    .map(|chunk| chunk.text(&documents).unwrap_or_default()),
+            ),
+        };
+        Ok(Self {
+            manifest: index.manifest.clone(),
+            ids: metadata
+                .iter()
+                .enumerate()
+                .map(|(document, meta)| (meta.id.clone(), document))
+                .collect(),
+            documents,
+            metadata,
+            removed: vec![false; chunks.len()],
+            num_removed: 0,
+            lexical,
+            index_rows: chunks.len(),
+            chunks,
+            document_rows,
+            vectors,
+            vector_index,
+            added: Vec::new(),
+            dim,
+        })
+    }
+
+    // Applies logged changes, in log order
+    fn replay(&mut self, records: Vec<WalRecord>) -> anyhow::Result<()> {
+        for record in records {
+            self.check(&record)?;
+            self.apply(record);
+        }
+        Ok(())
+    }
+
+    // Number of documents, not counting replaced or deleted ones
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    // Text and metadata of the document `id`
+    pub fn get(&self, id: &str) -> Option<(&str, &DocumentMeta)> {
+        let document = *self.ids.get(id)?;
+        Some((&self.documents[document], &self.metadata[document]))
+    }
+
+    /*
+    The `k` rows most similar to `query` ([1, hidden]), as (row, similarity) pairs, best first.
+
+    Rows of replaced or deleted documents, and of documents `filter` rejects, are excluded during
+    the search of the on-disk index; rows added since are all scored. With an approximate index,
+    `params` trade speed for recall; without one every index row is scored.
+    */
+    pub fn search(
+        &self,
+        query: Tensor,
+        k: usize,
+        params: &SearchParams,
+        filter: Option<&MetadataFilter>,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        let query: Vec<f32> = query.flatten_all()?.to_dtype(DType::F32)?.to_vec1()?;
+        ensure!(
+            query.len() == self.dim,
+            "query of {} dimensions for an index of {}",
+            query.len(),
+            self.dim
+        );
+        let allowed = self.allowed(filter);
+        let is_allowed = |row: usize| allowed.as_ref().is_none_or(|allowed| allowed[row]);
+
+        let mut results = match &self.vector_index {
+            Some(index) => {
+                let index_allowed = allowed
+                    .as_deref()
+                    .map(|allowed| &allowed[..self.index_rows]);
+                let num_index_allowed = index_allowed.map_or(self.index_rows, |allowed| {
+                    allowed.iter().filter(|&&allowed| allowed).count()
+                });
+                match num_index_allowed.min(k) {
+                    0 => Vec::new(),
+                    k => index.search(&query, k, params, index_allowed)?,
+                }
+            }
+            None => top_k(
+                (0..self.index_rows)
+                    .filter(|&row| is_allowed(row))
+                    .map(|row| (row, self.vectors.dot(row, &query))),
+                k,
+            ),
+        };
+        let added_rows = (self.index_rows..self.chunks.len())
+            .filter(|&row| is_allowed(row))
+            .map(|row| {
+                let start = (row - self.index_rows) * self.dim;
+                (row, dot(&self.added[start..start + self.dim], &query))
+            });
+        results.extend(added_rows);
+        Ok(top_k(results, k))
+    }
+
+    // The `k` rows ranking highest for the terms of `query` by BM25, with the same exclusions
+    pub fn lexical_search(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&MetadataFilter>,
+    ) -> Vec<(usize, f32)> {
+        self.lexical
+            .search(query, k, self.allowed(filter).as_deref())
+    }
+
+    // Rows a search may return, unless all of them: live rows of documents `filter` accepts
+    fn allowed(&self, filter: Option<&MetadataFilter>) -> Option<Vec<bool>> {
+        if filter.is_none() && self.num_removed == 0 {
+            return None;
+        }
+        let documents: Option<Vec<bool>> = filter.map(|filter| {
+            self.metadata
+                .iter()
+                .map(|meta| filter.matches(meta))
+                .collect()
+        });
+        let allowed = self
+            .chunks
+            .iter()
+            .zip(&self.removed)
+            .map(|(chunk, removed)| {
+                !removed
+                    && documents
+                        .as_ref()
+                        .is_none_or(|documents| documents[chunk.document])
+            });
+        Some(allowed.collect())
+    }
+
+    // Copy of the current documents, for compaction
+    fn snapshot(&self) -> Snapshot {
+        let mut documents = Vec::new();
+        let mut metadata = Vec::new();
+        let mut chunks = Vec::new();
+        let mut embeddings = Vec::with_capacity((self.chunks.len() - self.num_removed) * self.dim);
+        for (document, meta) in self.metadata.iter().enumerate() {
+            if self.ids.get(&meta.id) != Some(&document) {
+                continue;
+            }
+            for row in self.document_rows[document].clone() {
+                chunks.push(Chunk {
+                    document: documents.len(),
+                    ..self.chunks[row]
+                });
+                match row.checked_sub(self.index_rows) {
+                    Some(added) => embeddings
+                        .extend_from_slice(&self.added[added * self.dim..(added + 1) * self.dim]),
+                    None => embeddings.extend(self.vectors.row(row)),
+                }
+            }
+            documents.push(self.documents[document].clone());
+            metadata.push(meta.clone());
+        }
+        Snapshot {
+            manifest: self.manifest.clone(),
+            documents,
+            metadata,
+            chunks,
+            embeddings,
+        }
+    }
+
+    // Checks that `record` applies, before it is logged
+    fn check(&self, record: &WalRecord) -> anyhow::Result<()> {
+        if let WalRecord::Upsert {
+            text,
+            chunks,
+            embeddings,
+            ..
+        } = record
+        {
+            ensure!(
+                embeddings.len() == chunks.len() * self.dim,
+                "{} embedding values for {} chunks of {} dimensions",
+                embeddings.len(),
+                chunks.len(),
+                self.dim
+            );
+            if let Some(chunk) = chunks
+                .iter()
+                .find(|(start, end)| text.get(*start..*end).is_none())
+            {
+                bail!("chunk {:?} is not within the document text", chunk);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Upsert {
+                document: meta,
+                text,
+                chunks,
+                embeddings,
+            } => {
+                self.remove(&meta.id);
+                let document = self.documents.len();
+                let first_row = self.chunks.len();
+                for (start, end) in chunks {
+                    self.chunks.push(Chunk {
+                        document,
+                        start,
+                        end,
+                    });
+                    self.removed.push(false);
+                    self.lexical.push(&text[start..end]);
+                }
+                self.document_rows.push(first_row..self.chunks.len());
+                self.added.extend(embeddings);
+                self.ids.insert(meta.id.clone(), document);
+                self.documents.push(text);
+                self.metadata.push(meta);
+            }
+            WalRecord::Delete { id } => {
+                self.remove(&id);
+            }
+        }
+    }
+
+    // Removes the rows of the document `id` from search and frees its text
+    fn remove(&mut self, id: &str) {
+        let Some(document) = self.ids.remove(id) else {
+            return;
+        };
+        for row in self.document_rows[document].clone() {
+            self.removed[row] = true;
+            self.num_removed += 1;
+            let text = self.chunks[row].text(&self.documents).unwrap_or_default();
+            self.lexical.remove(row, text);
+        }
+        self.documents[document] = String::new();
+    }
+}
+
+// The approximate index of kind `kind` in `index`, or None for exact search
+fn open_vector_index(index: &IndexDir, kind: IndexKind) -> anyhow::Result<Option<VectorIndex>> {
+    let name = match kind {
+        IndexKind::Exact => return Ok(None),
+        IndexKind::Hnsw => HNSW_FILE,
+        IndexKind::IvfPq => IVFPQ_FILE,
+    };
+    let Some(path) = index.file(name) else {
+        println!(
+            "No {} in index {}, using exact search",
+            name,
+            index.path.display()
+        );
+        return Ok(None);
+    };
+    let vector_index = match kind {
+        IndexKind::Hnsw => VectorIndex::Hnsw(HnswIndex::open(path)?),
+        // Codes are read into memory; full vectors for re-ranking stay on disk
+        _ => {
+            let vectors = index.path.join(EMBEDDINGS_FILE);
+            VectorIndex::IvfPq(
+                IvfPqIndex::open(path)?
+                    .with_vectors(MappedVectors::open(vectors, EMBEDDINGS_KEY)?)?,
+            )
+        }
+    };
+    println!("Opened {:?} index of {} vectors", kind, vector_index.len());
+    Ok(Some(vector_index))
+}
+
+#[cfg(test)]
+// Documents are upserted as a single chunk
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use crate::chunking::whole_documents;
+    use crate::models::bert::Pooling;
+    use crate::search::store::EmbeddingModel;
+    use serde_json::Map;
+    use std::path::Path;
+
+    const DIM: usize = 8;
+
+    // Unit vector along axis `axis`; each test document gets its own axis
+    fn axis(axis: usize) -> Vec<f32> {
+        let mut vector = vec![0.0; DIM];
+        vector[axis] = 1.0;
+        vector
+    }
+
+    fn meta(id: &str) -> DocumentMeta {
+        DocumentMeta {
+            id: id.to_string(),
+            metadata: Map::new(),
+        }
+    }
+
+    // Writes an index of `texts`, with ids "0", "1", ... and document i embedded along axis i
+    fn write_index(dir: &Path, texts: &[&str], hnsw: bool) {
+        let documents: Vec<String> = texts.iter().map(|text| text.to_string()).collect();
+        let metadata: Vec<DocumentMeta> = (0..texts.len())
+            .map(|document| meta(&document.to_string()))
+            .collect();
+        let embeddings: Vec<f32> = (0..texts.len()).flat_map(axis).collect();
+        let mut writer = IndexWriter::create(dir).unwrap();
+        writer.write_documents(&documents, &metadata).unwrap();
+        writer.write_chunks(&whole_documents(&documents)).unwrap();
+        writer
+            .write_embeddings(
+                &Tensor::from_slice(&embeddings, (texts.len(), DIM), &Device::Cpu).unwrap(),
+            )
+            .unwrap();
+        if hnsw {
+            let index = HnswIndex::build(&embeddings, DIM, HnswParams::default()).unwrap();
+            writer.write_hnsw(&index).unwrap();
+        }
+        let model = EmbeddingModel {
+            model_id: "test-model".to_string(),
+            revision: "main".to_string(),
+            pooling: Pooling::Mean,
+            dimension: DIM,
+        };
+        writer.finish(model, None).unwrap();
+    }
+
+    fn open(dir: &Path, kind: IndexKind) -> Corpus {
+        Corpus::open(&IndexDir::open(dir).unwrap(), kind).unwrap()
+    }
+
+    fn upsert(corpus: &Corpus, id: &str, text: &str, embedding: usize) -> bool {
+        corpus
+            .upsert(
+                meta(id),
+                text.to_string(),
+                &[0..text.len()],
+                axis(embedding),
+            )
+            .unwrap()
+    }
+
+    // Texts of the `k` chunks most similar to axis `embedding`
+    fn search(corpus: &Corpus, embedding: usize, k: usize) -> Vec<String> {
+        let state = corpus.read();
+        let query = Tensor::from_slice(&axis(embedding), (1, DIM), &Device::Cpu).unwrap();
+        state
+            .search(query, k, &SearchParams::default(), None)
+            .unwrap()
+            .into_iter()
+            .map(|(row, _)| {
+                state.chunks[row]
+                    .text(&state.documents)
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn text(corpus: &Corpus, id: &str) -> Option<String> {
+        corpus.read().get(id).map(|(text, _)| text.to_string())
+    }
+
+    #[test]
+    fn upserts_replace_and_delete_documents_and_replay_after_reopening() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["zero", "one", "two"], false);
+        let corpus = open(&dir, IndexKind::Exact);
+
+        assert!(upsert(&corpus, "new", "first version", 5));
+        assert_eq!(search(&corpus, 5, 1), ["first version"]);
+        assert!(!upsert(&corpus, "new", "second version", 6));
+        assert!(!search(&corpus, 5, 10).contains(&"first version".to_string()));
+        assert_eq!(search(&corpus, 6, 1), ["second version"]);
+        assert!(corpus.delete("1").unwrap());
+        assert!(!corpus.delete("1").unwrap());
+        assert!(!corpus.delete("missing").unwrap());
+
+        let check = |corpus: &Corpus| {
+            assert_eq!(corpus.read().len(), 3);
+            assert_eq!(text(corpus, "new").as_deref(), Some("second version"));
+            assert_eq!(text(corpus, "1"), None);
+            assert_eq!(text(corpus, "2").as_deref(), Some("two"));
+            // Removed rows are never returned, however many results are asked for
+            let mut found = search(corpus, 1, 10);
+            found.sort();
+            assert_eq!(found, ["second version", "two", "zero"]);
+            let lexical = corpus.read().lexical_search("version", 10, None);
+            assert_eq!(lexical.len(), 1);
+        };
+        check(&corpus);
+        drop(corpus);
+        check(&open(&dir, IndexKind::Exact));
+    }
+
+    #[test]
+    fn lexical_search_never_returns_removed_rows() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(
+            &dir,
+            &["MMTC Q2 net loss", "Hudco bonds", "MMTC stake sale"],
+            false,
+        );
+        let corpus = open(&dir, IndexKind::Exact);
+        let lexical = |query: &str| -> Vec<String> {
+            let state = corpus.read();
+            state
+                .lexical_search(query, 10, None)
+                .into_iter()
+                .map(|(row, _)| {
+                    state.chunks[row]
+                        .text(&state.documents)
+                        .unwrap()
+                        .to_string()
+                })
+                .collect()
+        };
+        assert_eq!(
+            lexical("mmtc loss"),
+            ["MMTC Q2 net loss", "MMTC stake sale"]
+        );
+
+        corpus.delete("0").unwrap();
+        upsert(&corpus, "2", "MMTC Q3 net rises", 4);
+        assert_eq!(lexical("mmtc loss"), ["MMTC Q3 net rises"]);
+        assert!(lexical("sale").is_empty());
+    }
+
+    #[test]
+    fn filtered_searches_return_as_many_hits_as_documents_match() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["zero", "one", "two", "three", "four", "five"], true);
+        let corpus = open(&dir, IndexKind::Hnsw);
+        upsert(&corpus, "new", "added", 6);
+        corpus.delete("4").unwrap();
+
+        let filter: MetadataFilter =
+            serde_json::from_value(serde_json::json!({"id": {"in": ["0", "2", "4", "5", "new"]}}))
+                .unwrap();
+        let query = || Tensor::from_slice(&axis(0), (1, DIM), &Device::Cpu).unwrap();
+        let state = corpus.read();
+        let params = SearchParams::default();
+        for k in 1..=4 {
+            let found = state.search(query(), k, &params, Some(&filter)).unwrap();
+            assert_eq!(found.len(), k);
+            assert_eq!(found[0].0, 0);
+        }
+        // Matching rows of the index and added since, but not the deleted one
+        let mut found: Vec<usize> = state
+            .search(query(), 10, &params, Some(&filter))
+            .unwrap()
+            .into_iter()
+            .map(|(row, _)| row)
+            .collect();
+        found.sort();
+        assert_eq!(found, [0, 2, 5, 6]);
+    }
+
+    #[test]
+    fn rejects_changes_that_do_not_apply_without_logging_them() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["zero"], false);
+        let corpus = open(&dir, IndexKind::Exact);
+
+        let wrong_dimension = corpus.upsert(meta("a"), "text".into(), &[0..4], vec![1.0; 3]);
+        assert!(wrong_dimension.is_err());
+        let outside_text = corpus.upsert(meta("a"), "text".into(), &[0..9], axis(1));
+        assert!(outside_text.is_err());
+        drop(corpus);
+
+        let corpus = open(&dir, IndexKind::Exact);
+        assert_eq!(corpus.read().len(), 1);
+        assert!(corpus.compact().unwrap().is_none());
+    }
+
+    #[test]
+    fn compaction_serves_the_new_index_and_survives_reopening() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["zero", "one", "two"], true);
+        let corpus = open(&dir, IndexKind::Hnsw);
+        assert!(corpus.compact().unwrap().is_none());
+
+        upsert(&corpus, "new", "added", 5);
+        upsert(&corpus, "2", "two, replaced", 6);
+        corpus.delete("0").unwrap();
+        let manifest = corpus.compact().unwrap().unwrap();
+        assert_eq!((manifest.documents, manifest.count), (3, 3));
+        assert!(manifest.files.iter().any(|file| file.name == HNSW_FILE));
+
+        let check = |corpus: &Corpus| {
+            let state = corpus.read();
+            // Removed rows are gone and every row is in the on-disk index again
+            assert_eq!(state.chunks.len(), 3);
+            assert_eq!((state.index_rows, state.num_removed), (3, 0));
+            assert!(state.added.is_empty());
+            assert!(state.vector_index.is_some());
+            drop(state);
+            assert_eq!(text(corpus, "0"), None);
+            assert_eq!(text(corpus, "2").as_deref(), Some("two, replaced"));
+            assert_eq!(search(corpus, 5, 1), ["added"]);
+            assert_eq!(search(corpus, 6, 1), ["two, replaced"]);
+            assert_eq!(search(corpus, 1, 1), ["one"]);
+        };
+        check(&corpus);
+        assert!(corpus.compact().unwrap().is_none());
+        drop(corpus);
+        let corpus = open(&dir, IndexKind::Hnsw);
+        check(&corpus);
+        assert!(corpus.compact().unwrap().is_none());
+    }
+
+    #[test]
+    fn compaction_keeps_changes_made_while_it_builds_the_index() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["zero", "one"], false);
+        let corpus = open(&dir, IndexKind::Exact);
+        upsert(&corpus, "before", "before compaction", 2);
+
+        // The steps of `compact`, with changes in between taking the snapshot and replacing
+        // the index
+        let snapshot = {
+            let mut log = corpus.lock_log();
+            log.since_snapshot = Some(Vec::new());
+            corpus.read().snapshot()
+        };
+        upsert(&corpus, "during", "during compaction", 3);
+        corpus.delete("0").unwrap();
+        corpus.replace_index(snapshot).unwrap();
+
+        let check = |corpus: &Corpus| {
+            assert_eq!(text(corpus, "before").as_deref(), Some("before compaction"));
+            assert_eq!(text(corpus, "during").as_deref(), Some("during compaction"));
+            assert_eq!(text(corpus, "0"), None);
+            assert_eq!(search(corpus, 3, 1), ["during compaction"]);
+        };
+        check(&corpus);
+        // The changes made meanwhile are in the new log, so they are compacted next time
+        assert_eq!(corpus.lock_log().wal.len(), 2);
+        drop(corpus);
+        let corpus = open(&dir, IndexKind::Exact);
+        check(&corpus);
+        assert_eq!(corpus.read().index_rows, 3);
+        let manifest = corpus.compact().unwrap().unwrap();
+        assert_eq!(manifest.documents, 3);
+    }
+}
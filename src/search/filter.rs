@@ -0,0 +1,199 @@
+/*
+Metadata filters of similarity queries.
+
+A filter maps fields to conditions, all of which a document must meet:
+
+    {"Words": {"gte": 8}, "Decisions": {"eq": "positive"}, "id": {"in": ["1", "2"]}}
+
+`id` is the document id, any other field a metadata field. A condition on an object or array
+field holds when it holds for the field or any of its values, so `"Decisions": {"eq":
+"positive"}` matches documents with a positive entity. Numbers compare numerically and strings
+lexicographically; a missing field or a value of another type does not match.
+*/
+use super::store::DocumentMeta;
+use serde::Deserialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct MetadataFilter(BTreeMap<String, Condition>);
+
+/// Operators on one field; a value must satisfy all that are set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    #[serde(default)]
+    eq: Option<Value>,
+    #[serde(default, rename = "in")]
+    one_of: Option<Vec<Value>>,
+    #[serde(default)]
+    gt: Option<Value>,
+    #[serde(default)]
+    gte: Option<Value>,
+    #[serde(default)]
+    lt: Option<Value>,
+    #[serde(default)]
+    lte: Option<Value>,
+}
+
+impl MetadataFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        for (field, condition) in &self.0 {
+            condition
+                .validate()
+                .map_err(|message| format!("filter on {:?}: {}", field, message))?;
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, document: &DocumentMeta) -> bool {
+        self.0.iter().all(|(field, condition)| {
+            if field == "id" {
+                condition.holds(&Value::String(document.id.clone()))
+            } else {
+                document
+                    .metadata
+                    .get(field)
+                    .is_some_and(|value| condition.holds(value))
+            }
+        })
+    }
+}
+
+impl Condition {
+    fn validate(&self) -> Result<(), String> {
+        let bounds = [&self.gt, &self.gte, &self.lt, &self.lte];
+        if self.eq.is_none() && self.one_of.is_none() && bounds.iter().all(|bound| bound.is_none())
+        {
+            return Err("needs one of eq, in, gt, gte, lt or lte".to_string());
+        }
+        for bound in bounds.into_iter().flatten() {
+            if !matches!(bound, Value::Number(_) | Value::String(_)) {
+                return Err(format!(
+                    "range bounds must be numbers or strings, got {}",
+                    bound
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn holds(&self, value: &Value) -> bool {
+        self.holds_for(value)
+            || match value {
+                Value::Array(values) => values.iter().any(|value| self.holds_for(value)),
+                Value::Object(values) => values.values().any(|value| self.holds_for(value)),
+                _ => false,
+            }
+    }
+
+    fn holds_for(&self, value: &Value) -> bool {
+        let compares = |bound: &Option<Value>, accepted: &[Ordering]| {
+            bound.as_ref().is_none_or(|bound| {
+                compare(value, bound).is_some_and(|ordering| accepted.contains(&ordering))
+            })
+        };
+        self.eq.as_ref().is_none_or(|eq| equal(value, eq))
+            && self
+                .one_of
+                .as_ref()
+                .is_none_or(|values| values.iter().any(|other| equal(value, other)))
+            && compares(&self.gt, &[Ordering::Greater])
+            && compares(&self.gte, &[Ordering::Greater, Ordering::Equal])
+            && compares(&self.lt, &[Ordering::Less])
+            && compares(&self.lte, &[Ordering::Less, Ordering::Equal])
+    }
+}
+
+// Numbers are equal by value, so that 8 matches 8.0
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(filter: Value) -> MetadataFilter {
+        let filter: MetadataFilter = serde_json::from_value(filter).unwrap();
+        filter.validate().unwrap();
+        filter
+    }
+
+    fn document(metadata: Value) -> DocumentMeta {
+        DocumentMeta {
+            id: "7".to_string(),
+            metadata: serde_json::from_value(metadata).unwrap(),
+        }
+    }
+
+    #[test]
+    fn conditions_on_arrays_and_objects_hold_for_any_value() {
+        let headline = document(json!({
+            "Decisions": {"MMTC": "positive", "Hudco": "negative"},
+            "Tags": ["metals", "trade"],
+            "Counts": [3, 9],
+        }));
+        assert!(filter(json!({"Decisions": {"eq": "positive"}})).matches(&headline));
+        assert!(!filter(json!({"Decisions": {"eq": "neutral"}})).matches(&headline));
+        assert!(filter(json!({"Tags": {"in": ["banks", "trade"]}})).matches(&headline));
+        assert!(filter(json!({"Tags": {"eq": ["metals", "trade"]}})).matches(&headline));
+        assert!(filter(json!({"Counts": {"gte": 8}})).matches(&headline));
+        assert!(!filter(json!({"Counts": {"gt": 9}})).matches(&headline));
+        // All operators of a condition must hold for the same value
+        assert!(!filter(json!({"Counts": {"gt": 4, "lt": 8}})).matches(&headline));
+        assert!(filter(json!({"Counts": {"gt": 4, "lt": 10}})).matches(&headline));
+    }
+
+    #[test]
+    fn numbers_compare_by_value() {
+        let headline = document(json!({"Words": 8}));
+        assert!(filter(json!({"Words": {"eq": 8.0}})).matches(&headline));
+        assert!(filter(json!({"Words": {"in": [7, 8.0]}})).matches(&headline));
+        assert!(filter(json!({"Words": {"gte": 8.0, "lte": 8}})).matches(&headline));
+        assert!(!filter(json!({"Words": {"gt": 7.5, "lt": 8}})).matches(&headline));
+        assert!(filter(json!({"id": {"in": ["1", "7"]}})).matches(&headline));
+        assert!(filter(json!({"id": {"gt": "10"}})).matches(&headline));
+    }
+
+    #[test]
+    fn values_of_another_type_or_missing_fields_do_not_match() {
+        let headline = document(json!({"Words": 8, "Source": "wire"}));
+        assert!(!filter(json!({"Words": {"eq": "8"}})).matches(&headline));
+        assert!(!filter(json!({"Words": {"gte": "1"}})).matches(&headline));
+        assert!(!filter(json!({"Source": {"lt": 100}})).matches(&headline));
+        assert!(!filter(json!({"id": {"eq": 7}})).matches(&headline));
+        assert!(!filter(json!({"Author": {"eq": "wire"}})).matches(&headline));
+        assert!(!filter(json!({"Words": {"eq": 8}, "Source": {"eq": "desk"}})).matches(&headline));
+        assert!(filter(json!({})).matches(&headline));
+    }
+
+    #[test]
+    fn rejects_conditions_without_a_usable_operator() {
+        let invalid = |filter: Value| {
+            serde_json::from_value::<MetadataFilter>(filter)
+                .map_err(|e| e.to_string())
+                .and_then(|filter| filter.validate())
+                .is_err()
+        };
+        assert!(invalid(json!({"Words": {}})));
+        assert!(invalid(json!({"Words": {"gte": [8]}})));
+        assert!(invalid(json!({"Words": {"gte": null}})));
+        assert!(invalid(json!({"Words": {"like": "8"}})));
+        assert!(invalid(json!({"Words": 8})));
+    }
+}
@@ -0,0 +1,630 @@
+// Hierarchical navigable small world (HNSW) graph for approximate nearest-neighbour search
+use super::{dot, top_k, Scored};
+use anyhow::{bail, ensure};
+use memmap2::Mmap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::ops::{Deref, Range};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"HNSWIDX1";
+const HEADER_BYTES: usize = 40;
+
+/// Candidates kept while searching when `ef_search` is unset.
+pub const DEFAULT_EF_SEARCH: usize = 64;
+// A filtered search scans the allowed nodes when they are at most 1 / FILTER_SCAN_DIVISOR of the
+// index, since the walk would mostly visit nodes it cannot return
+const FILTER_SCAN_DIVISOR: usize = 20;
+
+/// Construction parameters of an HNSW index.
+#[derive(Debug, Clone, Copy)]
+pub struct HnswParams {
+    /// Links per node on the upper levels; level 0 keeps up to twice as many.
+    pub m: usize,
+    /// Candidates considered when linking a node; larger builds slower, better-connected graphs.
+    pub ef_construction: usize,
+    /// Seed of the random node levels, so builds are reproducible.
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            seed: 42,
+        }
+    }
+}
+
+// Bytes of a serialized index, built in memory or mapped from a file
+enum Storage {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for Storage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Storage::Owned(bytes) => bytes,
+            Storage::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+/*
+An HNSW index over inner-product similarity, searched in place in its serialized form.
+
+Layout (little-endian): a header (magic, dim u32, m u32, len u64, entry point u64, max level
+u32, padding), the vectors as `len * dim` f32, padding to 8 bytes, one u64 offset per node into
+the links, then the links as u32 words. A node's links are its number of levels, then for each
+level the neighbour count and `2 * m` (level 0) or `m` (upper levels) neighbour slots.
+*/
+pub struct HnswIndex {
+    data: Storage,
+    dim: usize,
+    m: usize,
+    len: usize,
+    entry_point: usize,
+    max_level: usize,
+    vectors: Range<usize>,
+    offsets: Range<usize>,
+    links: Range<usize>,
+}
+
+// What searching a layer needs from a graph under construction or a serialized index
+trait Graph {
+    fn similarity(&self, node: usize, query: &[f32]) -> f32;
+    fn for_each_neighbor(&self, node: usize, level: usize, f: impl FnMut(usize));
+}
+
+// Neighbour slots of a node on `level`
+fn capacity(m: usize, level: usize) -> usize {
+    if level == 0 {
+        2 * m
+    } else {
+        m
+    }
+}
+
+// Words of the link block of a node with `levels` levels
+fn block_words(m: usize, levels: usize) -> usize {
+    1 + (0..levels)
+        .map(|level| 1 + capacity(m, level))
+        .sum::<usize>()
+}
+
+/*
+The `ef` nodes of `level` most similar to `query` found by a best-first walk from
+`entry_points`, best first.
+
+The walk stops when the best unexplored candidate is worse than the worst of the `ef` found.
+With `allowed`, only allowed nodes are found, but the walk still goes through the others so
+that it can reach allowed nodes beyond them.
+*/
+fn search_layer<G: Graph>(
+    graph: &G,
+    query: &[f32],
+    entry_points: &[Scored],
+    ef: usize,
+    level: usize,
+    allowed: Option<&[bool]>,
+) -> Vec<Scored> {
+    let admits = |node: usize| allowed.is_none_or(|allowed| allowed[node]);
+    let mut visited: HashSet<usize> = entry_points.iter().map(|point| point.index).collect();
+    let mut candidates: BinaryHeap<Scored> = entry_points.iter().copied().collect();
+    let mut found: BinaryHeap<Reverse<Scored>> = entry_points
+        .iter()
+        .copied()
+        .filter(|point| admits(point.index))
+        .map(Reverse)
+        .collect();
+    while found.len() > ef {
+        found.pop();
+    }
+
+    while let Some(candidate) = candidates.pop() {
+        if let Some(Reverse(worst)) = found.peek() {
+            if found.len() >= ef && candidate < *worst {
+                break;
+            }
+        }
+        graph.for_each_neighbor(candidate.index, level, |neighbor| {
+            if !visited.insert(neighbor) {
+                return;
+            }
+            let scored = Scored {
+                score: graph.similarity(neighbor, query),
+                index: neighbor,
+            };
+            let improves = match found.peek() {
+                Some(Reverse(worst)) => found.len() < ef || scored > *worst,
+                None => true,
+            };
+            if improves {
+                candidates.push(scored);
+                if admits(neighbor) {
+                    found.push(Reverse(scored));
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        });
+    }
+    found
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(scored)| scored)
+        .collect()
+}
+
+// The graph while nodes are inserted, with links as vectors per node and level
+struct Builder<'a> {
+    vectors: &'a [f32],
+    dim: usize,
+    params: HnswParams,
+    links: Vec<Vec<Vec<u32>>>,
+    entry_point: Option<usize>,
+    max_level: usize,
+}
+
+impl Graph for Builder<'_> {
+    fn similarity(&self, node: usize, query: &[f32]) -> f32 {
+        dot(self.vector(node), query)
+    }
+
+    fn for_each_neighbor(&self, node: usize, level: usize, mut f: impl FnMut(usize)) {
+        if let Some(neighbors) = self.links[node].get(level) {
+            neighbors.iter().for_each(|&neighbor| f(neighbor as usize));
+        }
+    }
+}
+
+impl Builder<'_> {
+    fn vector(&self, node: usize) -> &[f32] {
+        &self.vectors[node * self.dim..(node + 1) * self.dim]
+    }
+
+    /*
+    Keeps up to `m` of `candidates` (sorted best first) as neighbours: a candidate is dropped
+    when it is more similar to an already kept neighbour than to the node, which spreads links
+    across clusters instead of spending them all on the nearest one.
+    */
+    fn select_neighbors(&self, candidates: &[Scored], m: usize) -> Vec<Scored> {
+        let mut selected: Vec<Scored> = Vec::with_capacity(m);
+        for &candidate in candidates {
+            if selected.len() == m {
+                break;
+            }
+            let vector = self.vector(candidate.index);
+            if selected
+                .iter()
+                .all(|kept| dot(vector, self.vector(kept.index)) < candidate.score)
+            {
+                selected.push(candidate);
+            }
+        }
+        selected
+    }
+
+    // Links `from` to `to` on `level`, pruning the links of `from` when they overflow
+    fn link(&mut self, from: usize, to: usize, level: usize) {
+        self.links[from][level].push(to as u32);
+        let capacity = capacity(self.params.m, level);
+        if self.links[from][level].len() <= capacity {
+            return;
+        }
+        let vector = self.vector(from);
+        let mut candidates: Vec<Scored> = self.links[from][level]
+            .iter()
+            .map(|&neighbor| Scored {
+                score: dot(vector, self.vector(neighbor as usize)),
+                index: neighbor as usize,
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        let kept = self.select_neighbors(&candidates, capacity);
+        self.links[from][level] = kept.iter().map(|scored| scored.index as u32).collect();
+    }
+
+    fn insert(&mut self, node: usize, level: usize) {
+        self.links.push(vec![Vec::new(); level + 1]);
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            self.max_level = level;
+            return;
+        };
+
+        // Greedy descent through the levels above the node's own
+        let vectors = self.vectors;
+        let query = &vectors[node * self.dim..(node + 1) * self.dim];
+        let mut entry_points = vec![Scored {
+            score: dot(self.vector(entry_point), query),
+            index: entry_point,
+        }];
+        for layer in (level + 1..=self.max_level).rev() {
+            entry_points = search_layer(self, query, &entry_points, 1, layer, None);
+        }
+
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found = search_layer(
+                self,
+                query,
+                &entry_points,
+                self.params.ef_construction,
+                layer,
+                None,
+            );
+            let neighbors = self.select_neighbors(&found, self.params.m);
+            for neighbor in &neighbors {
+                self.links[node][layer].push(neighbor.index as u32);
+                self.link(neighbor.index, node, layer);
+            }
+            entry_points = found;
+        }
+        if level > self.max_level {
+            self.entry_point = Some(node);
+            self.max_level = level;
+        }
+    }
+
+    fn serialize(self) -> Vec<u8> {
+        let m = self.params.m;
+        let len = self.links.len();
+        let link_words: usize = self
+            .links
+            .iter()
+            .map(|levels| block_words(m, levels.len()))
+            .sum();
+        let mut bytes = Vec::with_capacity(
+            HEADER_BYTES + self.vectors.len() * 4 + 8 + len * 8 + link_words * 4,
+        );
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.dim as u32).to_le_bytes());
+        bytes.extend_from_slice(&(m as u32).to_le_bytes());
+        bytes.extend_from_slice(&(len as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.entry_point.unwrap_or(0) as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.max_level as u32).to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        for value in self.vectors {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.resize(bytes.len().next_multiple_of(8), 0);
+
+        let mut offset = 0;
+        for levels in &self.links {
+            bytes.extend_from_slice(&(offset as u64).to_le_bytes());
+            offset += block_words(m, levels.len());
+        }
+        for levels in &self.links {
+            bytes.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+            for (level, neighbors) in levels.iter().enumerate() {
+                bytes.extend_from_slice(&(neighbors.len() as u32).to_le_bytes());
+                for slot in 0..capacity(m, level) {
+                    let neighbor = neighbors.get(slot).copied().unwrap_or(0);
+                    bytes.extend_from_slice(&neighbor.to_le_bytes());
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl Graph for HnswIndex {
+    fn similarity(&self, node: usize, query: &[f32]) -> f32 {
+        let start = self.vectors.start + node * self.dim * 4;
+        self.data[start..start + self.dim * 4]
+            .chunks_exact(4)
+            .zip(query)
+            .map(|(bytes, q)| f32::from_le_bytes(bytes.try_into().unwrap()) * q)
+            .sum()
+    }
+
+    fn for_each_neighbor(&self, node: usize, level: usize, mut f: impl FnMut(usize)) {
+        let block = self.node_block(node);
+        if level >= self.word(block) as usize {
+            return;
+        }
+        let slots = block + 1 + (0..level).map(|l| 1 + capacity(self.m, l)).sum::<usize>();
+        let count = self.word(slots) as usize;
+        (0..count).for_each(|slot| f(self.word(slots + 1 + slot) as usize));
+    }
+}
+
+impl HnswIndex {
+    /*
+    Builds an index over `vectors`, `dim` floats per vector and node i being vector i.
+
+    Node levels are drawn with probability decaying by `1 / m` per level, as in the HNSW paper.
+    Vectors are expected L2-normalized, so similarity is cosine similarity.
+    */
+    pub fn build(vectors: &[f32], dim: usize, params: HnswParams) -> anyhow::Result<Self> {
+        ensure!(dim > 0, "vectors must have at least one dimension");
+        ensure!(
+            vectors.len().is_multiple_of(dim),
+            "{} floats do not split into vectors of {} dimensions",
+            vectors.len(),
+            dim
+        );
+        ensure!(params.m >= 2, "m must be at least 2, got {}", params.m);
+        ensure!(
+            params.ef_construction >= 1,
+            "ef_construction must be at least 1"
+        );
+        let len = vectors.len() / dim;
+        ensure!(
+            len <= u32::MAX as usize,
+            "an index holds at most {} vectors",
+            u32::MAX
+        );
+
+        let level_multiplier = 1.0 / (params.m as f64).ln();
+        let mut rng = StdRng::seed_from_u64(params.seed);
+        let mut builder = Builder {
+            vectors,
+            dim,
+            params,
+            links: Vec::with_capacity(len),
+            entry_point: None,
+            max_level: 0,
+        };
+        for node in 0..len {
+            let uniform: f64 = rng.gen_range(f64::EPSILON..1.0);
+            let level = (-uniform.ln() * level_multiplier) as usize;
+            builder.insert(node, level);
+            if (node + 1).is_multiple_of(100_000) {
+                println!("Indexed {} of {} vectors", node + 1, len);
+            }
+        }
+        Self::from_storage(Storage::Owned(builder.serialize()))
+    }
+
+    // Memory-maps an index written by `save`; the file must not change while it is mapped
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::from_storage(Storage::Mapped(mmap))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        std::fs::write(path, &*self.data)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /*
+    The `k` nodes most similar to `query`, as (node, similarity) pairs, best first.
+
+    `ef_search` is how many candidates the level-0 search keeps (at least `k`): larger values
+    trade speed for recall. With `allowed` (one flag per node) only allowed nodes are returned;
+    when they are few, they are scanned instead of walking a graph made mostly of others.
+    */
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        ef_search: usize,
+        allowed: Option<&[bool]>,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        ensure!(
+            query.len() == self.dim,
+            "query has {} dimensions, the index {}",
+            query.len(),
+            self.dim
+        );
+        if self.is_empty() || k == 0 {
+            return Ok(Vec::new());
+        }
+        let ef_search = ef_search.max(k);
+        if let Some(allowed) = allowed {
+            ensure!(
+                allowed.len() == self.len,
+                "filter has {} flags for {} nodes",
+                allowed.len(),
+                self.len
+            );
+            let allowed_count = allowed.iter().filter(|&&allowed| allowed).count();
+            if allowed_count <= ef_search.max(self.len / FILTER_SCAN_DIVISOR) {
+                let scores = (0..self.len)
+                    .filter(|&node| allowed[node])
+                    .map(|node| (node, self.similarity(node, query)));
+                return Ok(top_k(scores, k));
+            }
+        }
+        let mut entry_points = vec![Scored {
+            score: self.similarity(self.entry_point, query),
+            index: self.entry_point,
+        }];
+        for level in (1..=self.max_level).rev() {
+            entry_points = search_layer(self, query, &entry_points, 1, level, None);
+        }
+        let mut found = search_layer(self, query, &entry_points, ef_search, 0, allowed);
+        found.truncate(k);
+        Ok(found
+            .into_iter()
+            .map(|scored| (scored.index, scored.score))
+            .collect())
+    }
+
+    // Parses and checks the layout, so that searching can index into it without failing
+    fn from_storage(data: Storage) -> anyhow::Result<Self> {
+        ensure!(
+            data.len() >= HEADER_BYTES && &data[..8] == MAGIC,
+            "not an HNSW index file"
+        );
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+        let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap()) as usize;
+        let (dim, m, len, entry_point, max_level) =
+            (u32_at(8), u32_at(12), u64_at(16), u64_at(24), u32_at(32));
+        ensure!(dim > 0 && m >= 2, "corrupt HNSW header");
+        ensure!(len == 0 || entry_point < len, "corrupt HNSW entry point");
+
+        let vectors = HEADER_BYTES..HEADER_BYTES + len * dim * 4;
+        let offsets_start = vectors.end.next_multiple_of(8);
+        let offsets = offsets_start..offsets_start + len * 8;
+        ensure!(offsets.end <= data.len(), "truncated HNSW index file");
+        let links = offsets.end..data.len();
+        ensure!(links.len().is_multiple_of(4), "corrupt HNSW links");
+        let link_words = links.len() / 4;
+        let word = |index: usize| u32_at(links.start + index * 4);
+
+        for node in 0..len {
+            let block = u64_at(offsets.start + node * 8);
+            ensure!(block < link_words, "corrupt HNSW links of node {}", node);
+            let levels = word(block);
+            ensure!(
+                (1..=max_level + 1).contains(&levels)
+                    && block + block_words(m, levels) <= link_words,
+                "corrupt HNSW links of node {}",
+                node
+            );
+            let mut slots = block + 1;
+            for level in 0..levels {
+                let count = word(slots);
+                ensure!(
+                    count <= capacity(m, level)
+                        && (0..count).all(|slot| word(slots + 1 + slot) < len),
+                    "corrupt HNSW links of node {}",
+                    node
+                );
+                slots += 1 + capacity(m, level);
+            }
+        }
+        if len > 0 && word(u64_at(offsets.start + entry_point * 8)) != max_level + 1 {
+            bail!("corrupt HNSW entry point");
+        }
+
+        Ok(Self {
+            data,
+            dim,
+            m,
+            len,
+            entry_point,
+            max_level,
+            vectors,
+            offsets,
+            links,
+        })
+    }
+
+    // Index of the first word of the link block of `node`
+    fn node_block(&self, node: usize) -> usize {
+        let at = self.offsets.start + node * 8;
+        u64::from_le_bytes(self.data[at..at + 8].try_into().unwrap()) as usize
+    }
+
+    fn word(&self, index: usize) -> u32 {
+        let at = self.links.start + index * 4;
+        u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::clustered_vectors;
+
+    const LEN: usize = 2000;
+    const DIM: usize = 16;
+    const K: usize = 10;
+
+    // The `k` allowed nodes most similar to `query`, by brute force
+    fn exact(vectors: &[f32], query: &[f32], allowed: &[bool]) -> Vec<usize> {
+        let scores = vectors
+            .chunks_exact(DIM)
+            .map(|vector| dot(vector, query))
+            .enumerate()
+            .filter(|&(node, _)| allowed[node]);
+        top_k(scores, K).into_iter().map(|(node, _)| node).collect()
+    }
+
+    // Searches the first 50 vectors as queries, returning the fraction of the exact results found
+    fn filtered_recall(index: &HnswIndex, vectors: &[f32], allowed: &[bool]) -> f64 {
+        let mut hits = 0;
+        for query in vectors.chunks_exact(DIM).take(50) {
+            let found = index
+                .search(query, K, DEFAULT_EF_SEARCH, Some(allowed))
+                .unwrap();
+            assert_eq!(found.len(), K);
+            assert!(found.iter().all(|&(node, _)| allowed[node]));
+            let exact = exact(vectors, query, allowed);
+            hits += found
+                .iter()
+                .filter(|(node, _)| exact.contains(node))
+                .count();
+        }
+        hits as f64 / (50 * K) as f64
+    }
+
+    #[test]
+    fn scans_when_few_nodes_are_allowed() {
+        let vectors = clustered_vectors(LEN, DIM, 20, 1);
+        let index = HnswIndex::build(&vectors, DIM, HnswParams::default()).unwrap();
+        // Up to LEN / FILTER_SCAN_DIVISOR allowed nodes are scanned, so the results are exact
+        let allowed: Vec<bool> = (0..LEN).map(|node| node % 20 == 3).collect();
+        assert_eq!(
+            allowed.iter().filter(|&&allowed| allowed).count(),
+            LEN / FILTER_SCAN_DIVISOR
+        );
+        for query in vectors.chunks_exact(DIM).take(20) {
+            let found = index
+                .search(query, K, DEFAULT_EF_SEARCH, Some(&allowed))
+                .unwrap();
+            let found: Vec<usize> = found.into_iter().map(|(node, _)| node).collect();
+            assert_eq!(found, exact(&vectors, query, &allowed));
+        }
+
+        let three: Vec<bool> = (0..LEN).map(|node| node < 3).collect();
+        let found = index.search(&vectors[..DIM], K, 8, Some(&three)).unwrap();
+        assert_eq!(found.len(), 3);
+    }
+
+    #[test]
+    fn walks_the_graph_through_nodes_that_are_not_allowed() {
+        let vectors = clustered_vectors(LEN, DIM, 20, 2);
+        let index = HnswIndex::build(&vectors, DIM, HnswParams::default()).unwrap();
+        // Just over the scan threshold, and most of the index
+        let sparse: Vec<bool> = (0..LEN).map(|node| node % 19 == 0).collect();
+        assert!(sparse.iter().filter(|&&allowed| allowed).count() > LEN / FILTER_SCAN_DIVISOR);
+        let dense: Vec<bool> = (0..LEN).map(|node| node % 3 != 0).collect();
+        assert!(filtered_recall(&index, &vectors, &sparse) >= 0.9);
+        assert!(filtered_recall(&index, &vectors, &dense) >= 0.9);
+    }
+
+    #[test]
+    fn default_ef_search_finds_most_exact_neighbours() {
+        // Queries are held-out vectors of the same clusters
+        let mut vectors = clustered_vectors(LEN + 100, DIM, 20, 3);
+        let queries = vectors.split_off(LEN * DIM);
+        let index = HnswIndex::build(&vectors, DIM, HnswParams::default()).unwrap();
+        let all = vec![true; LEN];
+        let mut hits = 0;
+        for query in queries.chunks_exact(DIM) {
+            let found = index.search(query, K, DEFAULT_EF_SEARCH, None).unwrap();
+            let exact = exact(&vectors, query, &all);
+            hits += found
+                .iter()
+                .filter(|(node, _)| exact.contains(node))
+                .count();
+        }
+        let recall = hits as f64 / (100 * K) as f64;
+        assert!(recall >= 0.95, "recall@{} {}", K, recall);
+    }
+}
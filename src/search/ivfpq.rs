@@ -0,0 +1,555 @@
+// Inverted-file index with product quantization (IVF-PQ) for approximate nearest-neighbour search
+use super::{dot, top_k, MappedVectors};
+use anyhow::ensure;
+use candle::{DType, Device, Tensor};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"IVFPQ001";
+const HEADER_BYTES: usize = 32;
+// Codewords per subquantizer; codes are one byte
+const MAX_CODEWORDS: usize = 256;
+// Rows assigned to centroids per matrix product, bounding the [rows, centroids] score matrix
+const ASSIGN_BATCH: usize = 8192;
+
+/// Lists probed per query when unset.
+pub const DEFAULT_NPROBE: usize = 16;
+/// Candidates re-ranked at full precision per result when unset.
+pub const DEFAULT_RERANK_FACTOR: usize = 10;
+
+/// Training parameters of an IVF-PQ index.
+#[derive(Debug, Clone, Copy)]
+pub struct IvfPqParams {
+    /// Coarse centroids, each owning the inverted list of the vectors nearest to it.
+    pub nlist: usize,
+    /// Subvectors each vector is cut into and encoded as one byte; must divide the dimension.
+    pub num_subquantizers: usize,
+    /// Vectors sampled to train the centroids and codebooks.
+    pub max_training_vectors: usize,
+    /// Lloyd iterations of each k-means.
+    pub iterations: usize,
+    pub seed: u64,
+}
+
+impl IvfPqParams {
+    // About 4 * sqrt(len) lists and 8-dimensional subvectors
+    pub fn for_size(len: usize, dim: usize) -> Self {
+        let nlist = ((len as f64).sqrt() * 4.0).round() as usize;
+        let num_subquantizers = (1..=(dim / 8).max(1))
+            .rev()
+            .find(|m| dim.is_multiple_of(*m))
+            .unwrap_or(1);
+        Self {
+            nlist: nlist.clamp(1, len.max(1)),
+            num_subquantizers,
+            max_training_vectors: 65_536,
+            iterations: 15,
+            seed: 42,
+        }
+    }
+}
+
+// The vectors nearest to one coarse centroid, with their PQ codes
+struct InvertedList {
+    ids: Vec<u32>,
+    // `num_subquantizers` bytes per vector
+    codes: Vec<u8>,
+}
+
+/*
+An IVF-PQ index over inner-product similarity.
+
+Each vector is filed under its nearest coarse centroid and its residual from that centroid is
+stored as one codeword index per subvector, so a vector takes `num_subquantizers` bytes instead
+of `4 * dim`. A query scores the vectors of the `nprobe` nearest lists from these codes and
+re-ranks the best candidates against the full-precision vectors, read from a memory-mapped file.
+
+Layout (little-endian): magic, dim u32, nlist u32, num_subquantizers u32, codewords u32, len
+u64, the centroids as `nlist * dim` f32, the codebooks as `num_subquantizers * codewords *
+(dim / num_subquantizers)` f32, then per list its length u64, ids u32 and codes.
+*/
+pub struct IvfPqIndex {
+    dim: usize,
+    num_subquantizers: usize,
+    codewords: usize,
+    len: usize,
+    centroids: Vec<f32>,
+    // Half the squared norm of each centroid, for nearest-centroid search by inner product
+    centroid_half_norms: Vec<f32>,
+    codebooks: Vec<f32>,
+    lists: Vec<InvertedList>,
+    vectors: Option<MappedVectors>,
+}
+
+// Index of the nearest centroid (by L2 distance) of each row of `data`
+fn assign(data: &Tensor, centroids: &Tensor) -> anyhow::Result<Vec<u32>> {
+    let half_norms = (centroids.sqr()?.sum(1)? * 0.5)?.unsqueeze(0)?;
+    let mut assignment = Vec::with_capacity(data.dim(0)?);
+    for start in (0..data.dim(0)?).step_by(ASSIGN_BATCH) {
+        let rows = data.narrow(0, start, ASSIGN_BATCH.min(data.dim(0)? - start))?;
+        // argmin |x - c|^2 = argmax x.c - |c|^2 / 2
+        let scores = rows.matmul(&centroids.t()?)?.broadcast_sub(&half_norms)?;
+        assignment.extend(scores.argmax(1)?.to_vec1::<u32>()?);
+    }
+    Ok(assignment)
+}
+
+// Lloyd's k-means of the rows of `data` into `k` centroids, seeded with distinct random rows;
+// a centroid left without rows keeps its position
+fn kmeans(data: &Tensor, k: usize, iterations: usize, rng: &mut StdRng) -> anyhow::Result<Tensor> {
+    let (rows, dim) = data.dims2()?;
+    let mut seeds: Vec<u32> = (0..rows as u32).collect();
+    seeds.shuffle(rng);
+    seeds.truncate(k);
+    let mut centroids = data.index_select(&Tensor::new(seeds.as_slice(), data.device())?, 0)?;
+    let ones = Tensor::ones(rows, DType::F32, data.device())?;
+    for _ in 0..iterations {
+        let assignment = Tensor::new(assign(data, &centroids)?.as_slice(), data.device())?;
+        let sums =
+            Tensor::zeros((k, dim), DType::F32, data.device())?.index_add(&assignment, data, 0)?;
+        let counts = Tensor::zeros(k, DType::F32, data.device())?
+            .index_add(&assignment, &ones, 0)?
+            .unsqueeze(1)?;
+        let means = sums.broadcast_div(&counts.clamp(1.0, f64::MAX)?)?;
+        let empty = counts.eq(0.0)?.broadcast_as((k, dim))?;
+        centroids = empty.where_cond(&centroids, &means)?;
+    }
+    Ok(centroids)
+}
+
+impl IvfPqIndex {
+    /*
+    Trains an index on a sample of `vectors` (`dim` floats each, vector i being id i) and
+    encodes all of them.
+
+    Vectors are expected L2-normalized, so similarity is cosine similarity.
+    */
+    pub fn build(vectors: &[f32], dim: usize, params: IvfPqParams) -> anyhow::Result<Self> {
+        ensure!(dim > 0, "vectors must have at least one dimension");
+        ensure!(
+            vectors.len().is_multiple_of(dim),
+            "{} floats do not split into vectors of {} dimensions",
+            vectors.len(),
+            dim
+        );
+        let len = vectors.len() / dim;
+        ensure!(len > 0, "an IVF-PQ index needs vectors to train on");
+        ensure!(
+            len <= u32::MAX as usize,
+            "an index holds at most {} vectors",
+            u32::MAX
+        );
+        ensure!(
+            (1..=len).contains(&params.nlist),
+            "nlist must be between 1 and the number of vectors ({}), got {}",
+            len,
+            params.nlist
+        );
+        let m = params.num_subquantizers;
+        ensure!(
+            m > 0 && dim.is_multiple_of(m),
+            "num_subquantizers must divide the dimension {}, got {}",
+            dim,
+            m
+        );
+        let subvector_dim = dim / m;
+        let device = Device::Cpu;
+        let mut rng = StdRng::seed_from_u64(params.seed);
+
+        // Train on a sample
+        let mut sample: Vec<usize> = (0..len).collect();
+        sample.shuffle(&mut rng);
+        sample.truncate(params.max_training_vectors.max(params.nlist));
+        let training: Vec<f32> = sample
+            .iter()
+            .flat_map(|&row| &vectors[row * dim..(row + 1) * dim])
+            .copied()
+            .collect();
+        let training = Tensor::from_vec(training, (sample.len(), dim), &device)?;
+        let centroids = kmeans(&training, params.nlist, params.iterations, &mut rng)?;
+        let lists = Tensor::new(assign(&training, &centroids)?.as_slice(), &device)?;
+        let residuals = (&training - centroids.index_select(&lists, 0)?)?;
+        let codewords = MAX_CODEWORDS.min(sample.len());
+        let codebooks = (0..m)
+            .map(|j| {
+                let subvectors = residuals
+                    .narrow(1, j * subvector_dim, subvector_dim)?
+                    .contiguous()?;
+                kmeans(&subvectors, codewords, params.iterations, &mut rng)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Encode every vector
+        let mut inverted_lists: Vec<InvertedList> = (0..params.nlist)
+            .map(|_| InvertedList {
+                ids: Vec::new(),
+                codes: Vec::new(),
+            })
+            .collect();
+        for start in (0..len).step_by(ASSIGN_BATCH) {
+            let rows = ASSIGN_BATCH.min(len - start);
+            let batch = Tensor::from_slice(
+                &vectors[start * dim..(start + rows) * dim],
+                (rows, dim),
+                &device,
+            )?;
+            let list_ids = assign(&batch, &centroids)?;
+            let residuals = (&batch
+                - centroids.index_select(&Tensor::new(list_ids.as_slice(), &device)?, 0)?)?;
+            let codes = codebooks
+                .iter()
+                .enumerate()
+                .map(|(j, codebook)| {
+                    let subvectors = residuals
+                        .narrow(1, j * subvector_dim, subvector_dim)?
+                        .contiguous()?;
+                    assign(&subvectors, codebook)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            for (row, &list) in list_ids.iter().enumerate() {
+                let list = &mut inverted_lists[list as usize];
+                list.ids.push((start + row) as u32);
+                list.codes
+                    .extend(codes.iter().map(|codes| codes[row] as u8));
+            }
+        }
+
+        let centroids = centroids.flatten_all()?.to_vec1::<f32>()?;
+        let codebooks = Tensor::stack(&codebooks, 0)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        Ok(Self::new(
+            dim,
+            m,
+            codewords,
+            centroids,
+            codebooks,
+            inverted_lists,
+        ))
+    }
+
+    fn new(
+        dim: usize,
+        num_subquantizers: usize,
+        codewords: usize,
+        centroids: Vec<f32>,
+        codebooks: Vec<f32>,
+        lists: Vec<InvertedList>,
+    ) -> Self {
+        let centroid_half_norms = centroids
+            .chunks_exact(dim)
+            .map(|centroid| dot(centroid, centroid) * 0.5)
+            .collect();
+        Self {
+            dim,
+            num_subquantizers,
+            codewords,
+            len: lists.iter().map(|list| list.ids.len()).sum(),
+            centroids,
+            centroid_half_norms,
+            codebooks,
+            lists,
+            vectors: None,
+        }
+    }
+
+    // Re-rank candidates against `vectors`, whose row i is the full-precision vector i
+    pub fn with_vectors(mut self, vectors: MappedVectors) -> anyhow::Result<Self> {
+        ensure!(
+            vectors.len() == self.len && vectors.dim() == self.dim,
+            "vectors file holds {} vectors of {} dimensions, the index {} of {}",
+            vectors.len(),
+            vectors.dim(),
+            self.len,
+            self.dim
+        );
+        self.vectors = Some(vectors);
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /*
+    The `k` vectors most similar to `query`, as (id, similarity) pairs, best first.
+
+    Vectors of the `nprobe` lists whose centroids are nearest to the query are scored from their
+    codes; the best `rerank` of them (at least `k`) are scored again at full precision when the
+    index has its vectors, otherwise the estimates are returned. With `allowed` (one flag per
+    vector) other vectors are skipped, and further lists are probed until `k` are found.
+    */
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        nprobe: usize,
+        rerank: usize,
+        allowed: Option<&[bool]>,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        ensure!(
+            query.len() == self.dim,
+            "query has {} dimensions, the index {}",
+            query.len(),
+            self.dim
+        );
+        if let Some(allowed) = allowed {
+            ensure!(
+                allowed.len() == self.len,
+                "filter has {} flags for {} vectors",
+                allowed.len(),
+                self.len
+            );
+        }
+        // Every list, nearest first; only the first `nprobe` are scanned unless they hold fewer
+        // than `k` vectors
+        let probed = top_k(
+            self.centroids
+                .chunks_exact(self.dim)
+                .zip(&self.centroid_half_norms)
+                .map(|(centroid, half_norm)| dot(centroid, query) - half_norm)
+                .enumerate(),
+            self.lists.len(),
+        );
+
+        // Similarity of each subvector of the query to each codeword, shared by all lists
+        let subvector_dim = self.dim / self.num_subquantizers;
+        let table: Vec<f32> = self
+            .codebooks
+            .chunks_exact(subvector_dim)
+            .enumerate()
+            .map(|(codeword, codeword_vector)| {
+                let j = codeword / self.codewords;
+                dot(
+                    &query[j * subvector_dim..(j + 1) * subvector_dim],
+                    codeword_vector,
+                )
+            })
+            .collect();
+
+        let mut estimates = Vec::new();
+        for (probe, &(list, _)) in probed.iter().enumerate() {
+            if probe >= nprobe && estimates.len() >= k {
+                break;
+            }
+            let centroid = &self.centroids[list * self.dim..(list + 1) * self.dim];
+            let base = dot(centroid, query);
+            let list = &self.lists[list];
+            let scored = list
+                .ids
+                .iter()
+                .zip(list.codes.chunks_exact(self.num_subquantizers))
+                .filter(|(&id, _)| allowed.is_none_or(|allowed| allowed[id as usize]))
+                .map(|(&id, codes)| {
+                    let residual: f32 = codes
+                        .iter()
+                        .enumerate()
+                        .map(|(j, &code)| table[j * self.codewords + code as usize])
+                        .sum();
+                    (id as usize, base + residual)
+                });
+            estimates.extend(scored);
+        }
+        let mut candidates = top_k(estimates, rerank.max(k));
+        match &self.vectors {
+            Some(vectors) => Ok(top_k(
+                candidates
+                    .into_iter()
+                    .map(|(id, _)| (id, vectors.dot(id, query))),
+                k,
+            )),
+            None => {
+                candidates.truncate(k);
+                Ok(candidates)
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut bytes = Vec::with_capacity(
+            HEADER_BYTES
+                + (self.centroids.len() + self.codebooks.len()) * 4
+                + self.lists.len() * 8
+                + self.len * (4 + self.num_subquantizers),
+        );
+        bytes.extend_from_slice(MAGIC);
+        for value in [
+            self.dim,
+            self.lists.len(),
+            self.num_subquantizers,
+            self.codewords,
+        ] {
+            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.len as u64).to_le_bytes());
+        for value in self.centroids.iter().chain(&self.codebooks) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for list in &self.lists {
+            bytes.extend_from_slice(&(list.ids.len() as u64).to_le_bytes());
+            for id in &list.ids {
+                bytes.extend_from_slice(&id.to_le_bytes());
+            }
+            bytes.extend_from_slice(&list.codes);
+        }
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    // Reads an index written by `save`, checking that every id and code is in range
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        ensure!(
+            bytes.len() >= HEADER_BYTES && &bytes[..8] == MAGIC,
+            "not an IVF-PQ index file"
+        );
+        let mut reader = Reader {
+            bytes: &bytes,
+            at: 8,
+        };
+        let dim = reader.u32()?;
+        let nlist = reader.u32()?;
+        let m = reader.u32()?;
+        let codewords = reader.u32()?;
+        let len = reader.u64()?;
+        ensure!(
+            dim > 0 && nlist > 0 && m > 0 && dim.is_multiple_of(m),
+            "corrupt IVF-PQ header"
+        );
+        ensure!(
+            (1..=MAX_CODEWORDS).contains(&codewords),
+            "corrupt IVF-PQ header"
+        );
+        let centroids = reader.f32s(nlist.saturating_mul(dim))?;
+        let codebooks = reader.f32s(codewords.saturating_mul(dim))?;
+        let mut lists = Vec::with_capacity(nlist);
+        for _ in 0..nlist {
+            let list_len = reader.u64()?;
+            let ids: Vec<u32> = reader
+                .take(list_len.saturating_mul(4))?
+                .chunks_exact(4)
+                .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+                .collect();
+            let codes = reader.take(list_len.saturating_mul(m))?.to_vec();
+            ensure!(
+                ids.iter().all(|&id| (id as usize) < len)
+                    && codes.iter().all(|&code| (code as usize) < codewords),
+                "corrupt IVF-PQ list"
+            );
+            lists.push(InvertedList { ids, codes });
+        }
+        ensure!(reader.at == bytes.len(), "corrupt IVF-PQ index file");
+        let index = Self::new(dim, m, codewords, centroids, codebooks, lists);
+        ensure!(index.len == len, "corrupt IVF-PQ lists");
+        Ok(index)
+    }
+}
+
+// Little-endian values read in order from a byte buffer
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .at
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len());
+        let Some(end) = end else {
+            anyhow::bail!("truncated IVF-PQ index file");
+        };
+        let bytes = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> anyhow::Result<usize> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()) as usize)
+    }
+
+    fn f32s(&mut self, len: usize) -> anyhow::Result<Vec<f32>> {
+        Ok(self
+            .take(len.saturating_mul(4))?
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::clustered_vectors;
+
+    const LEN: usize = 2000;
+    const DIM: usize = 32;
+    const K: usize = 10;
+    const KEY: &str = "vectors";
+
+    // Fraction of the exact `K` nearest neighbours of each query that `index` finds
+    fn recall(index: &IvfPqIndex, vectors: &[f32], queries: &[f32], rerank: usize) -> f64 {
+        let mut hits = 0;
+        for query in queries.chunks_exact(DIM) {
+            let exact: Vec<usize> = top_k(
+                vectors
+                    .chunks_exact(DIM)
+                    .map(|vector| dot(vector, query))
+                    .enumerate(),
+                K,
+            )
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+            let found = index
+                .search(query, K, DEFAULT_NPROBE, rerank, None)
+                .unwrap();
+            assert_eq!(found.len(), K);
+            hits += found.iter().filter(|(id, _)| exact.contains(id)).count();
+        }
+        hits as f64 / (queries.len() / DIM * K) as f64
+    }
+
+    #[test]
+    fn reranking_at_the_defaults_finds_most_exact_neighbours() {
+        // Queries are held-out vectors of the same clusters
+        let mut vectors = clustered_vectors(LEN + 100, DIM, 20, 4);
+        let queries = vectors.split_off(LEN * DIM);
+        let dir = tempfile::tempdir().unwrap();
+        let vectors_path = dir.path().join("vectors.safetensors");
+        Tensor::from_slice(&vectors, (LEN, DIM), &Device::Cpu)
+            .unwrap()
+            .save_safetensors(KEY, &vectors_path)
+            .unwrap();
+        let index_path = dir.path().join("index.ivfpq");
+        IvfPqIndex::build(&vectors, DIM, IvfPqParams::for_size(LEN, DIM))
+            .unwrap()
+            .save(&index_path)
+            .unwrap();
+
+        let codes_only = IvfPqIndex::open(&index_path).unwrap();
+        let reranked = IvfPqIndex::open(&index_path)
+            .unwrap()
+            .with_vectors(MappedVectors::open(&vectors_path, KEY).unwrap())
+            .unwrap();
+        let estimated = recall(&codes_only, &vectors, &queries, K);
+        let rerank = recall(&reranked, &vectors, &queries, DEFAULT_RERANK_FACTOR * K);
+        assert!(rerank >= 0.95, "recall@{} {}", K, rerank);
+        assert!(rerank > estimated);
+    }
+}
@@ -0,0 +1,334 @@
+// Nearest-neighbour search over embedding vectors
+use anyhow::ensure;
+use memmap2::Mmap;
+use safetensors::{Dtype, SafeTensors};
+use serde::Deserialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+pub mod bm25;
+pub mod corpus;
+pub mod filter;
+pub mod hnsw;
+pub mod ivfpq;
+pub mod store;
+pub mod wal;
+
+use hnsw::HnswIndex;
+use ivfpq::IvfPqIndex;
+
+// Rank offset of reciprocal rank fusion; 60 is the value of the original paper, and damps the
+// weight of the first few ranks
+const RRF_K: f32 = 60.0;
+
+/// Which index similarity queries search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexKind {
+    /// Every embedding, scored exactly.
+    Exact,
+    /// HNSW graph; fast with high recall, keeps full vectors.
+    Hnsw,
+    /// IVF-PQ codes; compact, re-ranked against full vectors read from disk.
+    IvfPq,
+}
+
+/// How similarity queries rank chunks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Embedding similarity.
+    #[default]
+    Semantic,
+    /// BM25 over the chunk texts; finds exact terms such as tickers and names.
+    Lexical,
+    /// Both rankings, fused by reciprocal rank.
+    Hybrid,
+}
+
+/// Per-query accuracy knobs of the approximate indexes; unset fields take the index defaults.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct SearchParams {
+    /// HNSW candidates kept while searching.
+    #[serde(default)]
+    pub ef_search: Option<usize>,
+    /// IVF lists probed.
+    #[serde(default)]
+    pub nprobe: Option<usize>,
+    /// IVF-PQ candidates re-ranked at full precision.
+    #[serde(default)]
+    pub rerank: Option<usize>,
+}
+
+impl SearchParams {
+    // These params, with the fields they leave unset taken from `defaults`
+    pub fn or(self, defaults: &SearchParams) -> Self {
+        Self {
+            ef_search: self.ef_search.or(defaults.ef_search),
+            nprobe: self.nprobe.or(defaults.nprobe),
+            rerank: self.rerank.or(defaults.rerank),
+        }
+    }
+}
+
+/// An approximate nearest-neighbour index.
+pub enum VectorIndex {
+    Hnsw(HnswIndex),
+    IvfPq(IvfPqIndex),
+}
+
+impl VectorIndex {
+    pub fn len(&self) -> usize {
+        match self {
+            VectorIndex::Hnsw(index) => index.len(),
+            VectorIndex::IvfPq(index) => index.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dim(&self) -> usize {
+        match self {
+            VectorIndex::Hnsw(index) => index.dim(),
+            VectorIndex::IvfPq(index) => index.dim(),
+        }
+    }
+
+    // The `k` vectors most similar to `query`, as (id, similarity) pairs, best first; only
+    // vectors flagged in `allowed`, when given
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        params: &SearchParams,
+        allowed: Option<&[bool]>,
+    ) -> anyhow::Result<Vec<(usize, f32)>> {
+        match self {
+            VectorIndex::Hnsw(index) => index.search(
+                query,
+                k,
+                params.ef_search.unwrap_or(hnsw::DEFAULT_EF_SEARCH),
+                allowed,
+            ),
+            VectorIndex::IvfPq(index) => index.search(
+                query,
+                k,
+                params.nprobe.unwrap_or(ivfpq::DEFAULT_NPROBE),
+                params.rerank.unwrap_or(k * ivfpq::DEFAULT_RERANK_FACTOR),
+                allowed,
+            ),
+        }
+    }
+}
+
+/// Rows of an f32 matrix read in place from a memory-mapped safetensors file.
+pub struct MappedVectors {
+    mmap: Mmap,
+    data: Range<usize>,
+    len: usize,
+    dim: usize,
+}
+
+impl MappedVectors {
+    // Maps the [len, dim] f32 tensor `key` of the safetensors file at `path`
+    pub fn open(path: impl AsRef<Path>, key: &str) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (header_len, metadata) = SafeTensors::read_metadata(&mmap)?;
+        let tensors = metadata.tensors();
+        let Some(info) = tensors.get(key) else {
+            anyhow::bail!("no tensor {:?} in the vectors file", key);
+        };
+        ensure!(
+            info.dtype == Dtype::F32 && info.shape.len() == 2,
+            "tensor {:?} is not an f32 matrix",
+            key
+        );
+        let (len, dim) = (info.shape[0], info.shape[1]);
+        let (start, end) = info.data_offsets;
+        let data = 8 + header_len + start..8 + header_len + end;
+        ensure!(
+            data.len() == len * dim * 4 && data.end <= mmap.len(),
+            "corrupt vectors file"
+        );
+        Ok(Self {
+            mmap,
+            data,
+            len,
+            dim,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    // Inner product of row `row` with `query`
+    pub fn dot(&self, row: usize, query: &[f32]) -> f32 {
+        self.values(row)
+            .zip(query)
+            .map(|(value, q)| value * q)
+            .sum()
+    }
+
+    pub fn row(&self, row: usize) -> Vec<f32> {
+        self.values(row).collect()
+    }
+
+    fn values(&self, row: usize) -> impl Iterator<Item = f32> + '_ {
+        let start = self.data.start + row * self.dim * 4;
+        self.mmap[start..start + self.dim * 4]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+// A score ordered with `f32::total_cmp`; equal scores rank the lower index first
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Scored {
+    pub score: f32,
+    pub index: usize,
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+/*
+The `k` highest (index, score) pairs of `scores`, best first.
+
+Keeps a min-heap of the best `k` seen, so selecting from n scores takes O(n log k) instead of a
+full sort. NaN scores are skipped.
+*/
+pub fn top_k(scores: impl IntoIterator<Item = (usize, f32)>, k: usize) -> Vec<(usize, f32)> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut heap: BinaryHeap<Reverse<Scored>> = BinaryHeap::with_capacity(k + 1);
+    for (index, score) in scores {
+        if score.is_nan() {
+            continue;
+        }
+        let scored = Scored { score, index };
+        if heap.len() < k {
+            heap.push(Reverse(scored));
+        } else if let Some(mut worst) = heap.peek_mut() {
+            if scored > worst.0 {
+                *worst = Reverse(scored);
+            }
+        }
+    }
+    // Ascending order of `Reverse` is descending order of score
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(scored)| (scored.index, scored.score))
+        .collect()
+}
+
+/*
+Fuses rankings of the same rows into one, best first, keeping the best `k`.
+
+Reciprocal rank fusion scores a row by the sum of 1 / (RRF_K + rank) over the rankings it appears
+in, ranks counting from 1. Only ranks matter, so scores on different scales (cosine similarity,
+BM25) combine without normalization.
+*/
+pub fn reciprocal_rank_fusion(rankings: &[Vec<(usize, f32)>], k: usize) -> Vec<(usize, f32)> {
+    let mut scores: HashMap<usize, f32> = HashMap::new();
+    for ranking in rankings {
+        for (rank, &(row, _)) in ranking.iter().enumerate() {
+            *scores.entry(row).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    top_k(scores, k)
+}
+
+// Inner product; the cosine similarity of L2-normalized vectors
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+// Normalized vectors scattered around `clusters` random centers, like topics of a corpus
+#[cfg(test)]
+pub(crate) fn clustered_vectors(len: usize, dim: usize, clusters: usize, seed: u64) -> Vec<f32> {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let centers: Vec<Vec<f32>> = (0..clusters)
+        .map(|_| (0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect())
+        .collect();
+    (0..len)
+        .flat_map(|_| {
+            let center = &centers[rng.gen_range(0..clusters)];
+            let vector: Vec<f32> = center
+                .iter()
+                .map(|value| value + rng.gen_range(-0.5..0.5))
+                .collect();
+            let norm = dot(&vector, &vector).sqrt();
+            vector.into_iter().map(move |value| value / norm)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(results: &[(usize, f32)]) -> Vec<usize> {
+        results.iter().map(|&(row, _)| row).collect()
+    }
+
+    #[test]
+    fn top_k_keeps_the_best_scores_in_order() {
+        let scores = [(0, 0.1), (1, 0.9), (2, f32::NAN), (3, 0.5), (4, 0.9)];
+        assert_eq!(rows(&top_k(scores, 3)), [1, 4, 3]);
+        assert_eq!(rows(&top_k(scores, 10)), [1, 4, 3, 0]);
+        assert!(top_k(scores, 0).is_empty());
+    }
+
+    #[test]
+    fn fusion_ranks_rows_found_by_both_rankings_first() {
+        let semantic = vec![(1, 0.9), (2, 0.8), (3, 0.7)];
+        let lexical = vec![(4, 12.0), (3, 9.0), (1, 1.0)];
+        let fused = reciprocal_rank_fusion(&[semantic, lexical], 10);
+        // 1 is first and third, 3 third and second; then the rows only one ranking found
+        assert_eq!(rows(&fused), [1, 3, 4, 2]);
+        let first = 1.0 / (RRF_K + 1.0) + 1.0 / (RRF_K + 3.0);
+        assert!((fused[0].1 - first).abs() < 1e-6);
+        assert_eq!(
+            rows(&reciprocal_rank_fusion(&[vec![(7, 1.0)], vec![]], 10)),
+            [7]
+        );
+    }
+}
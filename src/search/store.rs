@@ -0,0 +1,540 @@
+/*
+On-disk search indexes.
+
+An index is a directory holding the documents with their ids and metadata, their chunks, one
+embedding per chunk, a BM25 index of the chunk texts and, when built, approximate indexes over
+the embeddings. `manifest.json` records the format version, the embedding model the vectors come
+from (id, revision, pooling, dimension), the counts and every file with its size and CRC-32. The
+directory is written under a temporary name and renamed into place, and opening it checks every
+file against the manifest, so texts and vectors from different runs are never served together.
+*/
+use super::bm25::Bm25Index;
+use super::hnsw::HnswIndex;
+use super::ivfpq::IvfPqIndex;
+use crate::chunking::{Chunk, ChunkStrategy};
+use crate::models::bert::{BertInferenceModel, Pooling};
+use anyhow::{bail, ensure};
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+use candle::Tensor;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const FORMAT: &str = "inference-server-index";
+const FORMAT_VERSION: u32 = 2;
+const MANIFEST_FILE: &str = "manifest.json";
+const DOCUMENTS_FILE: &str = "documents.bin";
+const METADATA_FILE: &str = "metadata.bin";
+const CHUNKS_FILE: &str = "chunks.bin";
+pub const EMBEDDINGS_FILE: &str = "embeddings.safetensors";
+pub const EMBEDDINGS_KEY: &str = "embeddings";
+const BM25_FILE: &str = "bm25.bin";
+pub const HNSW_FILE: &str = "hnsw.bin";
+pub const IVFPQ_FILE: &str = "ivfpq.bin";
+
+/// The model embeddings were computed with; only embeddings of the same model are comparable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmbeddingModel {
+    pub model_id: String,
+    pub revision: String,
+    pub pooling: Pooling,
+    pub dimension: usize,
+}
+
+impl EmbeddingModel {
+    pub fn of(model: &BertInferenceModel) -> Self {
+        Self {
+            model_id: model.model_id.clone(),
+            revision: model.model_revision.clone(),
+            pooling: model.pooling(),
+            dimension: model.hidden_size(),
+        }
+    }
+}
+
+/// Id and metadata of a document, kept next to its text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentMeta {
+    // Unique within an index, and stable across rebuilds from the same source
+    pub id: String,
+    pub metadata: Map<String, Value>,
+}
+
+// Metadata is arbitrary JSON, which bincode cannot decode without a schema, so it is stored as
+// JSON text
+impl Encode for DocumentMeta {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.id.encode(encoder)?;
+        serde_json::to_string(&self.metadata)
+            .map_err(|e| EncodeError::OtherString(e.to_string()))?
+            .encode(encoder)
+    }
+}
+
+impl Decode for DocumentMeta {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let id = String::decode(decoder)?;
+        let metadata = serde_json::from_str(&String::decode(decoder)?)
+            .map_err(|e| DecodeError::OtherString(e.to_string()))?;
+        Ok(Self { id, metadata })
+    }
+}
+
+bincode::impl_borrow_decode!(DocumentMeta);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexFile {
+    pub name: String,
+    pub bytes: u64,
+    pub crc32: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub format_version: u32,
+    pub model: EmbeddingModel,
+    pub documents: usize,
+    // Chunks, and so embeddings
+    pub count: usize,
+    pub chunking: Option<ChunkStrategy>,
+    pub files: Vec<IndexFile>,
+}
+
+// Size and CRC-32 of the file at `path`
+fn checksum(path: &Path) -> anyhow::Result<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 1 << 20];
+    let mut bytes = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok((bytes, hasher.finalize()));
+        }
+        hasher.update(&buffer[..read]);
+        bytes += read as u64;
+    }
+}
+
+/// Writes an index directory; nothing is visible at its path until `finish`.
+pub struct IndexWriter {
+    dir: PathBuf,
+    partial: PathBuf,
+    files: Vec<IndexFile>,
+    documents: Option<usize>,
+    chunks: Option<usize>,
+    embeddings: Option<usize>,
+}
+
+impl IndexWriter {
+    pub fn create(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let partial = dir.with_extension("partial");
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        std::fs::create_dir_all(&partial)?;
+        Ok(Self {
+            dir,
+            partial,
+            files: Vec::new(),
+            documents: None,
+            chunks: None,
+            embeddings: None,
+        })
+    }
+
+    // Directory the files are written to until `finish`
+    pub fn path(&self) -> &Path {
+        &self.partial
+    }
+
+    // Document texts, and the id and metadata of each
+    pub fn write_documents(
+        &mut self,
+        documents: &[String],
+        metadata: &[DocumentMeta],
+    ) -> anyhow::Result<()> {
+        ensure!(
+            documents.len() == metadata.len(),
+            "metadata for {} of {} documents",
+            metadata.len(),
+            documents.len()
+        );
+        let mut ids = HashSet::new();
+        if let Some(meta) = metadata.iter().find(|meta| !ids.insert(&meta.id)) {
+            bail!("duplicate document id {:?}", meta.id);
+        }
+        self.write_bincode(DOCUMENTS_FILE, documents)?;
+        self.write_bincode(METADATA_FILE, metadata)?;
+        self.documents = Some(documents.len());
+        Ok(())
+    }
+
+    pub fn write_chunks(&mut self, chunks: &[Chunk]) -> anyhow::Result<()> {
+        self.write_bincode(CHUNKS_FILE, chunks)?;
+        self.chunks = Some(chunks.len());
+        Ok(())
+    }
+
+    // One row per chunk, in chunk order
+    pub fn write_embeddings(&mut self, embeddings: &Tensor) -> anyhow::Result<()> {
+        embeddings.save_safetensors(EMBEDDINGS_KEY, self.partial.join(EMBEDDINGS_FILE))?;
+        self.record(EMBEDDINGS_FILE)?;
+        self.embeddings = Some(embeddings.dim(0)?);
+        Ok(())
+    }
+
+    // Lexical index of the chunk texts, one row per chunk
+    pub fn write_bm25(&mut self, index: &Bm25Index) -> anyhow::Result<()> {
+        self.write_bincode(BM25_FILE, index)
+    }
+
+    pub fn write_hnsw(&mut self, index: &HnswIndex) -> anyhow::Result<()> {
+        index.save(self.partial.join(HNSW_FILE))?;
+        self.record(HNSW_FILE)
+    }
+
+    pub fn write_ivfpq(&mut self, index: &IvfPqIndex) -> anyhow::Result<()> {
+        index.save(self.partial.join(IVFPQ_FILE))?;
+        self.record(IVFPQ_FILE)
+    }
+
+    /*
+    Writes the manifest and moves the directory into place, replacing any previous index.
+
+    Documents, chunks and embeddings must all have been written, with one embedding per chunk.
+    */
+    pub fn finish(
+        self,
+        model: EmbeddingModel,
+        chunking: Option<ChunkStrategy>,
+    ) -> anyhow::Result<Manifest> {
+        let (Some(documents), Some(chunks), Some(embeddings)) =
+            (self.documents, self.chunks, self.embeddings)
+        else {
+            bail!("an index needs documents, chunks and embeddings");
+        };
+        ensure!(
+            chunks == embeddings,
+            "{} embeddings for {} chunks",
+            embeddings,
+            chunks
+        );
+        let manifest = Manifest {
+            format: FORMAT.to_string(),
+            format_version: FORMAT_VERSION,
+            model,
+            documents,
+            count: chunks,
+            chunking,
+            files: self.files,
+        };
+        std::fs::write(
+            self.partial.join(MANIFEST_FILE),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+
+        if self.dir.exists() {
+            let previous = self.dir.with_extension("previous");
+            if previous.exists() {
+                std::fs::remove_dir_all(&previous)?;
+            }
+            std::fs::rename(&self.dir, &previous)?;
+            std::fs::rename(&self.partial, &self.dir)?;
+            std::fs::remove_dir_all(&previous)?;
+        } else {
+            std::fs::rename(&self.partial, &self.dir)?;
+        }
+        Ok(manifest)
+    }
+
+    fn write_bincode<T: bincode::Encode + ?Sized>(
+        &mut self,
+        name: &str,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let mut file = File::create(self.partial.join(name))?;
+        bincode::encode_into_std_write(value, &mut file, bincode::config::standard())?;
+        self.record(name)
+    }
+
+    fn record(&mut self, name: &str) -> anyhow::Result<()> {
+        let (bytes, crc32) = checksum(&self.partial.join(name))?;
+        self.files.retain(|file| file.name != name);
+        self.files.push(IndexFile {
+            name: name.to_string(),
+            bytes,
+            crc32,
+        });
+        Ok(())
+    }
+}
+
+/// An index directory whose files match its manifest.
+pub struct IndexDir {
+    pub path: PathBuf,
+    pub manifest: Manifest,
+}
+
+impl IndexDir {
+    // Reads the manifest and checks the size and CRC-32 of every file it lists
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        // `finish` was interrupted between moving the old index aside and the new one in
+        let previous = path.with_extension("previous");
+        if !path.exists() && previous.exists() {
+            std::fs::rename(&previous, &path)?;
+        }
+        let manifest_path = path.join(MANIFEST_FILE);
+        let manifest: Manifest = match std::fs::read_to_string(&manifest_path) {
+            Ok(manifest) => serde_json::from_str(&manifest)?,
+            Err(e) => bail!("cannot read {}: {}", manifest_path.display(), e),
+        };
+        ensure!(
+            manifest.format == FORMAT && manifest.format_version == FORMAT_VERSION,
+            "{} is a {:?} version {} index, expected {:?} version {}",
+            path.display(),
+            manifest.format,
+            manifest.format_version,
+            FORMAT,
+            FORMAT_VERSION
+        );
+        for required in [DOCUMENTS_FILE, METADATA_FILE, CHUNKS_FILE, EMBEDDINGS_FILE] {
+            ensure!(
+                manifest.files.iter().any(|file| file.name == required),
+                "index {} has no {}",
+                path.display(),
+                required
+            );
+        }
+        for file in &manifest.files {
+            let (bytes, crc32) = checksum(&path.join(&file.name))?;
+            ensure!(
+                bytes == file.bytes && crc32 == file.crc32,
+                "{} in index {} does not match its manifest",
+                file.name,
+                path.display()
+            );
+        }
+        Ok(Self { path, manifest })
+    }
+
+    // Path of `name` when the index has that file
+    pub fn file(&self, name: &str) -> Option<PathBuf> {
+        self.manifest
+            .files
+            .iter()
+            .any(|file| file.name == name)
+            .then(|| self.path.join(name))
+    }
+
+    // Checks that the index was built with `model`, so query embeddings match its vectors
+    pub fn check_model(&self, model: &EmbeddingModel) -> anyhow::Result<()> {
+        ensure!(
+            self.manifest.model == *model,
+            "index {} was built with {:?}, but the loaded model is {:?}",
+            self.path.display(),
+            self.manifest.model,
+            model
+        );
+        Ok(())
+    }
+
+    pub fn documents(&self) -> anyhow::Result<Vec<String>> {
+        let documents: Vec<String> = self.read_bincode(DOCUMENTS_FILE)?;
+        ensure!(
+            documents.len() == self.manifest.documents,
+            "index {} holds {} documents, its manifest {}",
+            self.path.display(),
+            documents.len(),
+            self.manifest.documents
+        );
+        Ok(documents)
+    }
+
+    // Id and metadata of each document, in document order
+    pub fn metadata(&self) -> anyhow::Result<Vec<DocumentMeta>> {
+        let metadata: Vec<DocumentMeta> = self.read_bincode(METADATA_FILE)?;
+        ensure!(
+            metadata.len() == self.manifest.documents,
+            "index {} holds metadata for {} documents, its manifest {}",
+            self.path.display(),
+            metadata.len(),
+            self.manifest.documents
+        );
+        Ok(metadata)
+    }
+
+    // Chunks, each checked to lie within one of `documents`
+    pub fn chunks(&self, documents: &[String]) -> anyhow::Result<Vec<Chunk>> {
+        let chunks: Vec<Chunk> = self.read_bincode(CHUNKS_FILE)?;
+        ensure!(
+            chunks.len() == self.manifest.count,
+            "index {} holds {} chunks, its manifest {}",
+            self.path.display(),
+            chunks.len(),
+            self.manifest.count
+        );
+        if let Some(chunk) = chunks.iter().find(|chunk| chunk.text(documents).is_none()) {
+            bail!(
+                "index {} has a chunk outside its documents: {:?}",
+                self.path.display(),
+                chunk
+            );
+        }
+        Ok(chunks)
+    }
+
+    // The lexical index, unless the index was written without one
+    pub fn bm25(&self) -> anyhow::Result<Option<Bm25Index>> {
+        if self.file(BM25_FILE).is_none() {
+            return Ok(None);
+        }
+        let bm25: Bm25Index = self.read_bincode(BM25_FILE)?;
+        ensure!(
+            bm25.len() == self.manifest.count,
+            "index {} holds a lexical index of {} rows for {} chunks",
+            self.path.display(),
+            bm25.len(),
+            self.manifest.count
+        );
+        Ok(Some(bm25))
+    }
+
+    fn read_bincode<T: bincode::Decode>(&self, name: &str) -> anyhow::Result<T> {
+        let mut file = File::open(self.path.join(name))?;
+        Ok(bincode::decode_from_std_read(
+            &mut file,
+            bincode::config::standard(),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::whole_documents;
+    use candle::Device;
+
+    fn model() -> EmbeddingModel {
+        EmbeddingModel {
+            model_id: "test-model".to_string(),
+            revision: "main".to_string(),
+            pooling: Pooling::Mean,
+            dimension: 2,
+        }
+    }
+
+    // Writes an index of `texts` with ids "0", "1", ... at `dir`
+    fn write_index(dir: &Path, texts: &[&str]) -> Manifest {
+        let documents: Vec<String> = texts.iter().map(|text| text.to_string()).collect();
+        let metadata: Vec<DocumentMeta> = (0..texts.len())
+            .map(|document| DocumentMeta {
+                id: document.to_string(),
+                metadata: Map::new(),
+            })
+            .collect();
+        let embeddings: Vec<f32> = (0..texts.len()).flat_map(|_| [1.0, 0.0]).collect();
+        let mut writer = IndexWriter::create(dir).unwrap();
+        writer.write_documents(&documents, &metadata).unwrap();
+        writer.write_chunks(&whole_documents(&documents)).unwrap();
+        writer
+            .write_embeddings(
+                &Tensor::from_slice(&embeddings, (texts.len(), 2), &Device::Cpu).unwrap(),
+            )
+            .unwrap();
+        writer.finish(model(), None).unwrap()
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        let manifest = write_index(&dir, &["first", "second"]);
+        assert_eq!((manifest.documents, manifest.count), (2, 2));
+
+        let index = IndexDir::open(&dir).unwrap();
+        index.check_model(&model()).unwrap();
+        let documents = index.documents().unwrap();
+        assert_eq!(documents, ["first", "second"]);
+        assert_eq!(index.metadata().unwrap()[1].id, "1");
+        assert_eq!(index.chunks(&documents).unwrap().len(), 2);
+        assert!(index.bm25().unwrap().is_none());
+        assert!(!dir.with_extension("partial").exists());
+    }
+
+    #[test]
+    fn replaces_an_existing_index() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["old"]);
+        write_index(&dir, &["new", "newer"]);
+
+        let index = IndexDir::open(&dir).unwrap();
+        assert_eq!(index.documents().unwrap(), ["new", "newer"]);
+        assert!(!dir.with_extension("previous").exists());
+    }
+
+    #[test]
+    fn recovers_from_a_swap_interrupted_after_moving_the_old_index_aside() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["old"]);
+        // `finish` renamed the old index to .previous, then crashed before moving the new one in
+        std::fs::rename(&dir, dir.with_extension("previous")).unwrap();
+
+        let index = IndexDir::open(&dir).unwrap();
+        assert_eq!(index.documents().unwrap(), ["old"]);
+        assert!(!dir.with_extension("previous").exists());
+    }
+
+    #[test]
+    fn detects_a_file_that_does_not_match_the_manifest() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["first"]);
+        let path = dir.join(DOCUMENTS_FILE);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let error = IndexDir::open(&dir).err().unwrap();
+        assert!(error.to_string().contains(DOCUMENTS_FILE), "{}", error);
+    }
+
+    #[test]
+    fn rejects_another_model() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("index");
+        write_index(&dir, &["first"]);
+        let index = IndexDir::open(&dir).unwrap();
+        let other = EmbeddingModel {
+            pooling: Pooling::Cls,
+            ..model()
+        };
+        assert!(index.check_model(&other).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let root = tempfile::tempdir().unwrap();
+        let mut writer = IndexWriter::create(root.path().join("index")).unwrap();
+        let meta = DocumentMeta {
+            id: "same".to_string(),
+            metadata: Map::new(),
+        };
+        let documents = ["a".to_string(), "b".to_string()];
+        assert!(writer
+            .write_documents(&documents, &[meta.clone(), meta])
+            .is_err());
+    }
+}
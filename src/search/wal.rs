@@ -0,0 +1,250 @@
+/*
+Write-ahead log of changes to an index.
+
+Each record is framed as its length and CRC-32 (little-endian u32s) followed by its bincode
+encoding, and synced to disk before the change is applied. A crash can leave a partial record at
+the end of the log; replay stops at the first record that is incomplete or fails its checksum,
+and the log is truncated there. A failed append is cut off the same way, so that later records
+follow the intact ones rather than a partial record that would end replay before them.
+*/
+use super::store::DocumentMeta;
+use bincode::{Decode, Encode};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Larger records are taken for corruption rather than read
+const MAX_RECORD_BYTES: usize = 1 << 30;
+
+#[derive(Debug, Clone, Encode, Decode)]
+pub enum WalRecord {
+    /// Adds a document, replacing any with the same id.
+    Upsert {
+        document: DocumentMeta,
+        text: String,
+        // Byte ranges of the chunks of `text`, and their embeddings, one row per chunk
+        chunks: Vec<(usize, usize)>,
+        embeddings: Vec<f32>,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+pub struct WriteAheadLog {
+    file: File,
+    // Bytes of the complete records; appends write from here
+    end: u64,
+    records: usize,
+}
+
+impl WriteAheadLog {
+    // Opens or creates the log at `path`, returning the records it holds
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<(Self, Vec<WalRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some(record) = read_record(&bytes[offset..]) {
+            let (record, len) = record;
+            records.push(record);
+            offset += len;
+        }
+        if offset < bytes.len() {
+            println!(
+                "Discarding {} bytes of incomplete records at the end of {}",
+                bytes.len() - offset,
+                path.as_ref().display()
+            );
+            file.set_len(offset as u64)?;
+            file.sync_data()?;
+        }
+        let log = Self {
+            file,
+            end: offset as u64,
+            records: records.len(),
+        };
+        Ok((log, records))
+    }
+
+    /*
+    Appends `record` and waits until it is on disk.
+
+    The record is written after the last complete one and anything beyond it is cut off, so
+    bytes left by an append that failed partway are overwritten. On failure the log is also
+    truncated back right away, where it can be.
+    */
+    pub fn append(&mut self, record: &WalRecord) -> anyhow::Result<()> {
+        let payload = bincode::encode_to_vec(record, bincode::config::standard())?;
+        let mut frame = Vec::with_capacity(8 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        let end = self.end + frame.len() as u64;
+        let written = self
+            .file
+            .seek(SeekFrom::Start(self.end))
+            .and_then(|_| self.file.write_all(&frame))
+            .and_then(|()| self.file.set_len(end))
+            .and_then(|()| self.file.sync_data());
+        if let Err(e) = written {
+            let _ = self.file.set_len(self.end);
+            return Err(e.into());
+        }
+        self.end = end;
+        self.records += 1;
+        Ok(())
+    }
+
+    // Records appended since the log was created
+    pub fn len(&self) -> usize {
+        self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+}
+
+// The record at the start of `bytes` and its framed length, unless incomplete or corrupt
+fn read_record(bytes: &[u8]) -> Option<(WalRecord, usize)> {
+    let len = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+    let crc32 = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+    if len > MAX_RECORD_BYTES {
+        return None;
+    }
+    let payload = bytes.get(8..8 + len)?;
+    if crc32fast::hash(payload) != crc32 {
+        return None;
+    }
+    let (record, read) = bincode::decode_from_slice(payload, bincode::config::standard()).ok()?;
+    (read == len).then_some((record, 8 + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Map;
+
+    fn upsert(id: &str) -> WalRecord {
+        WalRecord::Upsert {
+            document: DocumentMeta {
+                id: id.to_string(),
+                metadata: Map::new(),
+            },
+            text: format!("text of {}", id),
+            chunks: vec![(0, 4)],
+            embeddings: vec![1.0, 0.0],
+        }
+    }
+
+    fn ids(records: &[WalRecord]) -> Vec<&str> {
+        records
+            .iter()
+            .map(|record| match record {
+                WalRecord::Upsert { document, .. } => document.id.as_str(),
+                WalRecord::Delete { id } => id.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_appended_records_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut log, records) = WriteAheadLog::open(&path).unwrap();
+        assert!(records.is_empty() && log.is_empty());
+        log.append(&upsert("a")).unwrap();
+        log.append(&WalRecord::Delete { id: "b".into() }).unwrap();
+        drop(log);
+
+        let (log, records) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(ids(&records), ["a", "b"]);
+        let WalRecord::Upsert {
+            text, embeddings, ..
+        } = &records[0]
+        else {
+            panic!("expected an upsert, got {:?}", records[0]);
+        };
+        assert_eq!(text, "text of a");
+        assert_eq!(embeddings, &[1.0, 0.0]);
+    }
+
+    #[test]
+    fn truncates_a_torn_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut log, _) = WriteAheadLog::open(&path).unwrap();
+        log.append(&upsert("a")).unwrap();
+        let intact = std::fs::metadata(&path).unwrap().len();
+        log.append(&upsert("b")).unwrap();
+        drop(log);
+        // A crash in the middle of writing the second record
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(std::fs::metadata(&path).unwrap().len() - 3)
+            .unwrap();
+        drop(file);
+
+        let (mut log, records) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(ids(&records), ["a"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact);
+        // Later records follow the intact ones
+        log.append(&upsert("c")).unwrap();
+        drop(log);
+        let (_, records) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(ids(&records), ["a", "c"]);
+    }
+
+    #[test]
+    fn appends_after_a_failed_append_survive_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut log, _) = WriteAheadLog::open(&path).unwrap();
+        log.append(&upsert("a")).unwrap();
+        // What an append failing partway leaves: the start of a frame after the last record
+        let partial = [16, 0, 0, 0, 1, 2, 3, 4, 5, 6];
+        log.file.seek(SeekFrom::End(0)).unwrap();
+        log.file.write_all(&partial).unwrap();
+
+        log.append(&upsert("b")).unwrap();
+        log.append(&WalRecord::Delete { id: "a".into() }).unwrap();
+        drop(log);
+        let (log, records) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(ids(&records), ["a", "b", "a"]);
+        assert_eq!(log.len(), 3);
+    }
+
+    #[test]
+    fn stops_at_a_record_failing_its_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut log, _) = WriteAheadLog::open(&path).unwrap();
+        log.append(&upsert("a")).unwrap();
+        let intact = std::fs::metadata(&path).unwrap().len() as usize;
+        log.append(&upsert("b")).unwrap();
+        drop(log);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (_, records) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(ids(&records), ["a"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, intact);
+    }
+
+    #[test]
+    fn rejects_an_oversized_length() {
+        let mut frame = ((MAX_RECORD_BYTES + 1) as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&[0; 8]);
+        assert!(read_record(&frame).is_none());
+    }
+}
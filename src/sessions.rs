@@ -0,0 +1,490 @@
+// Server-side chat sessions that keep the conversation history and its live KV cache
+use crate::chat::{ChatMessage, ChatTemplate, Role, Tool, ToolCall};
+use crate::constraints::TokenConstraint;
+use crate::models::kv_snapshot;
+use crate::models::llama::{ContextShift, Generation, GenerationState, LlamaInferenceModel};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Snapshot metadata keys holding the session alongside its KV cache
+const MESSAGES_KEY: &str = "session.messages";
+const RENDERED_MESSAGES_KEY: &str = "session.rendered_messages";
+const CONTEXT_SHIFT_KEY: &str = "session.context_shift";
+
+/*
+A conversation plus the decoding state of everything that has already been run through the
+model.
+
+Messages before `rendered_messages` are held in the KV cache of `generation`, so a new turn
+only renders and prefills the messages appended since. When the state is missing (a new
+session, or a previous turn failed half-way) it is rebuilt from the full history, which may
+still start from the prefix cache.
+*/
+pub struct Session {
+    messages: Vec<ChatMessage>,
+    generation: Option<GenerationState>,
+    rendered_messages: usize,
+    context_shift: bool,
+}
+
+impl Session {
+    pub fn new(system_prompt: Option<String>, context_shift: bool) -> Self {
+        Self {
+            messages: system_prompt
+                .map(|content| vec![ChatMessage::new(Role::System, content)])
+                .unwrap_or_default(),
+            generation: None,
+            rendered_messages: 0,
+            context_shift,
+        }
+    }
+
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    pub fn push_message(&mut self, message: ChatMessage) {
+        self.messages.push(message);
+    }
+
+    // Offer `tools` to the model with a system message ahead of the messages pending a reply,
+    // unless the latest system message already offers the same ones
+    pub fn offer_tools(&mut self, tools: &[Tool]) {
+        if let Some((index, message)) = self.tool_offer(tools) {
+            self.messages.insert(index, message);
+        }
+    }
+
+    // The system message `offer_tools` would insert, and where
+    fn tool_offer(&self, tools: &[Tool]) -> Option<(usize, ChatMessage)> {
+        let content = ChatTemplate::render_tools(tools);
+        let offered = self
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::System)
+            .is_some_and(|message| message.content == content);
+        if offered {
+            return None;
+        }
+        // After the last reply, or after the system prompt before the first one
+        let pending_start = match self
+            .messages
+            .iter()
+            .rposition(|message| message.role == Role::Assistant)
+        {
+            Some(reply) => reply + 1,
+            None => self
+                .messages
+                .iter()
+                .take_while(|message| message.role == Role::System)
+                .count(),
+        };
+        Some((
+            pending_start.max(self.rendered_messages),
+            ChatMessage::new(Role::System, content),
+        ))
+    }
+
+    // Whether an assistant message requested the call `tool_call_id`
+    pub fn has_tool_call(&self, tool_call_id: &str) -> bool {
+        self.messages
+            .iter()
+            .flat_map(|message| &message.tool_calls)
+            .any(|call| call.id == tool_call_id)
+    }
+
+    // Whether messages were added since the last generated reply
+    pub fn has_pending_messages(&self) -> bool {
+        self.messages.len() > self.rendered_messages
+    }
+
+    pub fn context_shift(&self) -> bool {
+        self.context_shift
+    }
+
+    // Number of tokens currently held in the session's context window
+    pub fn window_len(&self) -> usize {
+        self.generation
+            .as_ref()
+            .map_or(0, |state| state.tokens().len())
+    }
+
+    /*
+    Tokens the next turn adds to the window: the new messages and the assistant prompt.
+
+    With `tools`, includes the system message `offer_tools` adds, so a turn can be checked
+    before the history changes.
+    */
+    pub fn pending_tokens(
+        &self,
+        model: &LlamaInferenceModel,
+        tools: Option<&[Tool]>,
+    ) -> anyhow::Result<Vec<u32>> {
+        let start = match self.generation {
+            Some(_) => self.rendered_messages,
+            None => 0,
+        };
+        let mut messages = self.messages[start..].to_vec();
+        if let Some((index, message)) = tools.and_then(|tools| self.tool_offer(tools)) {
+            messages.insert(index - start, message);
+        }
+        match self.generation {
+            Some(_) => {
+                model.encode_continuation(&ChatTemplate::render_continuation(&messages, true))
+            }
+            None => model.encode_prompt(&ChatTemplate::render(&messages, true)),
+        }
+    }
+
+    /*
+    Generates the assistant reply to the pending messages and appends it to the history.
+
+    `pending` must come from `pending_tokens`, with the tools offered since. With a
+    `tool_constraint` (see `ToolChoice::matcher`) a reply that is a tool call is recorded as
+    the message's `tool_calls`. On failure the decoding state is dropped so the next turn
+    rebuilds it from the history instead of continuing from a half-updated KV cache.
+    */
+    pub fn generate_reply(
+        &mut self,
+        model: &LlamaInferenceModel,
+        pending: Vec<u32>,
+        max_length: usize,
+        seed: u64,
+        tool_constraint: Option<TokenConstraint>,
+    ) -> anyhow::Result<Generation> {
+        let offers_tools = tool_constraint.is_some();
+        match self.run_turn(model, pending, max_length, seed, tool_constraint) {
+            Ok(generation) => {
+                self.messages
+                    .push(reply_message(&generation.text, offers_tools));
+                self.rendered_messages = self.messages.len();
+                Ok(generation)
+            }
+            Err(e) => {
+                self.generation = None;
+                self.rendered_messages = 0;
+                Err(e)
+            }
+        }
+    }
+
+    fn run_turn(
+        &mut self,
+        model: &LlamaInferenceModel,
+        pending: Vec<u32>,
+        max_length: usize,
+        seed: u64,
+        constraint: Option<TokenConstraint>,
+    ) -> anyhow::Result<Generation> {
+        match self.generation.as_mut() {
+            Some(state) => state.begin_turn(&pending),
+            None => {
+                let context_shift = match self.context_shift {
+                    true => Some(self.system_context_shift(model)?),
+                    false => None,
+                };
+                self.generation = Some(model.start_generation(pending, seed, context_shift)?);
+            }
+        }
+        let Some(state) = self.generation.as_mut() else {
+            anyhow::bail!("session has no decoding state");
+        };
+        // The constraint only applies to this reply
+        state.set_constraint(constraint);
+        let generation = model.generate(state, max_length);
+        state.set_constraint(None);
+        let generation = generation?;
+        state.end_turn(model.eos_token_id());
+        Ok(generation)
+    }
+
+    /*
+    Writes the conversation and its KV cache to a safetensors snapshot at `path`.
+
+    A session that has not generated yet is saved with an empty cache and is rebuilt from
+    its history on the next turn after being restored.
+    */
+    pub fn save(&self, model: &LlamaInferenceModel, path: &Path) -> anyhow::Result<()> {
+        let metadata = HashMap::from([
+            (
+                MESSAGES_KEY.to_string(),
+                serde_json::to_string(&self.messages)?,
+            ),
+            (
+                RENDERED_MESSAGES_KEY.to_string(),
+                self.rendered_messages.to_string(),
+            ),
+            (
+                CONTEXT_SHIFT_KEY.to_string(),
+                self.context_shift.to_string(),
+            ),
+        ]);
+        match &self.generation {
+            Some(state) => model.save_generation(state, path, metadata),
+            None => kv_snapshot::save(
+                path,
+                &[],
+                &model.create_cache()?,
+                &model.model_id,
+                &model.config,
+                metadata,
+            ),
+        }
+    }
+
+    // Restore a session written by `save`, resuming from its saved KV cache
+    pub fn restore(model: &LlamaInferenceModel, path: &Path, seed: u64) -> anyhow::Result<Self> {
+        let (state, metadata) = model.restore_generation(path, seed, None)?;
+        let field = |key: &str| {
+            metadata
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("snapshot {} has no {}", path.display(), key))
+        };
+        let messages: Vec<ChatMessage> = serde_json::from_str(field(MESSAGES_KEY)?)?;
+        let rendered_messages: usize = field(RENDERED_MESSAGES_KEY)?.parse()?;
+        let context_shift: bool = field(CONTEXT_SHIFT_KEY)?.parse()?;
+        if rendered_messages > messages.len() {
+            anyhow::bail!("snapshot {} has an inconsistent history", path.display());
+        }
+
+        let mut session = Self {
+            messages,
+            generation: None,
+            rendered_messages: 0,
+            context_shift,
+        };
+        if !state.tokens().is_empty() {
+            let mut state = state;
+            if context_shift {
+                state.set_context_shift(Some(session.system_context_shift(model)?));
+            }
+            session.generation = Some(state);
+            session.rendered_messages = rendered_messages;
+        }
+        Ok(session)
+    }
+
+    // Slide the window past old turns while always keeping the system prompt
+    fn system_context_shift(&self, model: &LlamaInferenceModel) -> anyhow::Result<ContextShift> {
+        let preserve_prefix = match self.messages.first() {
+            Some(message) if message.role == Role::System => model
+                .encode_prompt(&ChatTemplate::render(&self.messages[..1], false))?
+                .len(),
+            _ => model.bos_token_id().map_or(0, |_| 1),
+        };
+        let context_shift = ContextShift {
+            preserve_prefix,
+            discard: None,
+        };
+        context_shift
+            .validate(model.context_length())
+            .map_err(anyhow::Error::msg)?;
+        Ok(context_shift)
+    }
+}
+
+/*
+History entry for a generated reply. The text is kept exactly as generated, whitespace
+included, so that rebuilding the window from the history renders the same tokens the live KV
+cache holds.
+*/
+fn reply_message(text: &str, offers_tools: bool) -> ChatMessage {
+    match offers_tools.then(|| ToolCall::parse(text)).flatten() {
+        Some(call) => ChatMessage {
+            tool_calls: vec![call],
+            ..ChatMessage::new(Role::Assistant, "")
+        },
+        None => ChatMessage::new(Role::Assistant, text),
+    }
+}
+
+struct SessionEntry {
+    session: Arc<Mutex<Session>>,
+    last_used: Instant,
+}
+
+// Sessions by id, dropped once they have not been used for `ttl`
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, SessionEntry>>,
+    ttl: Duration,
+    max_sessions: usize,
+    // Directory holding `{session_id}.safetensors` snapshots
+    snapshot_dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration, max_sessions: usize, snapshot_dir: PathBuf) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+            max_sessions,
+            snapshot_dir,
+        }
+    }
+
+    // Register a session and return its id, or `None` when the store is full
+    pub fn create(&self, session: Session) -> Option<String> {
+        let session_id = uuid::Uuid::new_v4().to_string();
+        self.insert(&session_id, session).then_some(session_id)
+    }
+
+    // Register a session under a known id (e.g. one restored from a snapshot), replacing any
+    // live session with that id; returns false when the store is full
+    pub fn insert(&self, session_id: &str, session: Session) -> bool {
+        self.evict_expired();
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        if !sessions.contains_key(session_id) && sessions.len() >= self.max_sessions {
+            return false;
+        }
+        sessions.insert(
+            session_id.to_string(),
+            SessionEntry {
+                session: Arc::new(Mutex::new(session)),
+                last_used: Instant::now(),
+            },
+        );
+        true
+    }
+
+    // Snapshot file for a session; ids are UUIDs so they can never escape the directory
+    pub fn snapshot_path(&self, session_id: &str) -> anyhow::Result<PathBuf> {
+        let session_id = uuid::Uuid::parse_str(session_id)
+            .map_err(|_| anyhow::anyhow!("invalid session id {:?}", session_id))?;
+        Ok(self
+            .snapshot_dir
+            .join(format!("{}.safetensors", session_id.hyphenated())))
+    }
+
+    // All live sessions, e.g. to snapshot them before shutting down
+    pub fn entries(&self) -> Vec<(String, Arc<Mutex<Session>>)> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(session_id, entry)| (session_id.clone(), Arc::clone(&entry.session)))
+            .collect()
+    }
+
+    // Look up a live session and refresh its expiry
+    pub fn get(&self, session_id: &str) -> Option<Arc<Mutex<Session>>> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = sessions.get_mut(session_id)?;
+        if entry.last_used.elapsed() > self.ttl {
+            sessions.remove(session_id);
+            return None;
+        }
+        entry.last_used = Instant::now();
+        Some(Arc::clone(&entry.session))
+    }
+
+    pub fn remove(&self, session_id: &str) -> bool {
+        self.sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(session_id)
+            .is_some()
+    }
+
+    // Drop every session idle for longer than the TTL, returning how many were removed
+    pub fn evict_expired(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = sessions.len();
+        sessions.retain(|_, entry| entry.last_used.elapsed() <= self.ttl);
+        before - sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraints::tool_call::{TOOL_CALL_END, TOOL_CALL_START};
+
+    fn tools(names: &[&str]) -> Vec<Tool> {
+        names
+            .iter()
+            .map(|name| {
+                serde_json::from_value(serde_json::json!({
+                    "type": "function",
+                    "function": {"name": name},
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn roles(session: &Session) -> Vec<Role> {
+        session
+            .messages()
+            .iter()
+            .map(|message| message.role)
+            .collect()
+    }
+
+    #[test]
+    fn offers_tools_ahead_of_the_messages_pending_a_reply() {
+        let mut session = Session::new(Some("Be brief.".into()), false);
+        session.push_message(ChatMessage::new(Role::User, "Weather?"));
+        session.offer_tools(&tools(&["get_weather"]));
+        assert_eq!(roles(&session), [Role::System, Role::System, Role::User]);
+        assert_eq!(session.messages()[0].content, "Be brief.");
+        // The same tools are not offered twice
+        session.offer_tools(&tools(&["get_weather"]));
+        assert_eq!(session.messages().len(), 3);
+
+        session.push_message(ChatMessage::new(Role::Assistant, "Where?"));
+        session.push_message(ChatMessage::new(Role::User, "Oslo"));
+        session.offer_tools(&tools(&["get_weather"]));
+        assert_eq!(session.messages().len(), 5);
+        session.offer_tools(&tools(&["get_weather", "get_time"]));
+        assert_eq!(
+            roles(&session),
+            [
+                Role::System,
+                Role::System,
+                Role::User,
+                Role::Assistant,
+                Role::System,
+                Role::User
+            ]
+        );
+        assert!(session.messages()[4].content.contains("get_time"));
+    }
+
+    #[test]
+    fn offers_tools_after_the_messages_already_in_the_cache() {
+        let mut session = Session::new(None, false);
+        session.push_message(ChatMessage::new(Role::User, "Hi"));
+        session.push_message(ChatMessage::new(Role::User, "Weather?"));
+        // As if the first message were already in the KV cache, which cannot change
+        session.rendered_messages = 1;
+        session.offer_tools(&tools(&["get_weather"]));
+        assert_eq!(roles(&session), [Role::User, Role::System, Role::User]);
+    }
+
+    #[test]
+    fn replies_keep_the_whitespace_of_the_generated_tokens() {
+        let reply = reply_message(" Sure.\n", false);
+        assert_eq!(reply.content, " Sure.\n");
+        // The history renders the reply exactly as it was generated after the prompt
+        let messages = [ChatMessage::new(Role::User, "Hi"), reply];
+        assert_eq!(
+            ChatTemplate::render(&messages[..1], true) + " Sure.\n</s>",
+            ChatTemplate::render(&messages, false)
+        );
+    }
+
+    #[test]
+    fn replies_are_tool_calls_only_when_tools_are_offered() {
+        let text = format!(
+            "{}{{\"name\": \"get_weather\", \"arguments\": {{}}}}{}",
+            TOOL_CALL_START, TOOL_CALL_END
+        );
+        let reply = reply_message(&text, true);
+        assert_eq!(reply.content, "");
+        assert_eq!(reply.tool_calls[0].function.name, "get_weather");
+        assert_eq!(reply_message(&text, false).content, text);
+    }
+}
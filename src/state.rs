@@ -1,11 +1,19 @@
 // Shared state management for models
 use inference_server::models::bert::BertInferenceModel;
 use inference_server::models::llama::LlamaInferenceModel;
+use inference_server::search::corpus::Corpus;
+use inference_server::search::SearchParams;
+use inference_server::sessions::SessionStore;
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AppState {
     pub bert_model: Arc<BertInferenceModel>,
-    pub text_map: Vec<String>,
+    // Documents with their ids and metadata, and the chunk of a document each row of the
+    // embedding index was computed from; updated through the API
+    pub corpus: Arc<Corpus>,
+    // Accuracy of approximate searches, for the fields a query does not set
+    pub search_params: SearchParams,
     pub llama_model: Arc<LlamaInferenceModel>,
+    pub sessions: Arc<SessionStore>,
 }